@@ -0,0 +1,81 @@
+use crate::error::{NipeError, Result};
+use std::time::Duration;
+use tracing::{debug, info};
+
+/// Tor Project's list of the bridges built into Tor Browser.
+const BUILTIN_URL: &str = "https://bridges.torproject.org/moat/circumvention/builtin";
+
+/// obfs4 bridges built into Tor Browser, used when the list can't be
+/// downloaded. They are public and may be blocked or retired over time.
+pub const FALLBACK_OBFS4: [&str; 5] = [
+    "obfs4 192.95.36.142:443 CDF2E852BF539B82BD10E27E9115A31734E378C2 cert=qUVQ0srL1JI/vO6V6m/24anYXiJD3QP2HgzUKQtQ7GRqqUvs7P+tG43RtAqdhLOALP7DJQ iat-mode=1",
+    "obfs4 37.218.245.14:38224 D9A82D2F9C2F65A18407B1D2B764F130847F8B5D cert=bjRaMrr1BRiAW8IE9U5z27fQaYgOhX1UCmOpg2pFpoMvo6ZgQMzLsaTzzQNTlm7hNcb+Sg iat-mode=0",
+    "obfs4 85.31.186.98:443 011F2599C0E9B27EE74B353155E244813763C3E5 cert=ayq0XzCwhpdysn5o0EyDUbmSOx3X/oTEbzDMvczHOdBJKlvIdHHLJGkZARtT4dcBFArPPg iat-mode=0",
+    "obfs4 85.31.186.26:443 91A6354697E6B02A386312F68D82CF86824D3606 cert=PBwr+S8JTVZo6MPdHnkTwXJPILWADLqfMGoVvhZClMq/Urndyd42BwX9YFJHZnBB3H0XCw iat-mode=0",
+    "obfs4 193.11.166.194:27015 2D82C2E354D531A68469ADF7F878FA6060C6BACA cert=4TLQPJrTSaDffMK7Nbao6LC7G9OW/NHkUwIdjLSS3KYf0Nv4/nQiiI8dY2TcsQx01NniOg iat-mode=0",
+];
+
+/// Where a set of bridge lines came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BridgeSource {
+    /// Downloaded through the running Tor instance
+    Tor,
+    /// Downloaded over a direct connection
+    Direct,
+    /// The list bundled with Nipe
+    Bundled,
+}
+
+/// Download the built-in obfs4 bridge list, through Tor when it is running
+/// and directly otherwise, falling back to the bundled list.
+pub async fn fetch_builtin_obfs4(socks_port: u16) -> (Vec<String>, BridgeSource) {
+    let proxy = format!("socks5h://127.0.0.1:{}", socks_port);
+
+    for (proxy, source) in [
+        (Some(proxy), BridgeSource::Tor),
+        (None, BridgeSource::Direct),
+    ] {
+        match download(proxy.as_deref()).await {
+            Ok(bridges) if !bridges.is_empty() => {
+                info!("Fetched {} obfs4 bridges ({:?})", bridges.len(), source);
+                return (bridges, source);
+            }
+            Ok(_) => debug!("Bridge list ({:?}) had no obfs4 bridges", source),
+            Err(e) => debug!("Bridge list download ({:?}) failed: {}", source, e),
+        }
+    }
+
+    (
+        FALLBACK_OBFS4.iter().map(|b| b.to_string()).collect(),
+        BridgeSource::Bundled,
+    )
+}
+
+async fn download(proxy: Option<&str>) -> Result<Vec<String>> {
+    let mut builder = reqwest::Client::builder().timeout(Duration::from_secs(30));
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+    }
+    let client = builder.build()?;
+
+    let json: serde_json::Value = client
+        .post(BUILTIN_URL)
+        .header("Content-Type", "application/vnd.api+json")
+        .body("{}")
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let bridges = json["obfs4"]
+        .as_array()
+        .ok_or_else(|| NipeError::Other("Bridge list has no obfs4 entry".to_string()))?;
+
+    Ok(bridges
+        .iter()
+        .filter_map(|b| b.as_str())
+        .map(|b| b.trim().to_string())
+        .filter(|b| b.starts_with("obfs4 "))
+        .collect())
+}
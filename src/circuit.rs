@@ -0,0 +1,179 @@
+use serde::{Deserialize, Serialize};
+
+/// A Tor circuit as reported by `GETINFO circuit-status`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Circuit {
+    pub id: String,
+    pub status: String,
+    pub purpose: Option<String>,
+    pub hops: Vec<Relay>,
+}
+
+/// A single relay in a circuit path.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Relay {
+    pub nickname: Option<String>,
+    pub fingerprint: String,
+    pub country: Option<String>,
+    pub ip: Option<String>,
+}
+
+impl Circuit {
+    /// Parse the value of `GETINFO circuit-status`, one circuit per line.
+    pub fn parse_status(value: &str) -> Vec<Circuit> {
+        value.lines().filter_map(Self::parse_line).collect()
+    }
+
+    /// Parse a single circuit-status line, e.g.
+    /// `7 BUILT $AAAA~alpha,$BBBB~beta BUILD_FLAGS=NEED_CAPACITY PURPOSE=GENERAL`.
+    pub fn parse_line(line: &str) -> Option<Circuit> {
+        let mut fields = line.split_whitespace();
+        let id = fields.next()?.to_string();
+        let status = fields.next()?.to_string();
+
+        let mut hops = Vec::new();
+        let mut purpose = None;
+
+        for field in fields {
+            if let Some(value) = field.strip_prefix("PURPOSE=") {
+                purpose = Some(value.to_string());
+            } else if field.starts_with('$') {
+                hops = field
+                    .split(',')
+                    .filter_map(Relay::parse_long_name)
+                    .collect();
+            }
+        }
+
+        Some(Circuit {
+            id,
+            status,
+            purpose,
+            hops,
+        })
+    }
+
+    /// The last hop, which is the exit for general-purpose circuits.
+    pub fn exit(&self) -> Option<&Relay> {
+        self.hops.last()
+    }
+
+    pub fn is_built(&self) -> bool {
+        self.status == "BUILT"
+    }
+}
+
+impl Relay {
+    /// Parse a relay "long name": `$FINGERPRINT~nickname`, `$FINGERPRINT=nickname`
+    /// or a bare `$FINGERPRINT`.
+    pub fn parse_long_name(name: &str) -> Option<Relay> {
+        let name = name.strip_prefix('$')?;
+        let (fingerprint, nickname) = match name.find(['~', '=']) {
+            Some(idx) => (&name[..idx], Some(name[idx + 1..].to_string())),
+            None => (name, None),
+        };
+
+        if fingerprint.is_empty() {
+            return None;
+        }
+
+        Some(Relay {
+            nickname,
+            fingerprint: fingerprint.to_string(),
+            country: None,
+            ip: None,
+        })
+    }
+
+    /// Extract the relay address from a `GETINFO ns/id/<fp>` router status entry.
+    pub fn parse_ns_address(value: &str) -> Option<String> {
+        value
+            .lines()
+            .find(|l| l.starts_with("r "))
+            .and_then(|l| l.split_whitespace().nth(6))
+            .map(|ip| ip.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MORIA1: &str = "9695DFC35FFEB861329B9F1AB04C46397020CE31";
+    const GUARD: &str = "847B1F850344D7876491A54892F904934E4EB85D";
+    const EXIT: &str = "E3DBEA6E9C4F1AE0F9C0C1F6D4C8BE1A4F2A7FDB";
+
+    #[test]
+    fn parses_a_built_general_circuit() {
+        let line = format!(
+            "12 BUILT ${}~moria1,${}~naggeh,${}=Unnamed BUILD_FLAGS=NEED_CAPACITY PURPOSE=GENERAL TIME_CREATED=2024-05-01T09:30:12.345678",
+            MORIA1, GUARD, EXIT
+        );
+        let circuit = Circuit::parse_line(&line).unwrap();
+
+        assert_eq!(circuit.id, "12");
+        assert!(circuit.is_built());
+        assert_eq!(circuit.purpose.as_deref(), Some("GENERAL"));
+        let nicknames: Vec<_> = circuit.hops.iter().map(|h| h.nickname.as_deref()).collect();
+        assert_eq!(nicknames, [Some("moria1"), Some("naggeh"), Some("Unnamed")]);
+        assert_eq!(circuit.exit().unwrap().fingerprint, EXIT);
+    }
+
+    #[test]
+    fn parses_circuits_without_a_path() {
+        let circuit = Circuit::parse_line(
+            "4 LAUNCHED BUILD_FLAGS=NEED_CAPACITY PURPOSE=GENERAL TIME_CREATED=2024-05-01T09:30:12.345678",
+        )
+        .unwrap();
+        assert_eq!(circuit.status, "LAUNCHED");
+        assert!(!circuit.is_built());
+        assert!(circuit.hops.is_empty());
+        assert!(circuit.exit().is_none());
+
+        assert!(Circuit::parse_line("").is_none());
+        assert!(Circuit::parse_line("4").is_none());
+    }
+
+    #[test]
+    fn parses_the_whole_circuit_status_value() {
+        let value = format!(
+            "3 EXTENDED ${}~moria1 BUILD_FLAGS=IS_INTERNAL,NEED_CAPACITY PURPOSE=HS_CLIENT_HSDIR TIME_CREATED=2024-05-01T09:30:10.000000\n\
+             5 BUILT ${}~naggeh,${} BUILD_FLAGS=NEED_CAPACITY PURPOSE=GENERAL TIME_CREATED=2024-05-01T09:30:11.000000\n",
+            MORIA1, GUARD, EXIT
+        );
+        let circuits = Circuit::parse_status(&value);
+
+        assert_eq!(circuits.len(), 2);
+        assert_eq!(circuits[0].purpose.as_deref(), Some("HS_CLIENT_HSDIR"));
+        assert_eq!(circuits[1].hops.len(), 2);
+        assert_eq!(circuits[1].exit().unwrap().nickname, None);
+    }
+
+    #[test]
+    fn parses_relay_long_names() {
+        let named = Relay::parse_long_name(&format!("${}~moria1", MORIA1)).unwrap();
+        assert_eq!(named.fingerprint, MORIA1);
+        assert_eq!(named.nickname.as_deref(), Some("moria1"));
+
+        // `=` marks a relay listed as Named in older consensuses
+        let old = Relay::parse_long_name(&format!("${}=moria1", MORIA1)).unwrap();
+        assert_eq!(old.nickname.as_deref(), Some("moria1"));
+
+        let bare = Relay::parse_long_name(&format!("${}", MORIA1)).unwrap();
+        assert_eq!(bare.fingerprint, MORIA1);
+        assert_eq!(bare.nickname, None);
+
+        assert!(Relay::parse_long_name("moria1").is_none());
+        assert!(Relay::parse_long_name("$").is_none());
+        assert!(Relay::parse_long_name("$~moria1").is_none());
+    }
+
+    #[test]
+    fn parses_the_address_from_a_router_status_entry() {
+        let ns = "r moria1 lpXfw1/+uGEym58asExGOXAgzjE 4dTlZr5ZKbfWWfEcrlFGkbNGbcg 2024-05-01 08:51:27 128.31.0.34 9101 9131\n\
+                  s Authority Fast Running Stable V2Dir Valid\n\
+                  w Bandwidth=20";
+        assert_eq!(Relay::parse_ns_address(ns).as_deref(), Some("128.31.0.34"));
+        assert_eq!(Relay::parse_ns_address("s Running Valid"), None);
+    }
+}
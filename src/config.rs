@@ -1,4 +1,6 @@
+use crate::error::{NipeError, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
 use std::path::PathBuf;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -6,6 +8,12 @@ pub struct NipeConfig {
     pub tor: TorConfig,
     pub firewall: FirewallConfig,
     pub rotation: RotationConfig,
+    /// Name of the isolated instance this config belongs to (`--instance`)
+    #[serde(skip)]
+    pub instance: Option<String>,
+    /// Config file given with `--config`, used instead of the default one
+    #[serde(skip)]
+    pub file: Option<PathBuf>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -13,17 +21,174 @@ pub struct TorConfig {
     pub socks_port: u16,
     pub control_port: u16,
     pub dns_port: u16,
+    /// Tor's TransPort, where the Linux kill switch redirects outgoing TCP
+    #[serde(default = "default_trans_port")]
+    pub trans_port: u16,
     pub data_directory: PathBuf,
+    /// Where the generated torrc, tor.log and pid files live. Defaults to the
+    /// data directory's parent; Tor runs unprivileged, so it must be able to
+    /// read this directory
+    #[serde(default)]
+    pub work_dir: Option<PathBuf>,
+    /// Tor executable to run instead of searching the usual install locations
+    #[serde(default)]
+    pub tor_binary: Option<PathBuf>,
+    /// Oldest Tor release `nipe start` accepts, e.g. "0.4.0"
+    #[serde(default = "default_min_tor_version")]
+    pub min_tor_version: String,
+    /// How many times the connection check is tried before reporting
+    /// "Not Connected"; fresh circuits often fail the first request
+    #[serde(default = "default_check_attempts")]
+    pub check_attempts: u32,
+    /// Timeout of each connection check request, in seconds
+    #[serde(default = "default_check_timeout_secs")]
+    pub check_timeout_secs: u64,
+    /// How long `start` waits for Tor to bootstrap, in seconds
+    #[serde(default = "default_bootstrap_timeout_secs")]
+    pub bootstrap_timeout_secs: u64,
+    /// Endpoint fetched through Tor to confirm the connection works. The
+    /// torproject JSON API also reports whether the request came through
+    /// Tor; any other endpoint counts as connected when it answers 200
+    #[serde(default = "default_check_url")]
+    pub check_url: String,
+    /// Protect the control port with this password (HashedControlPassword)
+    #[serde(default)]
+    pub control_password: Option<String>,
+    /// Protect the control port with an auth cookie file (CookieAuthentication)
+    #[serde(default)]
+    pub cookie_authentication: bool,
     #[serde(default)]
     pub use_bridges: bool,
     #[serde(default)]
     pub client_transport_plugin: Option<String>,
     #[serde(default)]
     pub bridges: Vec<String>,
+    /// Override `iat-mode` on every obfs4 bridge line (0 = off, 1 = enabled,
+    /// 2 = paranoid). Higher modes are harder to fingerprint but slower.
+    #[serde(default)]
+    pub bridge_iat_mode: Option<u8>,
     #[serde(default)]
     pub exit_nodes: Vec<String>,
+    /// Pin entry guards to these relays (EntryNodes). Tor ignores this while
+    /// bridges are in use, since the bridges act as the entry guards
+    #[serde(default)]
+    pub entry_nodes: Vec<String>,
+    /// Never use these relays as exits (ExcludeExitNodes)
+    #[serde(default)]
+    pub exclude_exit_nodes: Vec<String>,
+    /// Never use these relays anywhere in a circuit (ExcludeNodes)
+    #[serde(default)]
+    pub exclude_nodes: Vec<String>,
     #[serde(default)]
     pub country: Option<String>,
+    /// Traffic cap per accounting period, e.g. "10 GB"
+    #[serde(default)]
+    pub accounting_max: Option<String>,
+    /// Start of the accounting period, e.g. "month 1 00:00"
+    #[serde(default)]
+    pub accounting_start: Option<String>,
+    /// Average bandwidth cap (BandwidthRate), e.g. "1 MBytes"
+    #[serde(default)]
+    pub bandwidth_rate: Option<String>,
+    /// Largest burst allowed above `bandwidth_rate` (BandwidthBurst)
+    #[serde(default)]
+    pub bandwidth_burst: Option<String>,
+    /// IPv4 GeoIP database, needed for country-based node selection
+    #[serde(default)]
+    pub geoip_file: Option<PathBuf>,
+    /// IPv6 GeoIP database
+    #[serde(default)]
+    pub geoip6_file: Option<PathBuf>,
+    /// Port for Tor's HTTP CONNECT proxy (HTTPTunnelPort), used by `firewall.web_proxy`
+    #[serde(default)]
+    pub http_tunnel_port: Option<u16>,
+    /// Run Nipe's HTTP-to-SOCKS proxy on this localhost port (e.g. 8118)
+    #[serde(default)]
+    pub http_proxy_port: Option<u16>,
+    /// Emit `DormantCanceledByStartup`: wake from dormancy whenever Tor starts
+    #[serde(default)]
+    pub dormant_canceled_by_startup: Option<bool>,
+    /// Emit `DormantTimeoutDisabledByIdleStreams`: whether idle open streams
+    /// keep Tor from going dormant
+    #[serde(default)]
+    pub dormant_timeout_disabled_by_idle_streams: Option<bool>,
+    /// Emit `ConnectionPadding`; unset leaves Tor on "auto". Disabling it
+    /// saves bandwidth but weakens resistance to traffic analysis.
+    #[serde(default)]
+    pub connection_padding: Option<bool>,
+    /// Emit `ReducedConnectionPadding 1`: less padding and earlier closing of
+    /// idle connections, trading some traffic-analysis resistance for bandwidth
+    #[serde(default)]
+    pub reduced_connection_padding: bool,
+    /// Emit `CircuitBuildTimeout`: seconds to wait for a circuit to build,
+    /// fixed instead of learned, for high-latency or satellite links
+    #[serde(default)]
+    pub circuit_build_timeout: Option<u64>,
+    /// Emit `SocksTimeout`: seconds to wait for an unattached SOCKS
+    /// connection to get a circuit before giving up on it
+    #[serde(default)]
+    pub socks_timeout: Option<u64>,
+    /// Add `IsolateDestAddr` to the SocksPort: streams to different
+    /// destination addresses never share a circuit
+    #[serde(default)]
+    pub isolate_dest_addr: bool,
+    /// Add `IsolateDestPort` to the SocksPort: streams to different
+    /// destination ports never share a circuit
+    #[serde(default)]
+    pub isolate_dest_port: bool,
+    /// More SOCKS ports, each with its own isolation flags, e.g. an isolated
+    /// one for a browser next to a shared one for a scraper. `socks_port`
+    /// stays the primary port used by the firewall and status checks
+    #[serde(default)]
+    pub extra_socks_ports: Vec<SocksPortConfig>,
+    /// Local services to publish as .onion addresses
+    #[serde(default)]
+    pub hidden_services: Vec<HiddenServiceConfig>,
+    /// Lines appended verbatim to the generated torrc, for directives Nipe
+    /// has no setting for
+    #[serde(default)]
+    pub extra_torrc_lines: Vec<String>,
+    /// Use this hand-written torrc instead of generating one. It must set
+    /// the `socks_port` and `control_port` configured here; the other torrc
+    /// settings above are then ignored
+    #[serde(default)]
+    pub external_torrc: Option<PathBuf>,
+}
+
+/// One additional `SocksPort` line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SocksPortConfig {
+    pub port: u16,
+    #[serde(default)]
+    pub isolate_dest_addr: bool,
+    #[serde(default)]
+    pub isolate_dest_port: bool,
+}
+
+impl SocksPortConfig {
+    /// The `SocksPort` value: the port followed by its isolation flags.
+    pub fn torrc_value(&self) -> String {
+        let mut value = self.port.to_string();
+        if self.isolate_dest_addr {
+            value.push_str(" IsolateDestAddr");
+        }
+        if self.isolate_dest_port {
+            value.push_str(" IsolateDestPort");
+        }
+        value
+    }
+}
+
+/// One onion service: `HiddenServiceDir` plus a `HiddenServicePort`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HiddenServiceConfig {
+    /// Names the service's key directory, so its .onion address stays the
+    /// same across restarts
+    pub name: String,
+    /// Local address the service listens on, e.g. "127.0.0.1:22"
+    pub target: String,
+    /// Port the service is reachable on at its .onion address
+    pub virtual_port: u16,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -31,6 +196,18 @@ pub struct FirewallConfig {
     pub enable_kill_switch: bool,
     pub allow_lan: bool,
     pub block_ipv6: bool,
+    /// Also point the system HTTPS web proxy at Tor (macOS), for apps that
+    /// ignore SOCKS settings
+    #[serde(default)]
+    pub web_proxy: bool,
+    /// Destination ports (TCP and UDP) that go out directly instead of
+    /// through Tor, past the kill switch
+    #[serde(default)]
+    pub bypass_ports: Vec<u16>,
+    /// Destination networks (e.g. "192.168.1.1/32") that go out directly
+    /// instead of through Tor, past the kill switch
+    #[serde(default)]
+    pub bypass_cidrs: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -46,22 +223,174 @@ impl Default for NipeConfig {
                 socks_port: 9050,
                 control_port: 9051,
                 dns_port: 9061,
+                trans_port: default_trans_port(),
                 data_directory: PathBuf::from("/var/lib/nipe/tor-data"),
+                work_dir: None,
+                tor_binary: None,
+                min_tor_version: default_min_tor_version(),
+                check_attempts: default_check_attempts(),
+                check_timeout_secs: default_check_timeout_secs(),
+                bootstrap_timeout_secs: default_bootstrap_timeout_secs(),
+                check_url: default_check_url(),
+                control_password: None,
+                cookie_authentication: false,
                 use_bridges: false,
                 client_transport_plugin: None,
                 bridges: vec![],
+                bridge_iat_mode: None,
                 exit_nodes: vec![],
+                entry_nodes: vec![],
+                exclude_exit_nodes: vec![],
+                exclude_nodes: vec![],
                 country: None,
+                accounting_max: None,
+                accounting_start: None,
+                bandwidth_rate: None,
+                bandwidth_burst: None,
+                geoip_file: None,
+                geoip6_file: None,
+                http_tunnel_port: None,
+                http_proxy_port: None,
+                dormant_canceled_by_startup: None,
+                dormant_timeout_disabled_by_idle_streams: None,
+                connection_padding: None,
+                reduced_connection_padding: false,
+                circuit_build_timeout: None,
+                socks_timeout: None,
+                isolate_dest_addr: false,
+                isolate_dest_port: false,
+                extra_socks_ports: vec![],
+                hidden_services: vec![],
+                extra_torrc_lines: vec![],
+                external_torrc: None,
             },
             firewall: FirewallConfig {
                 enable_kill_switch: true,
                 allow_lan: true,
                 block_ipv6: true,
+                web_proxy: false,
+                bypass_ports: vec![],
+                bypass_cidrs: vec![],
             },
             rotation: RotationConfig {
                 auto_rotate: true,
                 interval_seconds: 60,
             },
+            instance: None,
+            file: None,
+        }
+    }
+}
+
+/// Where the effective value of a config key came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigSource {
+    /// Taken as-is from the config file
+    File,
+    /// Missing from the file; the built-in default applies
+    Default,
+    /// Set in the file but replaced by a flag or environment variable
+    Override,
+    /// Present in the file but not a recognised setting
+    Unused,
+}
+
+#[derive(Debug, Clone)]
+pub struct ConfigDiffEntry {
+    pub key: String,
+    pub file: Option<String>,
+    pub effective: Option<String>,
+    pub source: ConfigSource,
+}
+
+impl TorConfig {
+    pub const DEFAULT_HTTP_TUNNEL_PORT: u16 = 9080;
+
+    /// Directory holding the torrc, Tor's log and pid files.
+    pub fn work_dir(&self) -> PathBuf {
+        self.work_dir.clone().unwrap_or_else(|| {
+            self.data_directory
+                .parent()
+                .map(|p| p.to_path_buf())
+                .unwrap_or_else(|| PathBuf::from("/var/lib/nipe"))
+        })
+    }
+
+    /// Root-only directory in [`work_dir`](Self::work_dir) for the pid files
+    /// root signals and the firewall backups it restores.
+    pub fn state_dir(&self) -> PathBuf {
+        self.work_dir().join("state")
+    }
+
+    /// Tor's log file, written by the engine while Tor runs.
+    pub fn log_path(&self) -> PathBuf {
+        self.work_dir().join("tor.log")
+    }
+
+    /// Where Tor writes the control auth cookie when CookieAuthentication is on.
+    pub fn cookie_path(&self) -> PathBuf {
+        self.data_directory.join("control_auth_cookie")
+    }
+
+    /// Where Tor keeps the keys and `hostname` of the hidden service `name`.
+    pub fn hidden_service_dir(&self, name: &str) -> PathBuf {
+        self.data_directory.join(format!("hs-{}", name))
+    }
+
+    /// The primary SOCKS port with its isolation flags.
+    pub fn primary_socks_port(&self) -> SocksPortConfig {
+        SocksPortConfig {
+            port: self.socks_port,
+            isolate_dest_addr: self.isolate_dest_addr,
+            isolate_dest_port: self.isolate_dest_port,
+        }
+    }
+
+    /// The HTTPTunnelPort to use when the web proxy is enabled.
+    pub fn http_tunnel_port(&self) -> u16 {
+        self.http_tunnel_port
+            .unwrap_or(Self::DEFAULT_HTTP_TUNNEL_PORT)
+    }
+
+    /// The exit countries from `country`, which may list several
+    /// (`"us,de"`), normalised to lowercase.
+    pub fn countries(&self) -> Vec<String> {
+        self.country
+            .iter()
+            .flat_map(|c| c.split(','))
+            .map(|c| c.trim().to_lowercase())
+            .filter(|c| !c.is_empty())
+            .collect()
+    }
+
+    /// Whether exits are selected by country, which needs GeoIP data.
+    pub fn uses_country_selection(&self) -> bool {
+        self.country.is_some()
+            || self
+                .exit_nodes
+                .iter()
+                .chain(&self.entry_nodes)
+                .chain(&self.exclude_exit_nodes)
+                .chain(&self.exclude_nodes)
+                .any(|n| n.trim().starts_with('{'))
+    }
+
+    /// The IPv4 GeoIP database Tor will use: the configured one, or the
+    /// first file found in the usual package locations.
+    pub fn geoip_file_path(&self) -> Option<PathBuf> {
+        const DEFAULT_PATHS: [&str; 4] = [
+            "/usr/share/tor/geoip",
+            "/usr/local/share/tor/geoip",
+            "/opt/homebrew/share/tor/geoip",
+            "/opt/local/share/tor/geoip",
+        ];
+
+        match &self.geoip_file {
+            Some(path) => Some(path.clone()).filter(|p| p.is_file()),
+            None => DEFAULT_PATHS
+                .iter()
+                .map(PathBuf::from)
+                .find(|p| p.is_file()),
         }
     }
 }
@@ -72,7 +401,9 @@ impl NipeConfig {
 
         if config_path.exists() {
             let content = std::fs::read_to_string(&config_path)?;
-            Ok(toml::from_str(&content)?)
+            Ok(toml::from_str(&content).map_err(|e| {
+                NipeError::ConfigError(format!("Invalid config {}: {}", config_path.display(), e))
+            })?)
         } else {
             let default = Self::default();
             default.save()?;
@@ -80,24 +411,730 @@ impl NipeConfig {
         }
     }
 
+    /// Load the config from `path` instead of the default location,
+    /// creating it with the defaults on first use.
+    pub fn load_from(path: &std::path::Path) -> anyhow::Result<Self> {
+        let mut config = if path.exists() {
+            let content = std::fs::read_to_string(path)?;
+            toml::from_str(&content).map_err(|e| {
+                NipeError::ConfigError(format!("Invalid config {}: {}", path.display(), e))
+            })?
+        } else {
+            Self::default()
+        };
+
+        config.file = Some(path.to_path_buf());
+        if !path.exists() {
+            config.save()?;
+        }
+        Ok(config)
+    }
+
+    /// Load the config for a named instance, creating it on first use.
+    ///
+    /// A new instance starts from the main config with its own data directory
+    /// and freshly picked free ports, so several instances can run side by side.
+    /// Extra SOCKS ports and the HTTP proxy are not carried over.
+    pub fn load_instance(name: &str) -> anyhow::Result<Self> {
+        validate_instance_name(name)?;
+        let config_path = Self::instance_path(name);
+
+        let mut config = if config_path.exists() {
+            let content = std::fs::read_to_string(&config_path)?;
+            toml::from_str(&content).map_err(|e| {
+                NipeError::ConfigError(format!("Invalid config {}: {}", config_path.display(), e))
+            })?
+        } else {
+            let mut config = Self::load().unwrap_or_default();
+            let base = config
+                .tor
+                .data_directory
+                .parent()
+                .map(|p| p.to_path_buf())
+                .unwrap_or_else(|| PathBuf::from("/var/lib/nipe"));
+            config.tor.data_directory = base.join("instances").join(name).join("tor-data");
+
+            let [socks, control, dns, trans, http_tunnel] = free_ports::<5>()?;
+            config.tor.socks_port = socks;
+            config.tor.control_port = control;
+            config.tor.dns_port = dns;
+            config.tor.trans_port = trans;
+            config.tor.http_tunnel_port = Some(http_tunnel);
+            // Extra listeners would collide with the main config's; an
+            // instance is SOCKS only unless its own config adds them
+            config.tor.extra_socks_ports.clear();
+            config.tor.http_proxy_port = None;
+            config
+        };
+
+        config.instance = Some(name.to_string());
+        if !config_path.exists() {
+            config.save()?;
+        }
+        Ok(config)
+    }
+
     pub fn save(&self) -> anyhow::Result<()> {
-        let config_dir = dirs::config_dir()
-            .unwrap_or_else(|| PathBuf::from("."))
-            .join("nipe");
+        let config_path = self.path();
 
-        std::fs::create_dir_all(&config_dir)?;
+        if let Some(config_dir) = config_path.parent() {
+            std::fs::create_dir_all(config_dir)?;
+        }
 
-        let config_path = config_dir.join("config.toml");
         let content = toml::to_string_pretty(self)?;
         std::fs::write(&config_path, content)?;
 
         Ok(())
     }
 
-    fn config_path() -> PathBuf {
+    /// Move the config file aside to `<file>.bak` and write fresh defaults.
+    ///
+    /// Returns the new config and the backup path, if there was a file to
+    /// back up. An instance gets a new data directory and ports as on first use.
+    pub fn reset(&self) -> anyhow::Result<(Self, Option<PathBuf>)> {
+        let path = self.path();
+        let backup = if path.exists() {
+            let mut name = path.clone().into_os_string();
+            name.push(".bak");
+            let backup = PathBuf::from(name);
+            std::fs::rename(&path, &backup)?;
+            Some(backup)
+        } else {
+            None
+        };
+
+        let config = match (&self.file, &self.instance) {
+            (Some(file), _) => Self::load_from(file)?,
+            (None, Some(name)) => Self::load_instance(name)?,
+            (None, None) => {
+                let config = Self::default();
+                config.save()?;
+                config
+            }
+        };
+        Ok((config, backup))
+    }
+
+    /// The file this config is loaded from and saved to.
+    pub fn path(&self) -> PathBuf {
+        if let Some(file) = &self.file {
+            return file.clone();
+        }
+        match &self.instance {
+            Some(name) => Self::instance_path(name),
+            None => Self::config_path(),
+        }
+    }
+
+    /// Compare this (effective) config with what is on disk, key by key.
+    pub fn diff_with_file(&self) -> anyhow::Result<Vec<ConfigDiffEntry>> {
+        let path = self.path();
+        let file: toml::Table = if path.exists() {
+            toml::from_str(&std::fs::read_to_string(&path)?)?
+        } else {
+            toml::Table::new()
+        };
+        let effective = toml::Table::try_from(self)?;
+
+        let mut file_values = BTreeMap::new();
+        let mut effective_values = BTreeMap::new();
+        flatten_table("", &file, &mut file_values);
+        flatten_table("", &effective, &mut effective_values);
+
+        let keys: BTreeSet<&String> = file_values.keys().chain(effective_values.keys()).collect();
+
+        Ok(keys
+            .into_iter()
+            .map(|key| {
+                let file = file_values.get(key);
+                let effective = effective_values.get(key);
+                let source = match (file, effective) {
+                    (Some(f), Some(e)) if f == e => ConfigSource::File,
+                    (Some(_), Some(_)) => ConfigSource::Override,
+                    (None, _) => ConfigSource::Default,
+                    (Some(_), None) => ConfigSource::Unused,
+                };
+                ConfigDiffEntry {
+                    key: key.clone(),
+                    file: file.map(|v| v.to_string()),
+                    effective: effective.map(|v| v.to_string()),
+                    source,
+                }
+            })
+            .collect())
+    }
+
+    /// Set one setting by its dotted key, e.g. `tor.socks_port`.
+    ///
+    /// The value is read as a TOML literal (`9150`, `true`, `["a", "b"]`) and
+    /// falls back to a plain string, so `us` works without quotes.
+    pub fn set_key(&mut self, key: &str, value: &str) -> Result<()> {
+        let literal = toml::from_str::<toml::Table>(&format!("value = {}", value))
+            .ok()
+            .and_then(|mut t| t.remove("value"));
+
+        let updated = match literal {
+            Some(literal) => self
+                .with_key(key, literal)
+                .or_else(|_| self.with_key(key, toml::Value::String(value.to_string()))),
+            None => self.with_key(key, toml::Value::String(value.to_string())),
+        }?;
+
+        updated.validate()?;
+        *self = updated;
+        Ok(())
+    }
+
+    /// A copy of this config with `key` replaced, checking that the key is a
+    /// known setting and the value has the right type.
+    fn with_key(&self, key: &str, value: toml::Value) -> Result<NipeConfig> {
+        let unknown = || NipeError::ConfigError(format!("Unknown setting '{}'", key));
+
+        let mut table =
+            toml::Table::try_from(self).map_err(|e| NipeError::ConfigError(e.to_string()))?;
+        let (path, field) = key.rsplit_once('.').ok_or_else(unknown)?;
+
+        let mut section = &mut table;
+        for part in path.split('.') {
+            section = section
+                .get_mut(part)
+                .and_then(toml::Value::as_table_mut)
+                .ok_or_else(unknown)?;
+        }
+        section.insert(field.to_string(), value);
+
+        let mut updated: NipeConfig = table.try_into().map_err(|e: toml::de::Error| {
+            NipeError::ConfigError(format!("Invalid value for '{}': {}", key, e.message()))
+        })?;
+        updated.instance = self.instance.clone();
+        updated.file = self.file.clone();
+
+        // Serde drops fields it doesn't know, so a missing key was never valid
+        let mut known = BTreeMap::new();
+        let check =
+            toml::Table::try_from(&updated).map_err(|e| NipeError::ConfigError(e.to_string()))?;
+        flatten_table("", &check, &mut known);
+        if !known.contains_key(key) {
+            return Err(unknown());
+        }
+
+        Ok(updated)
+    }
+
+    /// Check settings that would otherwise produce a broken torrc.
+    pub fn validate(&self) -> Result<()> {
+        self.validate_ports()?;
+        if !self.tor.check_url.starts_with("http://") && !self.tor.check_url.starts_with("https://")
+        {
+            return Err(NipeError::ConfigError(format!(
+                "Invalid check_url '{}': expected an http:// or https:// URL",
+                self.tor.check_url
+            )));
+        }
+        if self.tor.check_attempts == 0 || self.tor.check_timeout_secs == 0 {
+            return Err(NipeError::ConfigError(
+                "check_attempts and check_timeout_secs must be at least 1".to_string(),
+            ));
+        }
+        if self.tor.bootstrap_timeout_secs == 0 {
+            return Err(NipeError::ConfigError(
+                "bootstrap_timeout_secs must be at least 1".to_string(),
+            ));
+        }
+        if parse_tor_version(&self.tor.min_tor_version).is_none() {
+            return Err(NipeError::ConfigError(format!(
+                "Invalid min_tor_version '{}': expected a version such as \"0.4.0\"",
+                self.tor.min_tor_version
+            )));
+        }
+        if let Some(max) = &self.tor.accounting_max {
+            validate_amount("accounting_max", max, "10 GB", &MEMORY_UNITS)?;
+        }
+        if let Some(rate) = &self.tor.bandwidth_rate {
+            validate_amount("bandwidth_rate", rate, "1 MBytes", &BANDWIDTH_UNITS)?;
+        }
+        if let Some(burst) = &self.tor.bandwidth_burst {
+            validate_amount("bandwidth_burst", burst, "2 MBytes", &BANDWIDTH_UNITS)?;
+        }
+        if self.firewall.bypass_ports.contains(&0) {
+            return Err(NipeError::ConfigError(
+                "firewall.bypass_ports must not contain 0".to_string(),
+            ));
+        }
+        for cidr in &self.firewall.bypass_cidrs {
+            validate_cidr(cidr)?;
+        }
+        if let Some(start) = &self.tor.accounting_start {
+            validate_accounting_start(start)?;
+        }
+        for (i, service) in self.tor.hidden_services.iter().enumerate() {
+            validate_hidden_service(service)?;
+            if self.tor.hidden_services[..i]
+                .iter()
+                .any(|other| other.name == service.name)
+            {
+                return Err(NipeError::ConfigError(format!(
+                    "Hidden service name '{}' is used more than once",
+                    service.name
+                )));
+            }
+        }
+        if self.tor.circuit_build_timeout == Some(0) || self.tor.socks_timeout == Some(0) {
+            return Err(NipeError::ConfigError(
+                "circuit_build_timeout and socks_timeout must be at least 1".to_string(),
+            ));
+        }
+        // One directive per entry; an embedded newline would smuggle in more
+        if let Some(line) = self
+            .tor
+            .extra_torrc_lines
+            .iter()
+            .find(|line| line.contains(['\n', '\r']))
+        {
+            return Err(NipeError::ConfigError(format!(
+                "Invalid extra_torrc_lines entry {:?}: must not contain line breaks",
+                line
+            )));
+        }
+        if let Some(mode) = self.tor.bridge_iat_mode {
+            if mode > 2 {
+                return Err(NipeError::ConfigError(format!(
+                    "Invalid bridge_iat_mode {}: expected 0, 1 or 2",
+                    mode
+                )));
+            }
+        }
+        if self.tor.country.is_some() && self.tor.countries().is_empty() {
+            return Err(NipeError::ConfigError(
+                "tor.country is empty: expected a code such as \"us\" or a list like \"us,de\""
+                    .to_string(),
+            ));
+        }
+        for code in self.tor.countries() {
+            if !is_country_code(&code) {
+                return Err(NipeError::ConfigError(format!(
+                    "Unknown country code '{}': expected an ISO 3166 two-letter code such as \"us\" or \"de\"",
+                    code
+                )));
+            }
+        }
+        for (field, entries) in [
+            ("exit_nodes", &self.tor.exit_nodes),
+            ("entry_nodes", &self.tor.entry_nodes),
+            ("exclude_exit_nodes", &self.tor.exclude_exit_nodes),
+            ("exclude_nodes", &self.tor.exclude_nodes),
+        ] {
+            for entry in entries {
+                validate_node_spec(entry).map_err(|e| {
+                    NipeError::ConfigError(format!("Invalid {} entry '{}': {}", field, entry, e))
+                })?;
+            }
+        }
+        for path in [&self.tor.geoip_file, &self.tor.geoip6_file]
+            .into_iter()
+            .flatten()
+        {
+            if !path.is_file() {
+                return Err(NipeError::ConfigError(format!(
+                    "GeoIP file not found: {}",
+                    path.display()
+                )));
+            }
+        }
+        if self.tor.accounting_start.is_some() && self.tor.accounting_max.is_none() {
+            return Err(NipeError::ConfigError(
+                "accounting_start requires accounting_max to be set".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Every port Tor or Nipe listens on must be set and unique.
+    fn validate_ports(&self) -> Result<()> {
+        let mut ports = vec![
+            ("socks_port", self.tor.socks_port),
+            ("control_port", self.tor.control_port),
+            ("dns_port", self.tor.dns_port),
+            ("trans_port", self.tor.trans_port),
+        ];
+        for extra in &self.tor.extra_socks_ports {
+            ports.push(("extra_socks_ports", extra.port));
+        }
+        if self.firewall.web_proxy {
+            ports.push(("http_tunnel_port", self.tor.http_tunnel_port()));
+        }
+        if let Some(port) = self.tor.http_proxy_port {
+            ports.push(("http_proxy_port", port));
+        }
+
+        for (i, (name, port)) in ports.iter().enumerate() {
+            if *port == 0 {
+                return Err(NipeError::ConfigError(format!(
+                    "tor.{} must not be 0",
+                    name
+                )));
+            }
+            if let Some((other, _)) = ports[..i].iter().find(|(_, p)| p == port) {
+                return Err(NipeError::ConfigError(format!(
+                    "tor.{} and tor.{} are both set to port {}; each port must be different",
+                    other, name, port
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Nipe's config directory, holding config.toml and instance configs.
+    pub fn config_dir() -> PathBuf {
         dirs::config_dir()
             .unwrap_or_else(|| PathBuf::from("."))
             .join("nipe")
-            .join("config.toml")
+    }
+
+    fn config_path() -> PathBuf {
+        Self::config_dir().join("config.toml")
+    }
+
+    fn instance_path(name: &str) -> PathBuf {
+        Self::config_dir()
+            .join("instances")
+            .join(format!("{}.toml", name))
+    }
+}
+
+fn default_trans_port() -> u16 {
+    9040
+}
+
+fn default_min_tor_version() -> String {
+    "0.4.0".to_string()
+}
+
+fn default_check_attempts() -> u32 {
+    3
+}
+
+fn default_check_timeout_secs() -> u64 {
+    30
+}
+
+fn default_bootstrap_timeout_secs() -> u64 {
+    60
+}
+
+fn default_check_url() -> String {
+    crate::status::TORPROJECT_CHECK_URL.to_string()
+}
+
+/// Parse a Tor version such as "0.4.8.10" or "0.4.9.1-alpha" into its numeric
+/// components, padding missing ones with zero so "0.4" compares equal to
+/// "0.4.0.0"; any status tag after the numbers is ignored.
+pub fn parse_tor_version(version: &str) -> Option<[u32; 4]> {
+    let numbers = version
+        .trim()
+        .split(|c: char| c == '-' || c.is_whitespace())
+        .next()?;
+
+    let mut parsed = [0; 4];
+    for (i, part) in numbers.split('.').enumerate() {
+        *parsed.get_mut(i)? = part.parse().ok()?;
+    }
+    Some(parsed)
+}
+
+/// Flatten nested tables into dotted keys, e.g. `tor.socks_port`.
+fn flatten_table(prefix: &str, table: &toml::Table, out: &mut BTreeMap<String, toml::Value>) {
+    for (key, value) in table {
+        let key = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        match value {
+            toml::Value::Table(nested) => flatten_table(&key, nested, out),
+            other => {
+                out.insert(key, other.clone());
+            }
+        }
+    }
+}
+
+/// Check a node list entry: a relay fingerprint (40 hex chars, optionally
+/// `$`-prefixed), a `{cc}` country code, or a relay nickname.
+pub fn validate_node_spec(entry: &str) -> std::result::Result<(), String> {
+    let entry = entry.trim();
+
+    if let Some(fingerprint) = entry.strip_prefix('$') {
+        return if is_fingerprint(fingerprint) {
+            Ok(())
+        } else {
+            Err("fingerprints must be 40 hex characters".to_string())
+        };
+    }
+
+    if entry.starts_with('{') || entry.ends_with('}') {
+        let code = entry
+            .strip_prefix('{')
+            .and_then(|e| e.strip_suffix('}'))
+            .unwrap_or_default();
+        return if is_country_code(&code.to_lowercase()) {
+            Ok(())
+        } else {
+            Err("country codes must be ISO 3166 two-letter codes in braces, e.g. {de}".to_string())
+        };
+    }
+
+    if is_fingerprint(entry) {
+        return Ok(());
+    }
+
+    if !entry.is_empty() && entry.len() <= 19 && entry.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Ok(());
+    }
+
+    Err("expected a 40-character hex fingerprint, a {cc} country code, or a relay nickname (1-19 letters/digits)".to_string())
+}
+
+/// Format a validated node list entry for the torrc; bare fingerprints get
+/// their `$` prefix so Tor never mistakes them for nicknames.
+pub fn torrc_node_entry(entry: &str) -> String {
+    let entry = entry.trim();
+    if is_fingerprint(entry) {
+        format!("${}", entry.to_uppercase())
+    } else if entry.starts_with('{') {
+        entry.to_lowercase()
+    } else {
+        entry.to_string()
+    }
+}
+
+/// ISO 3166-1 alpha-2 codes, lowercase as Tor's GeoIP data uses them.
+const COUNTRY_CODES: [&str; 249] = [
+    "ad", "ae", "af", "ag", "ai", "al", "am", "ao", "aq", "ar", "as", "at", "au", "aw", "ax", "az",
+    "ba", "bb", "bd", "be", "bf", "bg", "bh", "bi", "bj", "bl", "bm", "bn", "bo", "bq", "br", "bs",
+    "bt", "bv", "bw", "by", "bz", "ca", "cc", "cd", "cf", "cg", "ch", "ci", "ck", "cl", "cm", "cn",
+    "co", "cr", "cu", "cv", "cw", "cx", "cy", "cz", "de", "dj", "dk", "dm", "do", "dz", "ec", "ee",
+    "eg", "eh", "er", "es", "et", "fi", "fj", "fk", "fm", "fo", "fr", "ga", "gb", "gd", "ge", "gf",
+    "gg", "gh", "gi", "gl", "gm", "gn", "gp", "gq", "gr", "gs", "gt", "gu", "gw", "gy", "hk", "hm",
+    "hn", "hr", "ht", "hu", "id", "ie", "il", "im", "in", "io", "iq", "ir", "is", "it", "je", "jm",
+    "jo", "jp", "ke", "kg", "kh", "ki", "km", "kn", "kp", "kr", "kw", "ky", "kz", "la", "lb", "lc",
+    "li", "lk", "lr", "ls", "lt", "lu", "lv", "ly", "ma", "mc", "md", "me", "mf", "mg", "mh", "mk",
+    "ml", "mm", "mn", "mo", "mp", "mq", "mr", "ms", "mt", "mu", "mv", "mw", "mx", "my", "mz", "na",
+    "nc", "ne", "nf", "ng", "ni", "nl", "no", "np", "nr", "nu", "nz", "om", "pa", "pe", "pf", "pg",
+    "ph", "pk", "pl", "pm", "pn", "pr", "ps", "pt", "pw", "py", "qa", "re", "ro", "rs", "ru", "rw",
+    "sa", "sb", "sc", "sd", "se", "sg", "sh", "si", "sj", "sk", "sl", "sm", "sn", "so", "sr", "ss",
+    "st", "sv", "sx", "sy", "sz", "tc", "td", "tf", "tg", "th", "tj", "tk", "tl", "tm", "tn", "to",
+    "tr", "tt", "tv", "tw", "tz", "ua", "ug", "um", "us", "uy", "uz", "va", "vc", "ve", "vg", "vi",
+    "vn", "vu", "wf", "ws", "ye", "yt", "za", "zm", "zw",
+];
+
+/// Whether `code` is a known lowercase ISO 3166-1 alpha-2 country code.
+pub fn is_country_code(code: &str) -> bool {
+    COUNTRY_CODES.binary_search(&code).is_ok()
+}
+
+fn is_fingerprint(value: &str) -> bool {
+    value.len() == 40 && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Instance names end up in paths, so keep them to a safe character set.
+/// Up to 32 letters, digits, '-' or '_': safe as a file or directory name.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 32
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn validate_instance_name(name: &str) -> Result<()> {
+    if is_valid_name(name) {
+        Ok(())
+    } else {
+        Err(NipeError::ConfigError(format!(
+            "Invalid instance name '{}': use up to 32 letters, digits, '-' or '_'",
+            name
+        )))
+    }
+}
+
+fn validate_hidden_service(service: &HiddenServiceConfig) -> Result<()> {
+    if !is_valid_name(&service.name) {
+        return Err(NipeError::ConfigError(format!(
+            "Invalid hidden service name '{}': use up to 32 letters, digits, '-' or '_'",
+            service.name
+        )));
+    }
+    if service.target.parse::<std::net::SocketAddr>().is_err() {
+        return Err(NipeError::ConfigError(format!(
+            "Invalid target '{}' for hidden service '{}': expected addr:port, e.g. \"127.0.0.1:22\"",
+            service.target, service.name
+        )));
+    }
+    if service.virtual_port == 0 {
+        return Err(NipeError::ConfigError(format!(
+            "virtual_port of hidden service '{}' must not be 0",
+            service.name
+        )));
+    }
+    Ok(())
+}
+
+/// Ask the OS for `N` distinct free localhost ports.
+fn free_ports<const N: usize>() -> Result<[u16; N]> {
+    // Hold every listener until all ports are picked so none repeat
+    let listeners = (0..N)
+        .map(|_| std::net::TcpListener::bind("127.0.0.1:0"))
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    let mut ports = [0u16; N];
+    for (port, listener) in ports.iter_mut().zip(&listeners) {
+        *port = listener.local_addr()?.port();
+    }
+    Ok(ports)
+}
+
+/// Accepts an IPv4 or IPv6 address with an optional prefix length, e.g.
+/// "192.168.1.0/24" or "fd00::1".
+fn validate_cidr(value: &str) -> Result<()> {
+    let (addr, prefix) = match value.split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix)),
+        None => (value, None),
+    };
+    let max_prefix = match addr.parse::<std::net::IpAddr>() {
+        Ok(std::net::IpAddr::V4(_)) => 32,
+        Ok(std::net::IpAddr::V6(_)) => 128,
+        Err(_) => {
+            return Err(NipeError::ConfigError(format!(
+                "Invalid bypass_cidrs entry '{}': not an IP address",
+                value
+            )))
+        }
+    };
+    if let Some(prefix) = prefix {
+        if !prefix.parse::<u8>().is_ok_and(|p| p <= max_prefix) {
+            return Err(NipeError::ConfigError(format!(
+                "Invalid bypass_cidrs entry '{}': prefix length must be 0-{}",
+                value, max_prefix
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Tor's memory units, as accepted by e.g. `AccountingMax`.
+const MEMORY_UNITS: [&str; 18] = [
+    "bytes",
+    "kb",
+    "kbytes",
+    "kilobytes",
+    "mb",
+    "mbytes",
+    "megabytes",
+    "gb",
+    "gbytes",
+    "gigabytes",
+    "tb",
+    "tbytes",
+    "terabytes",
+    "byte",
+    "kilobyte",
+    "megabyte",
+    "gigabyte",
+    "terabyte",
+];
+
+/// Tor's bandwidth units: the memory units plus bits.
+const BANDWIDTH_UNITS: [&str; 36] = [
+    "bytes",
+    "kb",
+    "kbytes",
+    "kilobytes",
+    "mb",
+    "mbytes",
+    "megabytes",
+    "gb",
+    "gbytes",
+    "gigabytes",
+    "tb",
+    "tbytes",
+    "terabytes",
+    "byte",
+    "kilobyte",
+    "megabyte",
+    "gigabyte",
+    "terabyte",
+    "bit",
+    "bits",
+    "kbit",
+    "kbits",
+    "kilobit",
+    "kilobits",
+    "mbit",
+    "mbits",
+    "megabit",
+    "megabits",
+    "gbit",
+    "gbits",
+    "gigabit",
+    "gigabits",
+    "tbit",
+    "tbits",
+    "terabit",
+    "terabits",
+];
+
+/// Accepts Tor's "amount unit" syntax, e.g. "10 GB", "500 MBytes", "1 TB",
+/// with the unit taken from `units`.
+fn validate_amount(key: &str, value: &str, example: &str, units: &[&str]) -> Result<()> {
+    let invalid = || {
+        NipeError::ConfigError(format!(
+            "Invalid {} '{}': expected an amount and unit such as \"{}\"",
+            key, value, example
+        ))
+    };
+
+    let mut parts = value.split_whitespace();
+    let amount = parts.next().ok_or_else(invalid)?;
+    let unit = parts.next().ok_or_else(invalid)?;
+
+    if parts.next().is_some()
+        || amount.parse::<u64>().map(|n| n == 0).unwrap_or(true)
+        || !units.contains(&unit.to_lowercase().as_str())
+    {
+        return Err(invalid());
+    }
+
+    Ok(())
+}
+
+/// Accepts "day HH:MM", "week D HH:MM" (1-7) or "month D HH:MM" (1-28).
+fn validate_accounting_start(value: &str) -> Result<()> {
+    let invalid = || {
+        NipeError::ConfigError(format!(
+            "Invalid accounting_start '{}': expected \"day HH:MM\", \"week 1-7 HH:MM\" or \"month 1-28 HH:MM\"",
+            value
+        ))
+    };
+
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    let (day_range, time) = match parts.as_slice() {
+        ["day", time] => (None, *time),
+        ["week", day, time] => (Some((day, 1..=7)), *time),
+        ["month", day, time] => (Some((day, 1..=28)), *time),
+        _ => return Err(invalid()),
+    };
+
+    if let Some((day, range)) = day_range {
+        match day.parse::<u8>() {
+            Ok(d) if range.contains(&d) => {}
+            _ => return Err(invalid()),
+        }
+    }
+
+    let (hour, minute) = time.split_once(':').ok_or_else(invalid)?;
+    match (hour.parse::<u8>(), minute.parse::<u8>()) {
+        (Ok(h), Ok(m)) if h < 24 && m < 60 && minute.len() == 2 => Ok(()),
+        _ => Err(invalid()),
     }
 }
@@ -0,0 +1,319 @@
+use crate::config::TorConfig;
+use crate::error::{NipeError, Result};
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+/// An authenticated connection to the Tor control port.
+pub struct TorControl {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+    events: VecDeque<String>,
+}
+
+/// Tor's bootstrap progress, from `GETINFO status/bootstrap-phase`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootstrapPhase {
+    /// Percent complete, 0-100
+    pub progress: u8,
+    /// Machine-readable phase, e.g. `loading_descriptors`
+    pub tag: String,
+    /// Human-readable phase, e.g. "Loading relay descriptors"
+    pub summary: String,
+}
+
+impl BootstrapPhase {
+    /// Parse a bootstrap status line, e.g.
+    /// `NOTICE BOOTSTRAP PROGRESS=45 TAG=loading_descriptors SUMMARY="Loading relay descriptors"`.
+    pub fn parse(value: &str) -> Option<BootstrapPhase> {
+        let mut progress = None;
+        let mut tag = String::new();
+        let mut summary = String::new();
+
+        let mut rest = value.trim();
+        while !rest.is_empty() {
+            let (token, remainder) = match rest.find('=') {
+                Some(eq) if !rest[..eq].contains(' ') && rest[eq + 1..].starts_with('"') => {
+                    // Quoted value, may contain spaces and \" escapes
+                    let body = &rest[eq + 2..];
+                    let mut end = body.len();
+                    let mut escaped = false;
+                    for (i, c) in body.char_indices() {
+                        match c {
+                            _ if escaped => escaped = false,
+                            '\\' => escaped = true,
+                            '"' => {
+                                end = i;
+                                break;
+                            }
+                            _ => {}
+                        }
+                    }
+                    let token = (&rest[..eq], body[..end].replace("\\\"", "\""));
+                    (token, body.get(end + 1..).unwrap_or_default())
+                }
+                _ => {
+                    let end = rest.find(' ').unwrap_or(rest.len());
+                    let word = &rest[..end];
+                    let (key, val) = word.split_once('=').unwrap_or((word, ""));
+                    ((key, val.to_string()), &rest[end..])
+                }
+            };
+
+            match token.0 {
+                "PROGRESS" => progress = token.1.parse().ok(),
+                "TAG" => tag = token.1,
+                "SUMMARY" => summary = token.1,
+                _ => {}
+            }
+            rest = remainder.trim_start();
+        }
+
+        Some(BootstrapPhase {
+            progress: progress?,
+            tag,
+            summary,
+        })
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.progress >= 100
+    }
+}
+
+/// How long to wait for Tor to answer a command.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+impl TorControl {
+    pub async fn connect(tor: &TorConfig) -> Result<Self> {
+        let addr = format!("127.0.0.1:{}", tor.control_port);
+        let stream = TcpStream::connect(&addr).await.map_err(|e| {
+            NipeError::Other(format!("Failed to connect to Tor control port: {}", e))
+        })?;
+        let (reader, writer) = stream.into_split();
+
+        let mut control = Self {
+            reader: BufReader::new(reader),
+            writer,
+            events: VecDeque::new(),
+        };
+
+        control
+            .command(&format!("AUTHENTICATE {}", Self::credentials(tor)))
+            .await
+            .map_err(|e| {
+                NipeError::Other(format!("Tor control port authentication failed: {}", e))
+            })?;
+
+        Ok(control)
+    }
+
+    /// Pick the AUTHENTICATE argument: the configured password, else the
+    /// hex-encoded auth cookie if Tor wrote one, else an empty password.
+    fn credentials(tor: &TorConfig) -> String {
+        if let Some(password) = &tor.control_password {
+            return quote(password);
+        }
+
+        match std::fs::read(tor.cookie_path()) {
+            Ok(cookie) => cookie.iter().map(|b| format!("{:02x}", b)).collect(),
+            Err(_) => quote(""),
+        }
+    }
+
+    /// Send a single command and return the raw reply.
+    pub async fn command(&mut self, command: &str) -> Result<String> {
+        self.writer
+            .write_all(format!("{}\r\n", command).as_bytes())
+            .await?;
+        self.read_reply().await
+    }
+
+    /// Send `GETINFO <key>` and return the value, joining multi-line values.
+    pub async fn getinfo(&mut self, key: &str) -> Result<String> {
+        let reply = self.command(&format!("GETINFO {}", key)).await?;
+        Ok(parse_getinfo(&reply, key))
+    }
+
+    /// Ask Tor how far along bootstrapping is.
+    pub async fn bootstrap_phase(&mut self) -> Result<BootstrapPhase> {
+        let value = self.getinfo("status/bootstrap-phase").await?;
+        BootstrapPhase::parse(&value).ok_or_else(|| {
+            NipeError::Other(format!("Unexpected bootstrap status from Tor: {}", value))
+        })
+    }
+
+    /// Wait up to `wait` for the next asynchronous (650) event, returning
+    /// any that arrived while a command reply was being read first.
+    pub async fn next_event(&mut self, wait: Duration) -> Option<String> {
+        if let Some(event) = self.events.pop_front() {
+            return Some(event);
+        }
+
+        match tokio::time::timeout(wait, self.read_message()).await {
+            Ok(Ok(message)) if message.starts_with("650") => Some(message),
+            _ => None,
+        }
+    }
+
+    /// Read the reply to the last command, setting aside any async events.
+    async fn read_reply(&mut self) -> Result<String> {
+        loop {
+            let message = tokio::time::timeout(REPLY_TIMEOUT, self.read_message())
+                .await
+                .map_err(|_| {
+                    NipeError::Other("Timed out waiting for Tor control port reply".to_string())
+                })??;
+
+            if message.starts_with("650") {
+                self.events.push_back(message);
+                continue;
+            }
+
+            let last = message.trim_end().lines().last().unwrap_or_default();
+            return if last.starts_with('2') {
+                Ok(message)
+            } else {
+                Err(NipeError::Other(format!(
+                    "Tor control port error: {}",
+                    last
+                )))
+            };
+        }
+    }
+
+    /// Read one complete message, including any `+` data blocks.
+    async fn read_message(&mut self) -> Result<String> {
+        let mut message = String::new();
+        let mut in_data = false;
+
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line).await? == 0 {
+                return Err(NipeError::Other(
+                    "Tor control port closed the connection".to_string(),
+                ));
+            }
+            message.push_str(&line);
+
+            let trimmed = line.trim_end();
+            if in_data {
+                if trimmed == "." {
+                    in_data = false;
+                }
+                continue;
+            }
+
+            match trimmed.as_bytes().get(3) {
+                Some(b'+') => in_data = true,
+                Some(b' ') | None => return Ok(message),
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Drop for TorControl {
+    fn drop(&mut self) {
+        // Best effort; Tor closes the connection on its side after QUIT
+        let _ = self.writer.try_write(b"QUIT\r\n");
+    }
+}
+
+/// Quote a string for the control protocol, escaping `\` and `"`.
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Extract the value for `key` from a GETINFO reply.
+///
+/// Handles both the single-line `250-key=value` form and the data block
+/// `250+key=` form terminated by a lone `.`.
+fn parse_getinfo(reply: &str, key: &str) -> String {
+    let single = format!("250-{}=", key);
+    let block = format!("250+{}=", key);
+    let mut lines = reply.lines();
+
+    while let Some(line) = lines.next() {
+        if let Some(value) = line.strip_prefix(&single) {
+            return value.to_string();
+        }
+        if line.starts_with(&block) {
+            return lines
+                .take_while(|l| *l != ".")
+                .collect::<Vec<_>>()
+                .join("\n");
+        }
+    }
+
+    String::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_getinfo_bootstrap_phase() {
+        let reply = "250-status/bootstrap-phase=NOTICE BOOTSTRAP PROGRESS=45 TAG=loading_descriptors SUMMARY=\"Loading relay descriptors\"\r\n250 OK\r\n";
+        let value = parse_getinfo(reply, "status/bootstrap-phase");
+        let phase = BootstrapPhase::parse(&value).unwrap();
+
+        assert_eq!(
+            phase,
+            BootstrapPhase {
+                progress: 45,
+                tag: "loading_descriptors".to_string(),
+                summary: "Loading relay descriptors".to_string(),
+            }
+        );
+        assert!(!phase.is_done());
+    }
+
+    #[test]
+    fn parses_bootstrap_events() {
+        let phase = BootstrapPhase::parse(
+            "650 STATUS_CLIENT NOTICE BOOTSTRAP PROGRESS=100 TAG=done SUMMARY=\"Done\"",
+        )
+        .unwrap();
+        assert_eq!(phase.tag, "done");
+        assert!(phase.is_done());
+
+        // A stalled bootstrap carries extra, sometimes quoted, fields
+        let phase = BootstrapPhase::parse(
+            "650 STATUS_CLIENT WARN BOOTSTRAP PROGRESS=14 TAG=handshake SUMMARY=\"Handshaking with a relay\" WARNING=\"Connection refused\" REASON=CONNECTREFUSED COUNT=3 RECOMMENDATION=ignore HOSTID=\"9695DFC35FFEB861329B9F1AB04C46397020CE31\" HOSTADDR=\"128.31.0.34:9101\"",
+        )
+        .unwrap();
+        assert_eq!(phase.progress, 14);
+        assert_eq!(phase.tag, "handshake");
+        assert_eq!(phase.summary, "Handshaking with a relay");
+    }
+
+    #[test]
+    fn unescapes_quoted_summaries() {
+        let phase = BootstrapPhase::parse(
+            r#"NOTICE BOOTSTRAP PROGRESS=5 TAG=conn SUMMARY="Connecting to \"relay\" now""#,
+        )
+        .unwrap();
+        assert_eq!(phase.summary, r#"Connecting to "relay" now"#);
+    }
+
+    #[test]
+    fn rejects_lines_without_progress() {
+        assert!(BootstrapPhase::parse("").is_none());
+        assert!(BootstrapPhase::parse("NOTICE BOOTSTRAP TAG=starting").is_none());
+        assert!(BootstrapPhase::parse("NOTICE BOOTSTRAP PROGRESS=lots TAG=starting").is_none());
+    }
+
+    #[test]
+    fn reads_getinfo_data_blocks() {
+        let reply = "250+circuit-status=\r\n1 BUILT $9695DFC35FFEB861329B9F1AB04C46397020CE31~moria1 PURPOSE=GENERAL\r\n2 LAUNCHED PURPOSE=GENERAL\r\n.\r\n250 OK\r\n";
+        assert_eq!(
+            parse_getinfo(reply, "circuit-status"),
+            "1 BUILT $9695DFC35FFEB861329B9F1AB04C46397020CE31~moria1 PURPOSE=GENERAL\n2 LAUNCHED PURPOSE=GENERAL"
+        );
+        assert_eq!(parse_getinfo(reply, "version"), "");
+    }
+}
@@ -0,0 +1,198 @@
+use crate::installer::Installer;
+use colored::Colorize;
+use nipe::config::{parse_tor_version, NipeConfig};
+use nipe::control::TorControl;
+use nipe::engine::NipeEngine;
+
+/// Result of one `nipe doctor` check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Pass,
+    Warn,
+    Fail,
+}
+
+pub struct Check {
+    pub name: &'static str,
+    pub level: Level,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, level: Level, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            level,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// Run every setup check for `config`.
+pub async fn run_checks(config: &NipeConfig) -> anyhow::Result<Vec<Check>> {
+    let engine = NipeEngine::new(config.clone())?;
+
+    let mut checks = vec![check_tor(config)];
+    if config.tor.use_bridges {
+        checks.push(check_obfs4proxy(config));
+    }
+    checks.push(check_control_port(config).await);
+    checks.push(check_data_directory(config));
+    checks.push(match engine.check_firewall_backend() {
+        Ok(backend) => Check::new("Firewall", Level::Pass, backend),
+        Err(e) => Check::new("Firewall", Level::Fail, e.to_string()),
+    });
+    checks.extend(check_ports(config, &engine));
+
+    Ok(checks)
+}
+
+/// Print the checklist; returns whether nothing failed.
+pub fn print_checks(checks: &[Check]) -> bool {
+    for check in checks {
+        let mark = match check.level {
+            Level::Pass => "[✓]".bright_green(),
+            Level::Warn => "[!]".yellow(),
+            Level::Fail => "[✗]".bright_red(),
+        };
+        println!("  {} {:<16} {}", mark, check.name.bold(), check.detail);
+    }
+    checks.iter().all(|c| c.level != Level::Fail)
+}
+
+fn check_tor(config: &NipeConfig) -> Check {
+    let name = "Tor";
+    match Installer::tor_version(config) {
+        Ok(version)
+            if parse_tor_version(&version) < parse_tor_version(&config.tor.min_tor_version) =>
+        {
+            Check::new(
+                name,
+                Level::Fail,
+                format!(
+                    "{} is older than min_tor_version {}",
+                    version, config.tor.min_tor_version
+                ),
+            )
+        }
+        Ok(version) => Check::new(name, Level::Pass, version),
+        Err(e) => Check::new(name, Level::Fail, format!("not usable: {}", e)),
+    }
+}
+
+fn check_obfs4proxy(config: &NipeConfig) -> Check {
+    let name = "obfs4proxy";
+    if let Some(plugin) = &config.tor.client_transport_plugin {
+        return if std::path::Path::new(plugin).is_file() {
+            Check::new(name, Level::Pass, plugin.as_str())
+        } else {
+            Check::new(name, Level::Fail, format!("{} does not exist", plugin))
+        };
+    }
+
+    match Installer::obfs4proxy_version() {
+        Ok(version) => Check::new(name, Level::Pass, version),
+        Err(_) => Check::new(
+            name,
+            Level::Fail,
+            "bridges are enabled but obfs4proxy is not in PATH",
+        ),
+    }
+}
+
+async fn check_control_port(config: &NipeConfig) -> Check {
+    let name = "Control port";
+    match TorControl::connect(&config.tor).await {
+        Ok(_) => Check::new(
+            name,
+            Level::Pass,
+            format!("reachable on 127.0.0.1:{}", config.tor.control_port),
+        ),
+        Err(e) => Check::new(name, Level::Warn, format!("{} (is nipe started?)", e)),
+    }
+}
+
+/// Tor refuses a data directory that others can read, or that isn't owned
+/// by the user it runs as.
+fn check_data_directory(config: &NipeConfig) -> Check {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let name = "Data directory";
+    let dir = &config.tor.data_directory;
+    let metadata = match std::fs::metadata(dir) {
+        Ok(metadata) => metadata,
+        Err(_) => {
+            return Check::new(
+                name,
+                Level::Warn,
+                format!("{} does not exist yet (created on start)", dir.display()),
+            )
+        }
+    };
+
+    let tor_uid = NipeEngine::tor_user().map_or(0, |(uid, _)| uid);
+    if metadata.uid() != tor_uid {
+        return Check::new(
+            name,
+            Level::Fail,
+            format!(
+                "{} is owned by uid {}, but Tor runs as uid {}",
+                dir.display(),
+                metadata.uid(),
+                tor_uid
+            ),
+        );
+    }
+
+    let mode = metadata.permissions().mode() & 0o777;
+    if mode & 0o077 != 0 {
+        return Check::new(
+            name,
+            Level::Warn,
+            format!(
+                "{} has mode {:o}; Tor wants 700 (fixed on start)",
+                dir.display(),
+                mode
+            ),
+        );
+    }
+
+    Check::new(name, Level::Pass, format!("{} (mode 700)", dir.display()))
+}
+
+/// Each configured port must be free, or held by the Tor nipe started
+/// for that same option.
+fn check_ports(config: &NipeConfig, engine: &NipeEngine) -> Vec<Check> {
+    let tor_pid = engine.running_tor_pid();
+    let tor_ports = engine.running_tor_ports();
+    let mut ports = engine.tor_ports();
+    if let Some(port) = config.tor.http_proxy_port {
+        ports.push(("HTTP proxy port", port));
+    }
+
+    ports
+        .into_iter()
+        .map(|(name, port)| {
+            let free = if name == "DNSPort" {
+                std::net::UdpSocket::bind(("127.0.0.1", port)).is_ok()
+            } else {
+                std::net::TcpListener::bind(("127.0.0.1", port)).is_ok()
+            };
+            if free {
+                Check::new(name, Level::Pass, format!("{} is free", port))
+            } else if let Some(pid) = tor_pid.filter(|_| tor_ports.contains(&(name, port))) {
+                Check::new(
+                    name,
+                    Level::Pass,
+                    format!("{} is in use by nipe's Tor (pid {})", port, pid),
+                )
+            } else {
+                Check::new(
+                    name,
+                    Level::Fail,
+                    format!("{} is in use by another process", port),
+                )
+            }
+        })
+        .collect()
+}
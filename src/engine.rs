@@ -1,18 +1,34 @@
+use crate::circuit::{Circuit, Relay};
 use crate::config::NipeConfig;
+use crate::control::{BootstrapPhase, TorControl};
 use crate::error::{NipeError, Result};
-use crate::platform::{Firewall, FirewallProvider};
+use crate::metrics::Metrics;
+use crate::platform::{self, Firewall, FirewallProvider};
+use crate::status::ConnectionStatus;
+use colored::Colorize;
 use std::fs::Permissions;
 use std::os::unix::fs::PermissionsExt;
 
 use std::path::PathBuf;
-use tokio::io::AsyncWriteExt;
+use std::sync::Arc;
 use tokio::process::{Child, Command};
+use tokio::sync::mpsc::UnboundedSender;
 use tracing::{debug, info, warn};
 
+/// How long Tor gets to exit after a shutdown request before it is killed.
+const TOR_SHUTDOWN_WAIT: std::time::Duration = std::time::Duration::from_secs(5);
+
 pub struct NipeEngine {
     config: NipeConfig,
     tor_process: Option<Child>,
     tor_user: Option<(u32, u32)>, // uid, gid
+    force: bool,
+    verbose_tor: bool,
+    foreground: bool,
+    log_tail: Option<tokio::task::JoinHandle<()>>,
+    bootstrap_progress: Option<UnboundedSender<BootstrapPhase>>,
+    verification_skipped: bool,
+    metrics: Arc<Metrics>,
 }
 
 impl NipeEngine {
@@ -21,48 +37,61 @@ impl NipeEngine {
             config,
             tor_process: None,
             tor_user: None,
+            force: false,
+            verbose_tor: false,
+            foreground: false,
+            log_tail: None,
+            bootstrap_progress: None,
+            verification_skipped: false,
+            metrics: Arc::default(),
         })
     }
 
-    fn find_tor_user() -> Option<(u32, u32)> {
-        // Try standard Tor users
-        let users = ["debian-tor", "tor", "nobody"];
+    /// Allow start to proceed past a failed HTTP verification as long as the
+    /// control port reports an established circuit.
+    pub fn set_force(&mut self, force: bool) {
+        self.force = force;
+    }
 
-        for user in users {
-            let output = std::process::Command::new("id")
-                .arg("-u")
-                .arg(user)
-                .output()
-                .ok()?;
-
-            if output.status.success() {
-                let uid_str = String::from_utf8_lossy(&output.stdout).trim().to_string();
-                if let Ok(uid) = uid_str.parse::<u32>() {
-                    // Get GID as well
-                    let output_gid = std::process::Command::new("id")
-                        .arg("-g")
-                        .arg(user)
-                        .output()
-                        .ok()?;
-
-                    let gid_str = String::from_utf8_lossy(&output_gid.stdout)
-                        .trim()
-                        .to_string();
-                    if let Ok(gid) = gid_str.parse::<u32>() {
-                        info!(
-                            "Found unprivileged user for Tor: {} (uid: {}, gid: {})",
-                            user, uid, gid
-                        );
-                        return Some((uid, gid));
-                    }
-                }
+    /// Echo Tor's log to the console while waiting for bootstrap.
+    pub fn set_verbose_tor(&mut self, verbose_tor: bool) {
+        self.verbose_tor = verbose_tor;
+    }
+
+    /// Keep Tor attached after start and follow its log; see `supervise`.
+    pub fn set_foreground(&mut self, foreground: bool) {
+        self.foreground = foreground;
+    }
+
+    /// Send each new bootstrap phase here instead of logging it, so the
+    /// caller can draw its own progress display.
+    pub fn set_bootstrap_progress(&mut self, sender: UnboundedSender<BootstrapPhase>) {
+        self.bootstrap_progress = Some(sender);
+    }
+
+    /// Whether the last start skipped external verification because of `--force`.
+    pub fn verification_skipped(&self) -> bool {
+        self.verification_skipped
+    }
+
+    /// Rotation and bootstrap counters for `--metrics-addr`.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// The Tor executable to run: `tor_binary` if set, else the first of the
+    /// usual install locations that exists, else plain `tor` from PATH.
+    pub fn find_tor_path(&self) -> Result<String> {
+        if let Some(path) = &self.config.tor.tor_binary {
+            if !path.is_file() {
+                return Err(NipeError::ConfigError(format!(
+                    "Tor binary not found: {}",
+                    path.display()
+                )));
             }
+            return Ok(path.display().to_string());
         }
-        warn!("No unprivileged user found. Tor will run as root!");
-        None
-    }
 
-    fn find_tor_path() -> String {
         let common_paths = [
             "/usr/bin/tor",
             "/usr/sbin/tor",
@@ -73,12 +102,78 @@ impl NipeEngine {
 
         for path in common_paths {
             if std::path::Path::new(path).exists() {
-                return path.to_string();
+                return Ok(path.to_string());
             }
         }
 
         // Fallback to system PATH
-        "tor".to_string()
+        Ok("tor".to_string())
+    }
+
+    /// Whether Tor will need obfs4proxy: bridges are on and one is obfs4.
+    fn uses_obfs4_bridges(&self) -> bool {
+        let tor = &self.config.tor;
+        tor.use_bridges
+            && tor
+                .bridges
+                .iter()
+                .any(|bridge| bridge.trim_start().starts_with("obfs4 "))
+    }
+
+    /// The obfs4proxy to run: `client_transport_plugin` if set, else the
+    /// first one in the usual install locations or on PATH.
+    fn find_obfs4proxy(&self) -> Result<String> {
+        if let Some(path) = &self.config.tor.client_transport_plugin {
+            if !std::path::Path::new(path).is_file() {
+                return Err(NipeError::TransportPluginMissing(format!(
+                    "client_transport_plugin {} does not exist",
+                    path
+                )));
+            }
+            return Ok(path.clone());
+        }
+
+        #[cfg(not(target_os = "windows"))]
+        let (common_paths, exe) = (
+            [
+                "/usr/bin/obfs4proxy",
+                "/usr/local/bin/obfs4proxy",
+                "/opt/homebrew/bin/obfs4proxy",
+            ],
+            "obfs4proxy",
+        );
+
+        #[cfg(target_os = "windows")]
+        let (common_paths, exe) = (
+            [
+                r"C:\Program Files\Tor\obfs4proxy.exe",
+                r"C:\Program Files (x86)\Tor\obfs4proxy.exe",
+            ],
+            "obfs4proxy.exe",
+        );
+
+        if let Some(path) = common_paths
+            .iter()
+            .find(|p| std::path::Path::new(p).is_file())
+        {
+            return Ok(path.to_string());
+        }
+
+        std::env::var_os("PATH")
+            .and_then(|path| {
+                std::env::split_paths(&path)
+                    .map(|dir| dir.join(exe))
+                    .find(|candidate| candidate.is_file())
+            })
+            .map(|path| path.display().to_string())
+            .ok_or_else(|| {
+                NipeError::TransportPluginMissing(format!(
+                    "{} was not found in {} or PATH; install it (e.g. 'apt install obfs4proxy') \
+                     or set tor.client_transport_plugin",
+                    exe,
+                    common_paths.join(", ")
+                ))
+            })
     }
 
     fn set_owner(path: &std::path::Path, uid: u32, gid: u32) -> Result<()> {
@@ -94,13 +189,121 @@ impl NipeEngine {
         Ok(())
     }
 
+    /// Re-own every entry in the data directory that doesn't belong to the
+    /// user Tor will run as, failing with a precise message if that's not possible.
+    fn fix_data_dir_ownership(&self, uid: u32, gid: u32) -> Result<()> {
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::MetadataExt;
+
+        let data_dir = &self.config.tor.data_directory;
+        let mut pending = vec![data_dir.clone()];
+        let mut mismatched = Vec::new();
+
+        while let Some(path) = pending.pop() {
+            let meta = std::fs::symlink_metadata(&path)?;
+            if meta.uid() != uid || meta.gid() != gid {
+                mismatched.push((path.clone(), meta.uid()));
+            }
+            if meta.is_dir() {
+                for entry in std::fs::read_dir(&path)? {
+                    pending.push(entry?.path());
+                }
+            }
+        }
+
+        if mismatched.is_empty() {
+            return Ok(());
+        }
+
+        let (first, owner) = &mismatched[0];
+        warn!(
+            "{} entries in {} are not owned by the Tor user (uid {}), e.g. {} (uid {}); fixing ownership",
+            mismatched.len(),
+            data_dir.display(),
+            uid,
+            first.display(),
+            owner
+        );
+
+        for (path, owner) in &mismatched {
+            let path_c = std::ffi::CString::new(path.as_os_str().as_bytes())
+                .map_err(|e| NipeError::Other(e.to_string()))?;
+            // lchown so a symlink planted in the directory can't redirect the chown
+            if unsafe { libc::lchown(path_c.as_ptr(), uid, gid) } != 0 {
+                return Err(NipeError::TorStartFailed(format!(
+                    "{} is owned by uid {} but Tor runs as uid {} and it could not be changed ({}). \
+                     Fix it with: sudo chown -R {}:{} {}",
+                    path.display(),
+                    owner,
+                    uid,
+                    std::io::Error::last_os_error(),
+                    uid,
+                    gid,
+                    data_dir.display()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove Tor's `lock` file left behind by an unclean shutdown, after
+    /// confirming no live process still holds it.
+    fn clear_stale_lock(&self) -> Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let lock_path = self.config.tor.data_directory.join("lock");
+        let Ok(file) = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&lock_path)
+        else {
+            return Ok(());
+        };
+
+        // Tor holds an flock on the file while running
+        let held = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0;
+        if held {
+            let holder = std::process::Command::new("pgrep")
+                .arg("-f")
+                .arg(format!("tor -f {}", self.torrc_path().display()))
+                .output()
+                .ok()
+                .map(|o| {
+                    String::from_utf8_lossy(&o.stdout)
+                        .trim()
+                        .replace('\n', ", ")
+                })
+                .filter(|pids| !pids.is_empty());
+
+            return Err(NipeError::TorStartFailed(format!(
+                "Another Tor process{} is using {}; stop it first (nipe stop)",
+                holder.map(|p| format!(" (pid {})", p)).unwrap_or_default(),
+                self.config.tor.data_directory.display()
+            )));
+        }
+
+        drop(file);
+        info!(
+            "Removing stale Tor lock file {} left by an unclean shutdown",
+            lock_path.display()
+        );
+        std::fs::remove_file(&lock_path)?;
+        Ok(())
+    }
+
     pub async fn start(&mut self) -> Result<()> {
         info!("Starting Nipe engine");
+        self.config.validate()?;
 
         // 1. Stop any existing instance
         let _ = self.stop().await;
 
-        match self.start_internal().await {
+        let result = self.start_internal().await;
+        // Whatever happened, let a progress display know we're finished
+        self.bootstrap_progress = None;
+
+        match result {
             Ok(_) => Ok(()),
             Err(e) => {
                 warn!("Start failed, performing rollback: {}", e);
@@ -111,15 +314,48 @@ impl NipeEngine {
     }
 
     async fn start_internal(&mut self) -> Result<()> {
+        // 1. Make sure Tor can bind its ports and, with obfs4 bridges, run
+        // the transport; otherwise it only logs the failure and the
+        // bootstrap wait below times out
+        self.check_ports_free()?;
+        if self.uses_obfs4_bridges() {
+            self.find_obfs4proxy()?;
+        }
+
         // 2. Create data directory
         // 1.5 Find Tor user
-        self.tor_user = Self::find_tor_user();
+        self.tor_user = platform::find_tor_user();
+        match self.tor_user {
+            Some((uid, gid)) => info!(
+                "Found unprivileged user for Tor (uid: {}, gid: {})",
+                uid, gid
+            ),
+            None => warn!("No unprivileged user found. Tor will run as root!"),
+        }
 
         // 2. Create data directory with secure permissions
-        // Ensure parent dir exists
-        let parent = self.config.tor.data_directory.parent().unwrap();
-        debug!("Creating parent directory: {:?}", parent);
-        std::fs::create_dir_all(parent)?;
+        // Ensure the working directory exists
+        let work_dir = self.config.tor.work_dir();
+        debug!("Creating working directory: {:?}", work_dir);
+        std::fs::create_dir_all(&work_dir)?;
+
+        // Tor only reads the torrc and reaches its data directory through
+        // here, so the directory stays root's; earlier versions gave it to
+        // the Tor user, so drop anything it may have left for root to
+        // write through
+        std::fs::set_permissions(&work_dir, Permissions::from_mode(0o755))?;
+        let (root_uid, root_gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        Self::set_owner(&work_dir, root_uid, root_gid)?;
+        for path in [
+            self.torrc_path(),
+            self.config.tor.log_path(),
+            ConnectionStatus::cache_path(&self.config.tor),
+        ] {
+            if path.symlink_metadata().is_ok() && !platform::owned_by_us(&path) {
+                std::fs::remove_file(&path)?;
+            }
+        }
+        platform::private_dir(&self.config.tor.state_dir())?;
 
         debug!(
             "Creating data directory: {:?}",
@@ -134,13 +370,21 @@ impl NipeEngine {
             Permissions::from_mode(0o700),
         )?;
 
-        // Set ownership if we have a target user
-        if let Some((uid, gid)) = self.tor_user {
-            debug!("Setting owner on data directory to {}:{}", uid, gid);
-            Self::set_owner(&self.config.tor.data_directory, uid, gid)?;
-        }
+        // Tor refuses a DataDirectory (or files in it) owned by another user,
+        // e.g. left over from a run without privilege dropping
+        let (uid, gid) = self
+            .tor_user
+            .unwrap_or_else(|| unsafe { (libc::geteuid(), libc::getegid()) });
+        self.fix_data_dir_ownership(uid, gid)?;
+        self.clear_stale_lock()?;
 
         // 3. Generate torrc
+        if self.config.tor.uses_country_selection() && self.config.tor.geoip_file_path().is_none() {
+            warn!(
+                "Country-based exit selection is configured but no GeoIP file was found; \
+                 Tor may match no exits. Install Tor's geoip package or set tor.geoip_file"
+            );
+        }
         debug!("Generating torrc");
         let torrc_path = self.generate_torrc()?;
         debug!("Generated torrc at: {:?}", torrc_path);
@@ -154,13 +398,7 @@ impl NipeEngine {
         // 4. Start Tor process
         info!("Starting Tor process");
         // Redirect Tor logs to file
-        let log_dir = self
-            .config
-            .tor
-            .data_directory
-            .parent()
-            .unwrap()
-            .to_path_buf();
+        let log_dir = self.config.tor.work_dir();
 
         // Ensure log dir exists with secure permissions
         if !log_dir.exists() {
@@ -169,19 +407,7 @@ impl NipeEngine {
             })?;
         }
 
-        // Determine ownership
-        let (uid, gid) = if let Some((u, g)) = self.tor_user {
-            (u, g)
-        } else {
-            // Fallback to current user (root) if no user found, but we warn about this
-            unsafe { (libc::geteuid(), libc::getegid()) }
-        };
-
-        // Apply permissions to log dir
-        std::fs::set_permissions(&log_dir, Permissions::from_mode(0o755))?; // needs to be readable
-        Self::set_owner(&log_dir, uid, gid)?;
-
-        let log_file_path = log_dir.join("tor.log");
+        let log_file_path = self.config.tor.log_path();
         let log_file = std::fs::File::create(&log_file_path).map_err(|e| {
             NipeError::TorStartFailed(format!(
                 "Failed to create log file {}: {}",
@@ -201,7 +427,7 @@ impl NipeEngine {
             .map_err(|e| NipeError::TorStartFailed(format!("Failed to clone log handle: {}", e)))?;
 
         // Resolve absolute path to Tor to avoid PATH issues with sudo
-        let tor_cmd = Self::find_tor_path();
+        let tor_cmd = self.find_tor_path()?;
         debug!("Using Tor binary at: {}", tor_cmd);
 
         let mut cmd = Command::new(tor_cmd);
@@ -220,47 +446,136 @@ impl NipeEngine {
             .spawn()
             .map_err(|e| NipeError::TorStartFailed(e.to_string()))?;
 
+        // Later invocations have no Child handle, so `stop` finds Tor by pid
+        if let Some(pid) = child.id() {
+            std::fs::write(self.tor_pid_path(), pid.to_string())?;
+        }
         self.tor_process = Some(child);
 
         // 5. Wait for Tor to bootstrap
         info!("Waiting for Tor to bootstrap");
-        self.wait_for_bootstrap().await?;
+        let tail = (self.verbose_tor || self.foreground)
+            .then(|| Self::tail_tor_log(log_file_path.clone()));
+        let bootstrap = self.wait_for_bootstrap().await;
+        match tail {
+            // In the foreground the log keeps streaming until `supervise` ends
+            Some(tail) if self.foreground && bootstrap.is_ok() => self.log_tail = Some(tail),
+            Some(tail) => tail.abort(),
+            None => {}
+        }
+        bootstrap?;
+        self.metrics.record_bootstrap();
 
         // 6. Configure firewall/kill switch
-        info!("Configuring firewall");
-        let firewall = Firewall::new()?;
-        firewall.enable_kill_switch()?;
-        firewall.enable_socks_proxy(self.config.tor.socks_port)?;
+        // Named instances share the host with the default one, so they only
+        // expose their SOCKS port and leave system-wide state alone.
+        if self.is_system_wide() {
+            info!("Configuring firewall");
+            let firewall = Firewall::new(&self.config)?;
+            firewall.enable_kill_switch()?;
+            firewall.enable_socks_proxy(self.config.tor.socks_port)?;
+            if self.config.firewall.web_proxy {
+                firewall.enable_web_proxy(self.config.tor.http_tunnel_port())?;
+            } else if cfg!(target_os = "macos") {
+                warn!(
+                    "Only the SOCKS proxy is set; apps that read just the HTTP/HTTPS proxy \
+                     settings will not use Tor (their traffic is blocked by the kill switch). \
+                     Set firewall.web_proxy = true to also configure the HTTPS web proxy"
+                );
+            }
+        } else {
+            info!(
+                "Instance ready: SOCKS proxy at 127.0.0.1:{} (system firewall untouched)",
+                self.config.tor.socks_port
+            );
+        }
+
+        // 7. Optional HTTP-to-SOCKS proxy for apps without SOCKS support
+        if let Some(port) = self.config.tor.http_proxy_port {
+            self.start_http_proxy(port)?;
+        }
 
         info!("Nipe engine started successfully");
 
         // Detach Tor process so it keeps running after CLI exits
         // The Drop impl kills it if it's still in self.tor_process
-        let _ = self.tor_process.take();
+        if !self.foreground {
+            let _ = self.tor_process.take();
+        }
 
         Ok(())
     }
 
+    /// Block until Ctrl-C or until Tor exits on its own, then run the full
+    /// stop sequence. Used after a foreground `start`.
+    pub async fn supervise(&mut self) -> Result<()> {
+        let exit_status = match self.tor_process.as_mut() {
+            Some(child) => tokio::select! {
+                status = child.wait() => Some(status?),
+                _ = tokio::signal::ctrl_c() => None,
+            },
+            None => {
+                tokio::signal::ctrl_c().await?;
+                None
+            }
+        };
+
+        if let Some(tail) = self.log_tail.take() {
+            tail.abort();
+        }
+        if exit_status.is_some() {
+            // Already reaped; let stop() clean up via the pidfile
+            self.tor_process = None;
+        }
+
+        self.stop().await?;
+
+        match exit_status {
+            Some(status) => Err(NipeError::Other(format!(
+                "Tor exited unexpectedly ({})",
+                status
+            ))),
+            None => Ok(()),
+        }
+    }
+
     pub async fn stop(&mut self) -> Result<()> {
         info!("Stopping Nipe engine");
 
         // 1. Disable firewall
-        let firewall = Firewall::new()?;
-        firewall.disable_kill_switch()?;
-        firewall.disable_socks_proxy()?;
+        if self.is_system_wide() {
+            let firewall = Firewall::new(&self.config)?;
+            firewall.disable_kill_switch()?;
+            firewall.disable_socks_proxy()?;
+        }
+
+        // 2. Stop the HTTP proxy helper, if one was started
+        self.stop_http_proxy();
+        ConnectionStatus::clear_cached(&self.config.tor);
 
-        // 2. Stop Tor process
+        // 3. Stop Tor process, cleanly if it still answers on the control port
         if let Some(mut process) = self.tor_process.take() {
-            info!("Killing Tor process");
-            process
-                .kill()
-                .await
-                .map_err(|e| NipeError::TorStopFailed(e.to_string()))?;
+            info!("Stopping Tor process");
+            let exited = self.request_tor_shutdown().await
+                && tokio::time::timeout(TOR_SHUTDOWN_WAIT, process.wait())
+                    .await
+                    .is_ok();
+            if !exited {
+                warn!("Tor did not shut down cleanly, killing it");
+                process
+                    .kill()
+                    .await
+                    .map_err(|e| NipeError::TorStopFailed(e.to_string()))?;
+            }
+            let _ = std::fs::remove_file(self.tor_pid_path());
+        } else if let Some(pid) = self.read_tor_pid() {
+            self.stop_tor_pid(pid).await?;
         } else {
-            // Try to kill any running Tor process
+            // Try to kill the Tor process started with this instance's torrc,
+            // whatever the binary is called (see `tor_binary`)
             let _ = Command::new("pkill")
-                .arg("-f")
-                .arg("tor -f /tmp/nipe_torrc")
+                .args(["-f", "--"])
+                .arg(format!(" -f {}$", self.torrc_path().display()))
                 .output()
                 .await;
         }
@@ -272,35 +587,148 @@ impl NipeEngine {
     pub async fn rotate(&self) -> Result<()> {
         info!("Rotating Tor identity");
 
-        // Send NEWNYM signal via control port
-        let addr = format!("127.0.0.1:{}", self.config.tor.control_port);
-        let mut stream = tokio::net::TcpStream::connect(&addr).await.map_err(|e| {
-            NipeError::Other(format!("Failed to connect to Tor control port: {}", e))
-        })?;
-
-        // Authenticate (no password)
-        stream.write_all(b"AUTHENTICATE \"\"\r\n").await?;
-
-        // Send NEWNYM signal
-        stream.write_all(b"SIGNAL NEWNYM\r\n").await?;
+        // Authenticate (failing loudly if Tor rejects it) and send NEWNYM
+        let mut control = TorControl::connect(&self.config.tor).await?;
+        control.command("SETEVENTS NOTICE").await?;
+        control.command("SIGNAL NEWNYM").await?;
+
+        // Tor still answers 250 OK when it rate-limits NEWNYM; the only sign
+        // is a notice, so watch the event stream briefly for it
+        let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(1);
+        while let Some(event) = control
+            .next_event(deadline.saturating_duration_since(tokio::time::Instant::now()))
+            .await
+        {
+            if let Some(idx) = event.find("Rate limiting NEWNYM") {
+                return Err(NipeError::Other(format!(
+                    "Tor rate-limited the rotation ({}); wait a few seconds and try again",
+                    event[idx..].trim()
+                )));
+            }
+        }
 
         info!("Identity rotation signal sent");
+        self.metrics.record_rotation();
+
+        // The cached exit is stale now. NEWNYM only affects new circuits, so
+        // a check right away could still see the old exit; `nipe rotate`
+        // saves the new one once it shows up
+        ConnectionStatus::clear_cached(&self.config.tor);
         Ok(())
     }
 
-    async fn wait_for_bootstrap(&self) -> Result<()> {
+    /// Follow Tor's log file and print notice-and-above lines until aborted.
+    fn tail_tor_log(path: PathBuf) -> tokio::task::JoinHandle<()> {
+        use tokio::io::AsyncBufReadExt;
+
+        tokio::spawn(async move {
+            let Ok(file) = tokio::fs::File::open(&path).await else {
+                warn!("Cannot follow Tor log at {}", path.display());
+                return;
+            };
+            let mut reader = tokio::io::BufReader::new(file);
+            let mut line = String::new();
+
+            loop {
+                line.clear();
+                match reader.read_line(&mut line).await {
+                    Ok(0) => tokio::time::sleep(std::time::Duration::from_millis(200)).await,
+                    Ok(_) => {
+                        // Strip the timestamp: "Oct 16 12:00:00.000 [notice] ..."
+                        let entry = line
+                            .find('[')
+                            .map_or(line.trim_end(), |i| line[i..].trim_end());
+                        let shown = ["[notice]", "[warn]", "[err]"]
+                            .iter()
+                            .any(|level| entry.starts_with(level));
+                        if shown {
+                            println!("    {} {}", "tor".dimmed(), entry);
+                        }
+                    }
+                    Err(_) => return,
+                }
+            }
+        })
+    }
+
+    async fn wait_for_bootstrap(&mut self) -> Result<()> {
         use tokio::time::{sleep, Duration};
 
-        let max_attempts = 60; // Increased from 30 to 60 seconds
-        for attempt in 0..max_attempts {
+        // With --force, give the HTTP check a few more attempts after the
+        // circuit is up before skipping external verification.
+        const FORCE_GRACE_ATTEMPTS: u64 = 5;
+
+        self.verification_skipped = false;
+        let mut circuit_since: Option<u64> = None;
+
+        // One attempt per second
+        let max_attempts = self.config.tor.bootstrap_timeout_secs;
+        let mut attempt = 0;
+
+        // Follow Tor's own bootstrap progress on the control port; only fall
+        // back to the external check while the control port can't be queried
+        let mut last_progress = None;
+        while attempt < max_attempts {
+            match self.bootstrap_phase().await {
+                Ok(phase) => {
+                    let done = phase.is_done();
+                    if last_progress != Some(phase.progress) {
+                        last_progress = Some(phase.progress);
+                        match &self.bootstrap_progress {
+                            Some(sender) => {
+                                let _ = sender.send(phase);
+                            }
+                            None => info!("Bootstrapped {}%: {}", phase.progress, phase.summary),
+                        }
+                    }
+                    if done {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    debug!("Bootstrap status unavailable: {}", e);
+                    if self.check_tor_connection().await.is_ok() {
+                        info!("Tor bootstrap complete");
+                        return Ok(());
+                    }
+                }
+            }
+
+            attempt += 1;
+            sleep(Duration::from_secs(1)).await;
+        }
+
+        // Bootstrapped; close the channel so the caller's display finishes
+        self.bootstrap_progress = None;
+
+        // Confirm traffic really leaves through Tor
+        for attempt in attempt..max_attempts {
             if self.check_tor_connection().await.is_ok() {
                 info!("Tor bootstrap complete");
                 return Ok(());
             }
 
+            if self.force {
+                if circuit_since.is_none() && self.circuit_established().await {
+                    info!("Control port reports an established circuit");
+                    circuit_since = Some(attempt);
+                }
+
+                if let Some(since) = circuit_since {
+                    if attempt - since >= FORCE_GRACE_ATTEMPTS {
+                        warn!(
+                            "External verification failed but Tor has an established circuit; \
+                             continuing because --force was given"
+                        );
+                        self.verification_skipped = true;
+                        return Ok(());
+                    }
+                }
+            }
+
             if attempt % 5 == 0 {
                 info!(
-                    "Waiting for Tor bootstrap... ({}/{})",
+                    "Waiting for Tor connection check... ({}/{})",
                     attempt, max_attempts
                 );
             }
@@ -308,7 +736,53 @@ impl NipeEngine {
             sleep(Duration::from_secs(1)).await;
         }
 
-        Err(NipeError::BootstrapTimeout)
+        Err(NipeError::BootstrapTimeout(max_attempts))
+    }
+
+    async fn bootstrap_phase(&self) -> Result<BootstrapPhase> {
+        TorControl::connect(&self.config.tor)
+            .await?
+            .bootstrap_phase()
+            .await
+    }
+
+    async fn circuit_established(&self) -> bool {
+        let established = match TorControl::connect(&self.config.tor).await {
+            Ok(mut control) => control.getinfo("status/circuit-established").await,
+            Err(e) => Err(e),
+        };
+
+        match established {
+            Ok(value) => value == "1",
+            Err(e) => {
+                debug!("Control port query failed: {}", e);
+                false
+            }
+        }
+    }
+
+    /// List Tor's current circuits, resolving each relay's address and country.
+    pub async fn circuits(&self) -> Result<Vec<Circuit>> {
+        let mut control = TorControl::connect(&self.config.tor).await?;
+        let mut circuits = Circuit::parse_status(&control.getinfo("circuit-status").await?);
+
+        for relay in circuits.iter_mut().flat_map(|c| c.hops.iter_mut()) {
+            let ns = control
+                .getinfo(&format!("ns/id/{}", relay.fingerprint))
+                .await
+                .unwrap_or_default();
+            relay.ip = Relay::parse_ns_address(&ns);
+
+            if let Some(ip) = &relay.ip {
+                relay.country = control
+                    .getinfo(&format!("ip-to-country/{}", ip))
+                    .await
+                    .ok()
+                    .filter(|cc| !cc.is_empty() && cc != "??");
+            }
+        }
+
+        Ok(circuits)
     }
 
     async fn check_tor_connection(&self) -> Result<()> {
@@ -319,14 +793,15 @@ impl NipeEngine {
             .timeout(std::time::Duration::from_secs(5))
             .build()?;
 
-        let response = client
-            .get("https://check.torproject.org/api/ip")
-            .send()
-            .await?;
-
-        let json: serde_json::Value = response.json().await?;
+        let mut status = ConnectionStatus::check_with(client, &self.config.tor.check_url)
+            .await
+            .map_err(|e| NipeError::Other(e.to_string()))?;
 
-        if json["IsTor"].as_bool() == Some(true) {
+        if status.is_tor {
+            status.check_exit_country(&self.config.tor).await;
+            if let Err(e) = status.save_cached(&self.config.tor) {
+                debug!("Cannot save the status for `status --cached`: {}", e);
+            }
             Ok(())
         } else {
             Err(NipeError::NotConnected)
@@ -334,108 +809,787 @@ impl NipeEngine {
     }
 
     fn generate_torrc(&self) -> Result<PathBuf> {
+        let path = self.torrc_path();
+        std::fs::write(&path, self.torrc_contents()?)?;
+        Ok(path)
+    }
+
+    /// The torrc `start` runs Tor with: `external_torrc` if set, else one
+    /// generated from the config.
+    pub fn torrc_contents(&self) -> Result<String> {
+        if let Some(external) = &self.config.tor.external_torrc {
+            return self.external_torrc_contents(external);
+        }
+
         // Handle Bridge Configuration
         let bridge_config = if self.config.tor.use_bridges {
             let mut config = String::from("\n# Bridge Configuration\nUseBridges 1\n");
 
-            // 1. ClientTransportPlugin
-            if let Some(path) = &self.config.tor.client_transport_plugin {
+            // 1. ClientTransportPlugin (`client_transport_plugin` or a found obfs4proxy)
+            if let Ok(p) = self.find_obfs4proxy() {
+                // On Windows, paths with spaces must be quoted, but usually torrc handles exec paths well
+                // However, passing raw backslashes can be tricky.
+                config.push_str(&format!("ClientTransportPlugin obfs4 exec {}\n", p));
+            } else if let Some(path) = &self.config.tor.client_transport_plugin {
+                // Missing; `start` refuses to run, but --dry-run still shows it
                 config.push_str(&format!("ClientTransportPlugin obfs4 exec {}\n", path));
             } else {
-                // Try to find obfs4proxy in path, otherwise fallback to standard paths
+                // Fallback logic
                 #[cfg(not(target_os = "windows"))]
-                let paths = [
-                    "/usr/bin/obfs4proxy",
-                    "/usr/local/bin/obfs4proxy",
-                    "/opt/homebrew/bin/obfs4proxy",
-                ];
+                config.push_str("ClientTransportPlugin obfs4 exec /usr/bin/obfs4proxy\n");
 
                 #[cfg(target_os = "windows")]
-                let paths = [
-                    r"C:\Program Files\Tor\obfs4proxy.exe",
-                    r"C:\Program Files (x86)\Tor\obfs4proxy.exe",
-                ];
-
-                let found_path = paths.iter().find(|p| std::path::Path::new(p).exists());
-
-                if let Some(p) = found_path {
-                    // On Windows, paths with spaces must be quoted, but usually torrc handles exec paths well
-                    // However, passing raw backslashes can be tricky.
-                    config.push_str(&format!("ClientTransportPlugin obfs4 exec {}\n", p));
-                } else {
-                    // Fallback logic
-                    #[cfg(not(target_os = "windows"))]
-                    config.push_str("ClientTransportPlugin obfs4 exec /usr/bin/obfs4proxy\n");
-
-                    #[cfg(target_os = "windows")]
-                    config.push_str("ClientTransportPlugin obfs4 exec obfs4proxy.exe\n");
-                    // Hope it's in PATH
-                }
+                config.push_str("ClientTransportPlugin obfs4 exec obfs4proxy.exe\n");
+                // Hope it's in PATH
             }
 
             // 2. Add Bridges
+            if let Some(mode) = self.config.tor.bridge_iat_mode {
+                if mode > 0 {
+                    warn!(
+                        "obfs4 iat-mode={} is harder for DPI to detect but adds latency and lowers throughput",
+                        mode
+                    );
+                }
+            }
             for bridge in &self.config.tor.bridges {
-                config.push_str(&format!("Bridge {}\n", bridge));
+                let line = match self.config.tor.bridge_iat_mode {
+                    Some(mode) => Self::with_iat_mode(bridge, mode),
+                    None => bridge.clone(),
+                };
+                config.push_str(&format!("Bridge {}\n", line));
             }
             config
         } else {
             String::new()
         };
 
+        let mut accounting_config = String::new();
+        if let Some(max) = &self.config.tor.accounting_max {
+            accounting_config.push_str(&format!(
+                "\n# Bandwidth accounting\nAccountingMax {}\n",
+                max
+            ));
+            if let Some(start) = &self.config.tor.accounting_start {
+                accounting_config.push_str(&format!("AccountingStart {}\n", start));
+            }
+        }
+
+        let mut bandwidth_config = String::new();
+        if let Some(rate) = &self.config.tor.bandwidth_rate {
+            bandwidth_config.push_str(&format!("\n# Bandwidth limits\nBandwidthRate {}\n", rate));
+        }
+        if let Some(burst) = &self.config.tor.bandwidth_burst {
+            if bandwidth_config.is_empty() {
+                bandwidth_config.push_str("\n# Bandwidth limits\n");
+            }
+            bandwidth_config.push_str(&format!("BandwidthBurst {}\n", burst));
+        }
+
+        let mut control_auth = match &self.config.tor.control_password {
+            Some(password) => format!(
+                "HashedControlPassword {}\n",
+                hash_control_password(password)?
+            ),
+            None => String::new(),
+        };
+        if self.config.tor.cookie_authentication {
+            control_auth.push_str(&format!(
+                "CookieAuthentication 1\nCookieAuthFile {}\n",
+                self.config.tor.cookie_path().display()
+            ));
+        }
+
+        // The kill switch redirects TCP and DNS here; instances leave the
+        // system firewall alone and only need their SOCKS port
+        let transparent = if self.is_system_wide() {
+            format!(
+                "TransPort {}\nDNSPort {}\n",
+                self.config.tor.trans_port, self.config.tor.dns_port
+            )
+        } else {
+            String::new()
+        };
+
+        let http_tunnel = if self.config.firewall.web_proxy {
+            format!("HTTPTunnelPort {}\n", self.config.tor.http_tunnel_port())
+        } else {
+            String::new()
+        };
+
+        let mut dormant_config = String::new();
+        if let Some(value) = self.config.tor.dormant_canceled_by_startup {
+            dormant_config.push_str(&format!("DormantCanceledByStartup {}\n", value as u8));
+        }
+        if let Some(value) = self.config.tor.dormant_timeout_disabled_by_idle_streams {
+            dormant_config.push_str(&format!(
+                "DormantTimeoutDisabledByIdleStreams {}\n",
+                value as u8
+            ));
+        }
+
+        let mut padding_config = String::new();
+        if let Some(enabled) = self.config.tor.connection_padding {
+            padding_config.push_str(&format!("ConnectionPadding {}\n", enabled as u8));
+        }
+        if self.config.tor.reduced_connection_padding {
+            padding_config.push_str("ReducedConnectionPadding 1\n");
+        }
+        if self.config.tor.connection_padding == Some(false)
+            || self.config.tor.reduced_connection_padding
+        {
+            warn!("Connection padding is reduced or disabled; this lowers resistance to traffic analysis");
+        }
+
+        let mut timeout_config = String::new();
+        if let Some(secs) = self.config.tor.circuit_build_timeout {
+            // Tor otherwise learns its own timeout and ignores this value
+            timeout_config.push_str(&format!(
+                "LearnCircuitBuildTimeout 0\nCircuitBuildTimeout {}\n",
+                secs
+            ));
+        }
+        if let Some(secs) = self.config.tor.socks_timeout {
+            timeout_config.push_str(&format!("SocksTimeout {}\n", secs));
+        }
+
+        let mut geoip_config = String::new();
+        if let Some(path) = &self.config.tor.geoip_file {
+            geoip_config.push_str(&format!("GeoIPFile {}\n", path.display()));
+        }
+        if let Some(path) = &self.config.tor.geoip6_file {
+            geoip_config.push_str(&format!("GeoIPv6File {}\n", path.display()));
+        }
+
+        // Node selection: exits by list or country, pinned guards, exclusions
+        let tor = &self.config.tor;
+        let node_list = |entries: &[String]| -> String {
+            entries
+                .iter()
+                .map(|n| crate::config::torrc_node_entry(n))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        let mut node_config = String::new();
+        let mut strict_nodes = false;
+        if !tor.exit_nodes.is_empty() {
+            node_config.push_str(&format!("ExitNodes {}\n", node_list(&tor.exit_nodes)));
+        } else {
+            let countries: Vec<String> = tor
+                .countries()
+                .iter()
+                .map(|c| format!("{{{}}}", c))
+                .collect();
+            if !countries.is_empty() {
+                node_config.push_str(&format!("ExitNodes {}\n", countries.join(",")));
+                strict_nodes = true;
+            }
+        }
+        if !tor.entry_nodes.is_empty() {
+            if tor.use_bridges {
+                warn!("entry_nodes is ignored while bridges are enabled; the bridges are the entry guards");
+            }
+            node_config.push_str(&format!("EntryNodes {}\n", node_list(&tor.entry_nodes)));
+            strict_nodes = true;
+        }
+        if !tor.exclude_exit_nodes.is_empty() {
+            node_config.push_str(&format!(
+                "ExcludeExitNodes {}\n",
+                node_list(&tor.exclude_exit_nodes)
+            ));
+            strict_nodes = true;
+        }
+        if !tor.exclude_nodes.is_empty() {
+            node_config.push_str(&format!("ExcludeNodes {}\n", node_list(&tor.exclude_nodes)));
+            strict_nodes = true;
+        }
+        if strict_nodes {
+            node_config.push_str("StrictNodes 1\n");
+        }
+
+        let mut socks_ports = format!("SocksPort {}\n", tor.primary_socks_port().torrc_value());
+        for extra in &tor.extra_socks_ports {
+            socks_ports.push_str(&format!("SocksPort {}\n", extra.torrc_value()));
+        }
+
+        let mut hidden_service_config = String::new();
+        if !tor.hidden_services.is_empty() {
+            hidden_service_config.push_str("\n# Hidden services\n");
+        }
+        for service in &tor.hidden_services {
+            hidden_service_config.push_str(&format!(
+                "HiddenServiceDir {}\nHiddenServicePort {} {}\n",
+                tor.hidden_service_dir(&service.name).display(),
+                service.virtual_port,
+                service.target
+            ));
+        }
+
+        let mut extra_config = String::new();
+        if !tor.extra_torrc_lines.is_empty() {
+            extra_config.push_str("\n# Extra lines from extra_torrc_lines\n");
+            for line in &tor.extra_torrc_lines {
+                extra_config.push_str(line);
+                extra_config.push('\n');
+            }
+        }
+
         let torrc_content = format!(
             r#"
 # Nipe Tor Configuration
-SocksPort {}
-ControlPort {}
-DataDirectory {}
+{}ControlPort {}
+{}{}{}DataDirectory {}
 
 # Basic settings
 Log notice stdout
 DisableNetwork 0
-{}
-# Exit nodes preference (if specified)
-{}
-"#,
-            self.config.tor.socks_port,
+{}{}{}{}{}{}{}
+# Node selection preferences (if specified)
+{}{}{}"#,
+            socks_ports,
             self.config.tor.control_port,
+            transparent,
+            control_auth,
+            http_tunnel,
             self.config.tor.data_directory.display(),
+            dormant_config,
+            padding_config,
+            timeout_config,
+            geoip_config,
             bridge_config,
-            if self.config.tor.exit_nodes.is_empty() {
-                if let Some(country) = &self.config.tor.country {
-                    format!("ExitNodes {{{}}}\nStrictNodes 1", country)
-                } else {
-                    String::new()
+            accounting_config,
+            bandwidth_config,
+            node_config,
+            hidden_service_config,
+            extra_config
+        );
+
+        Ok(torrc_content)
+    }
+
+    /// Launch `nipe http-proxy` as a detached helper so it outlives this CLI
+    /// invocation, recording its pid for `stop`.
+    fn start_http_proxy(&self, port: u16) -> Result<()> {
+        use std::os::unix::process::CommandExt;
+        use std::process::Stdio;
+
+        let exe = std::env::current_exe()
+            .map_err(|e| NipeError::Other(format!("Cannot locate nipe binary: {}", e)))?;
+
+        let child = std::process::Command::new(exe)
+            .arg("http-proxy")
+            .arg("--port")
+            .arg(port.to_string())
+            .arg("--socks-port")
+            .arg(self.config.tor.socks_port.to_string())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .process_group(0)
+            .spawn()
+            .map_err(|e| NipeError::Other(format!("Failed to start HTTP proxy: {}", e)))?;
+
+        std::fs::write(self.http_proxy_pid_path(), child.id().to_string())?;
+        info!("HTTP proxy available at 127.0.0.1:{}", port);
+        Ok(())
+    }
+
+    fn stop_http_proxy(&self) {
+        let pid_path = self.http_proxy_pid_path();
+        if !platform::owned_by_us(&pid_path) {
+            return;
+        }
+        let Ok(pid) = std::fs::read_to_string(&pid_path) else {
+            return;
+        };
+
+        match pid.trim().parse::<i32>() {
+            Ok(pid) if pid > 0 && self.is_our_http_proxy(pid) => {
+                info!("Stopping HTTP proxy (pid {})", pid);
+                unsafe {
+                    libc::kill(pid, libc::SIGTERM);
                 }
-            } else {
-                format!("ExitNodes {{{}}}", self.config.tor.exit_nodes.join(","))
             }
-        );
+            Ok(pid) if unsafe { libc::kill(pid, 0) == 0 } => warn!(
+                "pid {} from the HTTP proxy pidfile is not nipe's proxy, not signalling it",
+                pid
+            ),
+            Ok(pid) => debug!("HTTP proxy (pid {}) from the pidfile is not running", pid),
+            Err(_) => {}
+        }
+        let _ = std::fs::remove_file(pid_path);
+    }
 
-        let path = self
-            .config
-            .tor
-            .data_directory
-            .parent()
-            .unwrap()
-            .join("torrc");
-        std::fs::write(&path, torrc_content)?;
+    /// Whether `pid` is the `nipe http-proxy` helper `start_http_proxy` runs.
+    fn is_our_http_proxy(&self, pid: i32) -> bool {
+        let args = format!(" --socks-port {}", self.config.tor.socks_port);
+        process_cmdline(pid).is_some_and(|cmdline| {
+            cmdline.contains(" http-proxy --port ") && cmdline.ends_with(&args)
+        })
+    }
 
-        Ok(path)
+    fn tor_pid_path(&self) -> PathBuf {
+        self.config.tor.state_dir().join("tor.pid")
+    }
+
+    /// The pid in the Tor pidfile, unless someone other than us wrote it.
+    fn read_tor_pid(&self) -> Option<i32> {
+        let path = self.tor_pid_path();
+        if !platform::owned_by_us(&path) {
+            return None;
+        }
+        std::fs::read_to_string(path)
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+            .filter(|pid| *pid > 0)
+    }
+
+    /// Whether `pid` is Tor running our torrc, and not some process that
+    /// took over the pid of a Tor that crashed or didn't survive a reboot.
+    fn is_our_tor(&self, pid: i32) -> bool {
+        let args = format!(" -f {}", self.torrc_path().display());
+        process_cmdline(pid).is_some_and(|cmdline| cmdline.ends_with(&args))
+    }
+
+    /// Ask Tor to exit through the control port, so it closes its circuits
+    /// and writes its state file. Returns whether Tor accepted the request.
+    async fn request_tor_shutdown(&self) -> bool {
+        let mut control = match TorControl::connect(&self.config.tor).await {
+            Ok(control) => control,
+            Err(e) => {
+                debug!("Cannot ask Tor to shut down: {}", e);
+                return false;
+            }
+        };
+        match control.command("SIGNAL SHUTDOWN").await {
+            Ok(_) => true,
+            Err(e) => {
+                debug!("Tor refused SIGNAL SHUTDOWN: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Poll `alive` until it turns false, for at most [`TOR_SHUTDOWN_WAIT`].
+    async fn wait_for_exit(alive: impl Fn() -> bool) -> bool {
+        let deadline = tokio::time::Instant::now() + TOR_SHUTDOWN_WAIT;
+        while alive() {
+            if tokio::time::Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+        }
+        true
+    }
+
+    /// Stop the Tor process from the pidfile: ask it through the control
+    /// port, then SIGTERM it, then SIGKILL it if it is still around after a
+    /// few seconds.
+    async fn stop_tor_pid(&self, pid: i32) -> Result<()> {
+        let alive = || unsafe { libc::kill(pid, 0) == 0 };
+
+        if alive() && !self.is_our_tor(pid) {
+            warn!(
+                "pid {} from the pidfile is not nipe's Tor, not signalling it",
+                pid
+            );
+        } else if alive() {
+            info!("Stopping Tor process (pid {})", pid);
+            let exited = self.request_tor_shutdown().await && Self::wait_for_exit(alive).await;
+            if !exited {
+                debug!("Tor (pid {}) is still running, sending SIGTERM", pid);
+                unsafe {
+                    libc::kill(pid, libc::SIGTERM);
+                }
+                Self::wait_for_exit(alive).await;
+            }
+
+            if alive() {
+                warn!("Tor (pid {}) ignored SIGTERM, sending SIGKILL", pid);
+                if unsafe { libc::kill(pid, libc::SIGKILL) } != 0 {
+                    return Err(NipeError::TorStopFailed(format!(
+                        "Failed to kill Tor (pid {}): {}",
+                        pid,
+                        std::io::Error::last_os_error()
+                    )));
+                }
+            }
+        } else {
+            debug!("Tor (pid {}) from the pidfile is not running", pid);
+        }
+
+        let _ = std::fs::remove_file(self.tor_pid_path());
+        Ok(())
+    }
+
+    fn http_proxy_pid_path(&self) -> PathBuf {
+        self.config.tor.state_dir().join("http-proxy.pid")
+    }
+
+    /// Set `iat-mode` on an obfs4 bridge line, replacing any existing value.
+    /// Other transports are returned unchanged.
+    fn with_iat_mode(bridge: &str, mode: u8) -> String {
+        if !bridge.trim_start().starts_with("obfs4 ") {
+            return bridge.to_string();
+        }
+
+        let mut parts: Vec<String> = bridge
+            .split_whitespace()
+            .filter(|p| !p.starts_with("iat-mode="))
+            .map(|p| p.to_string())
+            .collect();
+        parts.push(format!("iat-mode={}", mode));
+        parts.join(" ")
+    }
+
+    /// The contents of `external_torrc`, once it is known to listen where
+    /// Nipe connects.
+    fn external_torrc_contents(&self, external: &std::path::Path) -> Result<String> {
+        let contents = std::fs::read_to_string(external).map_err(|e| {
+            NipeError::ConfigError(format!(
+                "Cannot read external_torrc {}: {}",
+                external.display(),
+                e
+            ))
+        })?;
+
+        let tor = &self.config.tor;
+        let mut required = vec![
+            ("SocksPort", tor.socks_port),
+            ("ControlPort", tor.control_port),
+        ];
+        if self.is_system_wide() {
+            required.push(("TransPort", tor.trans_port));
+            required.push(("DNSPort", tor.dns_port));
+        }
+        for (option, port) in required {
+            if torrc_ports(&contents, option).contains(&port) {
+                continue;
+            }
+            let message = format!(
+                "external_torrc {} does not set \"{} {}\"",
+                external.display(),
+                option,
+                port
+            );
+            match option {
+                // Nipe cannot check the connection or rotate without these
+                "SocksPort" | "ControlPort" => return Err(NipeError::ConfigError(message)),
+                // The kill switch would redirect traffic to a closed port
+                _ => warn!("{}; redirected traffic will be dropped", message),
+            }
+        }
+
+        Ok(format!(
+            "# Copied from {}\n{}",
+            external.display(),
+            contents
+        ))
+    }
+
+    pub fn torrc_path(&self) -> PathBuf {
+        self.config.tor.work_dir().join("torrc")
+    }
+
+    /// Delete everything `start` leaves on disk: the data directory, torrc,
+    /// tor.log and pid files, and the working directory if that leaves it
+    /// empty. Returns the paths that were removed.
+    pub fn remove_files(&self) -> Result<Vec<PathBuf>> {
+        let mut removed = Vec::new();
+
+        let data_dir = &self.config.tor.data_directory;
+        if data_dir.exists() {
+            std::fs::remove_dir_all(data_dir)?;
+            removed.push(data_dir.clone());
+        }
+
+        for path in [
+            self.torrc_path(),
+            self.config.tor.log_path(),
+            self.tor_pid_path(),
+            self.http_proxy_pid_path(),
+        ] {
+            if path.exists() {
+                std::fs::remove_file(&path)?;
+                removed.push(path);
+            }
+        }
+
+        // Both only fail when something else lives there, which we leave alone
+        let state_dir = self.config.tor.state_dir();
+        if state_dir.exists() && std::fs::remove_dir(&state_dir).is_ok() {
+            removed.push(state_dir);
+        }
+        let work_dir = self.config.tor.work_dir();
+        if work_dir.exists() && std::fs::remove_dir(&work_dir).is_ok() {
+            removed.push(work_dir);
+        }
+
+        Ok(removed)
+    }
+
+    /// The default instance owns the kill switch and system proxy.
+    fn is_system_wide(&self) -> bool {
+        self.config.instance.is_none()
     }
 
-    #[allow(dead_code)]
     pub fn config(&self) -> &NipeConfig {
         &self.config
     }
+
+    /// The firewall tool the kill switch uses on this system, e.g. "nftables".
+    pub fn firewall_backend(&self) -> Result<&'static str> {
+        Ok(Firewall::new(&self.config)?.backend())
+    }
+
+    /// Turn the kill switch on or off by itself, leaving Tor and the system
+    /// proxy as they are. Named instances never touch the firewall.
+    pub fn set_kill_switch(&self, on: bool) -> Result<()> {
+        if !self.is_system_wide() {
+            return Err(NipeError::FirewallError(
+                "named instances don't manage the kill switch".to_string(),
+            ));
+        }
+        let firewall = Firewall::new(&self.config)?;
+        if on {
+            firewall.enable_kill_switch()
+        } else {
+            firewall.disable_kill_switch()
+        }
+    }
+
+    /// The commands `start` would run to set up the kill switch and system
+    /// proxy, for `start --dry-run`; empty for a named instance.
+    pub fn describe_firewall(&self) -> Result<Vec<String>> {
+        if !self.is_system_wide() {
+            return Ok(vec![]);
+        }
+        let web_proxy_port = self
+            .config
+            .firewall
+            .web_proxy
+            .then(|| self.config.tor.http_tunnel_port());
+        Ok(
+            Firewall::new(&self.config)?
+                .describe_enable(self.config.tor.socks_port, web_proxy_port),
+        )
+    }
+
+    /// Check that the firewall tool is installed; returns its name.
+    pub fn check_firewall_backend(&self) -> Result<&'static str> {
+        let firewall = Firewall::new(&self.config)?;
+        if firewall.backend_available() {
+            Ok(firewall.backend())
+        } else {
+            Err(NipeError::FirewallError(format!(
+                "{} is not installed",
+                firewall.backend()
+            )))
+        }
+    }
+
+    /// The account Tor is run as, `(uid, gid)`; `None` means root.
+    pub fn tor_user() -> Option<(u32, u32)> {
+        platform::find_tor_user()
+    }
+
+    /// The .onion address of each configured hidden service, read from the
+    /// `hostname` file Tor writes once the service is up; `None` until then.
+    pub fn onion_addresses(&self) -> Vec<(String, Result<Option<String>>)> {
+        let tor = &self.config.tor;
+        tor.hidden_services
+            .iter()
+            .map(|service| {
+                let path = tor.hidden_service_dir(&service.name).join("hostname");
+                let hostname = match std::fs::read_to_string(&path) {
+                    Ok(hostname) => Ok(Some(format!(
+                        "{}:{}",
+                        hostname.trim(),
+                        service.virtual_port
+                    ))),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                    Err(e) => Err(e.into()),
+                };
+                (service.name.clone(), hostname)
+            })
+            .collect()
+    }
+
+    /// The ports Tor listens on with this configuration, by torrc option.
+    pub fn tor_ports(&self) -> Vec<(&'static str, u16)> {
+        let tor = &self.config.tor;
+        let mut ports = vec![
+            ("SocksPort", tor.socks_port),
+            ("ControlPort", tor.control_port),
+        ];
+        for extra in &tor.extra_socks_ports {
+            ports.push(("SocksPort", extra.port));
+        }
+        if self.is_system_wide() {
+            ports.push(("TransPort", tor.trans_port));
+            ports.push(("DNSPort", tor.dns_port));
+        }
+        if self.config.firewall.web_proxy {
+            ports.push(("HTTPTunnelPort", tor.http_tunnel_port()));
+        }
+        ports
+    }
+
+    /// Fail if another process already holds one of Tor's ports.
+    fn check_ports_free(&self) -> Result<()> {
+        for (option, port) in self.tor_ports() {
+            // DNSPort is UDP; every other port is TCP
+            let in_use = if option == "DNSPort" {
+                std::net::UdpSocket::bind(("127.0.0.1", port)).is_err()
+            } else {
+                std::net::TcpListener::bind(("127.0.0.1", port)).is_err()
+            };
+            if in_use {
+                return Err(NipeError::TorStartFailed(format!(
+                    "{} {} is already in use by another process",
+                    option, port
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Pid of the Tor process started by `start`, if it is still running.
+    pub fn running_tor_pid(&self) -> Option<i32> {
+        self.read_tor_pid()
+            .filter(|pid| unsafe { libc::kill(*pid, 0) == 0 } && self.is_our_tor(*pid))
+    }
+
+    /// The ports the running Tor listens on, as `(option, port)` like
+    /// [`tor_ports`](Self::tor_ports), read from the torrc it was started
+    /// with. Empty when nipe's Tor is not running.
+    pub fn running_tor_ports(&self) -> Vec<(&'static str, u16)> {
+        if self.running_tor_pid().is_none() {
+            return vec![];
+        }
+        let Ok(contents) = std::fs::read_to_string(self.torrc_path()) else {
+            return vec![];
+        };
+
+        [
+            "SocksPort",
+            "ControlPort",
+            "TransPort",
+            "DNSPort",
+            "HTTPTunnelPort",
+        ]
+        .into_iter()
+        .flat_map(|option| {
+            torrc_ports(&contents, option)
+                .into_iter()
+                .map(move |port| (option, port))
+        })
+        .collect()
+    }
 }
 
 impl Drop for NipeEngine {
+    /// Kill a Tor process that was never detached, e.g. when `start` failed
+    /// part-way. `start_kill` works on every platform and is a no-op for a
+    /// process that has already exited.
     fn drop(&mut self) {
-        if let Some(process) = self.tor_process.take() {
-            let _ = std::process::Command::new("kill")
-                .arg("-9")
-                .arg(process.id().unwrap().to_string())
-                .output();
+        if let Some(mut process) = self.tor_process.take() {
+            if let Err(e) = process.start_kill() {
+                debug!("Failed to kill Tor on drop: {}", e);
+            }
+            let _ = std::fs::remove_file(self.tor_pid_path());
         }
     }
 }
+
+/// Hash a control port password the way `tor --hash-password` does,
+/// without putting the password on a command line other users can read.
+fn hash_control_password(password: &str) -> Result<String> {
+    let mut salt = [0u8; 8];
+    getrandom::getrandom(&mut salt)
+        .map_err(|e| NipeError::ConfigError(format!("Failed to hash control password: {}", e)))?;
+    Ok(s2k_hash(password, salt))
+}
+
+/// Tor's RFC 2440 iterated and salted S2K with its fixed count byte 0x60
+/// (65536 bytes hashed), as `16:` and the hex of salt, count and digest.
+fn s2k_hash(password: &str, salt: [u8; 8]) -> String {
+    use sha1::{Digest, Sha1};
+
+    const COUNT_BYTE: u8 = 0x60;
+    let mut count = (16 + (COUNT_BYTE as usize & 15)) << ((COUNT_BYTE >> 4) + 6);
+    let input = [&salt[..], password.as_bytes()].concat();
+    let mut sha1 = Sha1::new();
+    while count > 0 {
+        let n = count.min(input.len());
+        sha1.update(&input[..n]);
+        count -= n;
+    }
+
+    let mut hashed = String::from("16:");
+    for byte in salt.iter().chain([&COUNT_BYTE]).chain(&sha1.finalize()[..]) {
+        hashed.push_str(&format!("{:02X}", byte));
+    }
+    hashed
+}
+
+/// The command line of process `pid`, arguments joined with spaces.
+#[cfg(target_os = "linux")]
+fn process_cmdline(pid: i32) -> Option<String> {
+    let raw = std::fs::read(format!("/proc/{}/cmdline", pid)).ok()?;
+    let args: Vec<_> = raw
+        .split(|b| *b == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(String::from_utf8_lossy)
+        .collect();
+    Some(args.join(" "))
+}
+
+/// The command line of process `pid`, as `ps` shows it.
+#[cfg(not(target_os = "linux"))]
+fn process_cmdline(pid: i32) -> Option<String> {
+    let output = std::process::Command::new("ps")
+        .args(["-ww", "-o", "command=", "-p", &pid.to_string()])
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Ports set by every `option` line in torrc `contents`, whether written as
+/// `PORT` or `ADDRESS:PORT`.
+fn torrc_ports(contents: &str, option: &str) -> Vec<u16> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            let key = words.next()?;
+            if !key.eq_ignore_ascii_case(option) {
+                return None;
+            }
+            let value = words.next()?;
+            value.rsplit(':').next()?.parse().ok()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_control_passwords_like_tor() {
+        assert_eq!(
+            s2k_hash("nipe", [1, 2, 3, 4, 5, 6, 7, 8]),
+            "16:010203040506070860BD4E02E42E0ED98F03885F2147551C0877E99901"
+        );
+        assert_eq!(
+            s2k_hash("", [0; 8]),
+            "16:0000000000000000601ADC95BEBE9EEA8C112D40CD04AB7A8D75C4F961"
+        );
+    }
+}
@@ -8,8 +8,11 @@ pub enum NipeError {
     #[error("Tor process failed to stop: {0}")]
     TorStopFailed(String),
 
-    #[error("Tor bootstrap timeout")]
-    BootstrapTimeout,
+    #[error("Tor did not bootstrap within {0}s (raise tor.bootstrap_timeout_secs on slow links or bridges)")]
+    BootstrapTimeout(u64),
+
+    #[error("obfs4 bridges need a pluggable transport: {0}")]
+    TransportPluginMissing(String),
 
     #[error("Not connected to Tor network")]
     NotConnected,
@@ -20,7 +23,6 @@ pub enum NipeError {
     #[error("Network interface not found")]
     InterfaceNotFound,
 
-    #[allow(dead_code)]
     #[error("Configuration error: {0}")]
     ConfigError(String),
 
@@ -35,3 +37,37 @@ pub enum NipeError {
 }
 
 pub type Result<T> = std::result::Result<T, NipeError>;
+
+/// Exit codes of the `nipe` binary, so wrapper scripts can tell failures apart.
+pub mod exit_code {
+    /// Any failure without a more specific code
+    pub const FAILURE: i32 = 1;
+    /// Invalid command-line usage (the code clap uses too)
+    pub const USAGE: i32 = 2;
+    /// Tor is not installed and could not be installed, or failed to run
+    pub const TOR_UNAVAILABLE: i32 = 3;
+    /// Tor did not finish bootstrapping in time
+    pub const BOOTSTRAP_TIMEOUT: i32 = 4;
+    /// The kill switch or system proxy could not be changed
+    pub const FIREWALL: i32 = 5;
+    /// Traffic is not going through Tor
+    pub const NOT_CONNECTED: i32 = 6;
+    /// Not running as root/Administrator
+    pub const NOT_ROOT: i32 = 7;
+    /// The configuration is invalid
+    pub const CONFIG: i32 = 8;
+}
+
+impl NipeError {
+    /// The process exit code for this error (see [`exit_code`]).
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            NipeError::TorStartFailed(_) => exit_code::TOR_UNAVAILABLE,
+            NipeError::BootstrapTimeout(_) => exit_code::BOOTSTRAP_TIMEOUT,
+            NipeError::FirewallError(_) | NipeError::InterfaceNotFound => exit_code::FIREWALL,
+            NipeError::NotConnected => exit_code::NOT_CONNECTED,
+            NipeError::ConfigError(_) => exit_code::CONFIG,
+            _ => exit_code::FAILURE,
+        }
+    }
+}
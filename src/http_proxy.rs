@@ -0,0 +1,211 @@
+use crate::error::{NipeError, Result};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+/// Largest request head we accept before giving up on a client.
+const MAX_HEADER_BYTES: usize = 16 * 1024;
+
+/// Serve an HTTP proxy on `127.0.0.1:listen_port` that tunnels every request
+/// through Tor's SOCKS port.
+///
+/// Supports `CONNECT host:port` (HTTPS and other TLS traffic) and absolute-form
+/// requests such as `GET http://host/path` (plain `http_proxy=` users).
+pub async fn serve(listen_port: u16, socks_port: u16) -> Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", listen_port))
+        .await
+        .map_err(|e| {
+            NipeError::Other(format!(
+                "Failed to listen on 127.0.0.1:{}: {}",
+                listen_port, e
+            ))
+        })?;
+    info!(
+        "HTTP proxy listening on 127.0.0.1:{} (via SOCKS 127.0.0.1:{})",
+        listen_port, socks_port
+    );
+
+    loop {
+        let (client, peer) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(client, socks_port).await {
+                debug!("HTTP proxy connection from {} ended: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle_client(client: TcpStream, socks_port: u16) -> Result<()> {
+    let mut client = BufReader::new(client);
+
+    // Read the request head (request line + headers)
+    let mut head = Vec::new();
+    loop {
+        let mut line = String::new();
+        if client.read_line(&mut line).await? == 0 {
+            return Ok(());
+        }
+        let end = line == "\r\n" || line == "\n";
+        head.push(line);
+        if end {
+            break;
+        }
+        if head.iter().map(|l| l.len()).sum::<usize>() > MAX_HEADER_BYTES {
+            return respond(client.get_mut(), "431 Request Header Fields Too Large").await;
+        }
+    }
+
+    let mut parts = head[0].split_whitespace();
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(m), Some(t), Some(v)) => (m.to_string(), t.to_string(), v.to_string()),
+        _ => return respond(client.get_mut(), "400 Bad Request").await,
+    };
+
+    if method.eq_ignore_ascii_case("CONNECT") {
+        let Some((host, port)) = split_host_port(&target, 443) else {
+            return respond(client.get_mut(), "400 Bad Request").await;
+        };
+        let mut upstream = match socks_connect(socks_port, &host, port).await {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("CONNECT {} failed: {}", target, e);
+                return respond(client.get_mut(), "502 Bad Gateway").await;
+            }
+        };
+
+        client
+            .get_mut()
+            .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+            .await?;
+        // Forward anything the client already sent past the request head
+        let buffered = client.buffer().to_vec();
+        upstream.write_all(&buffered).await?;
+        let mut client = client.into_inner();
+        tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+        return Ok(());
+    }
+
+    // Absolute-form request: http://host[:port]/path
+    let Some(rest) = target.strip_prefix("http://") else {
+        return respond(client.get_mut(), "400 Bad Request").await;
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(idx) => (&rest[..idx], &rest[idx..]),
+        None => (rest, "/"),
+    };
+    let Some((host, port)) = split_host_port(authority, 80) else {
+        return respond(client.get_mut(), "400 Bad Request").await;
+    };
+
+    let mut upstream = match socks_connect(socks_port, &host, port).await {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("{} {} failed: {}", method, target, e);
+            return respond(client.get_mut(), "502 Bad Gateway").await;
+        }
+    };
+
+    // Rewrite to origin-form, drop proxy headers, and close after one
+    // response so a reused connection can't be pointed at another host.
+    let mut request = format!("{} {} {}\r\n", method, path, version);
+    for line in &head[1..head.len() - 1] {
+        let name = line
+            .split(':')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        if name.starts_with("proxy-") || name == "connection" || name == "keep-alive" {
+            continue;
+        }
+        request.push_str(line);
+    }
+    request.push_str("Connection: close\r\n\r\n");
+    upstream.write_all(request.as_bytes()).await?;
+
+    let buffered = client.buffer().to_vec();
+    upstream.write_all(&buffered).await?;
+    let mut client = client.into_inner();
+    tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+    Ok(())
+}
+
+async fn respond(client: &mut TcpStream, status: &str) -> Result<()> {
+    client
+        .write_all(format!("HTTP/1.1 {}\r\nConnection: close\r\n\r\n", status).as_bytes())
+        .await?;
+    Ok(())
+}
+
+/// Split `host:port` (or `[v6]:port`), using `default_port` when absent.
+fn split_host_port(authority: &str, default_port: u16) -> Option<(String, u16)> {
+    if let Some(rest) = authority.strip_prefix('[') {
+        let (host, after) = rest.split_once(']')?;
+        let port = match after.strip_prefix(':') {
+            Some(p) => p.parse().ok()?,
+            None => default_port,
+        };
+        return Some((host.to_string(), port));
+    }
+
+    match authority.rsplit_once(':') {
+        Some((host, port)) => Some((host.to_string(), port.parse().ok()?)),
+        None if !authority.is_empty() => Some((authority.to_string(), default_port)),
+        None => None,
+    }
+}
+
+/// Open a connection to `host:port` through Tor's SOCKS5 port, letting Tor
+/// resolve the hostname so DNS never leaves the Tor network.
+async fn socks_connect(socks_port: u16, host: &str, port: u16) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(("127.0.0.1", socks_port)).await?;
+
+    // Greeting: version 5, one method, "no authentication"
+    stream.write_all(&[0x05, 0x01, 0x00]).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply != [0x05, 0x00] {
+        return Err(NipeError::Other(
+            "SOCKS proxy refused authentication method".to_string(),
+        ));
+    }
+
+    // CONNECT with a domain name address
+    let host = host.as_bytes();
+    if host.len() > 255 {
+        return Err(NipeError::Other("Hostname too long".to_string()));
+    }
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, host.len() as u8];
+    request.extend_from_slice(host);
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    if header[1] != 0x00 {
+        return Err(NipeError::Other(format!(
+            "SOCKS connect failed (code {})",
+            header[1]
+        )));
+    }
+
+    // Skip the bound address, whose length depends on the address type
+    let addr_len = match header[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            len[0] as usize
+        }
+        other => {
+            return Err(NipeError::Other(format!(
+                "Unexpected SOCKS address type {}",
+                other
+            )))
+        }
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+
+    Ok(stream)
+}
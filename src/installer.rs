@@ -1,117 +1,186 @@
-use crate::config::NipeConfig;
 use colored::Colorize;
+use nipe::config::{parse_tor_version, NipeConfig};
+use nipe::engine::NipeEngine;
+use nipe::error::exit_code;
+use std::path::PathBuf;
 use std::process::Command;
 use tracing::info;
 
 pub struct Installer;
 
+/// What to do when Tor is missing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstallPolicy {
+    /// Ask before running the package manager
+    Prompt,
+    /// Install without asking (`--yes`)
+    Yes,
+    /// Never install; a missing Tor is an error (`--no-install`)
+    Never,
+}
+
 impl Installer {
-    pub fn ensure_prerequisites(config: &NipeConfig) -> anyhow::Result<()> {
+    pub fn ensure_prerequisites(config: &NipeConfig, policy: InstallPolicy) -> anyhow::Result<()> {
         // 1. Check Tor
-        println!("{}", "[+] Checking Tor installation...".cyan());
-        if let Err(e) = Self::check_and_install_tor() {
+        say!("{}", "[+] Checking Tor installation...".cyan());
+        if let Some(path) = &config.tor.tor_binary {
+            // An explicit binary is checked when starting; never install over it
+            info!("Using configured Tor binary at {}", path.display());
+        } else if let Err(e) = Self::check_and_install_tor(policy) {
             eprintln!("{} {}", "[✗] Tor installation failed:".bright_red(), e);
             eprintln!(
                 "\n{}",
                 "Please install Tor manually and try again.".yellow()
             );
-            std::process::exit(1);
-        }
-
-        // 2. Check obfs4proxy
-        if config.tor.use_bridges
-            && config.tor.client_transport_plugin.is_none()
-            && Self::check_obfs4proxy().is_err()
-        {
-            eprintln!(
-                "{}",
-                "[!] Warning: Bridge support is enabled but 'obfs4proxy' was not found in PATH."
-                    .yellow()
-            );
-            eprintln!(
-                "{}",
-                "    Tor functionality might fail if bridges are required.".yellow()
-            );
-            eprintln!("{}", "    Please install it system-wide (e.g. 'brew install obfs4proxy' or 'apt install obfs4proxy').".yellow());
+            std::process::exit(exit_code::TOR_UNAVAILABLE);
         }
+        Self::check_tor_version(config)?;
 
-        // 3. Self-install
-        Self::check_and_install_system_wide()?;
-
+        // obfs4proxy is checked by `NipeEngine::start`, which fails without it
         Ok(())
     }
 
-    fn check_and_install_system_wide() -> anyhow::Result<()> {
+    /// Where `nipe install` puts the binary.
+    pub fn system_install_path() -> PathBuf {
         #[cfg(target_os = "windows")]
         {
-            let install_dir = std::path::Path::new("C:\\Program Files\\Nipe");
-            let install_path = install_dir.join("nipe.exe");
-            if let Ok(current_exe) = std::env::current_exe() {
-                if current_exe != install_path {
-                    println!("{}", "[+] Checking system-wide installation...".cyan());
-                    if !install_dir.exists() {
-                        let _ = std::fs::create_dir_all(install_dir);
-                    }
-                    match std::fs::copy(&current_exe, &install_path) {
-                        Ok(_) => println!(
-                            "{}",
-                            "[✓] Installed Nipe to C:\\Program Files\\Nipe\\nipe.exe".green()
-                        ),
-                        Err(e) => eprintln!(
-                            "{} {}",
-                            "[!] Failed to install system-wide (ignoring):".yellow(),
-                            e
-                        ),
-                    }
-                }
-            }
+            PathBuf::from("C:\\Program Files\\Nipe\\nipe.exe")
         }
         #[cfg(not(target_os = "windows"))]
         {
-            if let Ok(current_exe) = std::env::current_exe() {
-                let install_dir = std::path::Path::new("/usr/local/bin");
-                let install_path = install_dir.join("nipe");
-                // Don't try to install if we are running from the target path
-                if current_exe != install_path {
-                    println!("{}", "[+] Checking system-wide installation...".cyan());
-                    if !install_dir.exists() {
-                        let _ = std::fs::create_dir_all(install_dir);
-                    }
-                    // simple copy
-                    match std::fs::copy(&current_exe, &install_path) {
-                        Ok(_) => {
-                            println!("{}", "[✓] Installed Nipe to /usr/local/bin/nipe".green())
-                        }
-                        Err(e) => eprintln!(
-                            "{} {}",
-                            "[!] Failed to install system-wide (ignoring):".yellow(),
-                            e
-                        ),
-                    }
-                }
-            }
+            PathBuf::from("/usr/local/bin/nipe")
         }
-        Ok(())
     }
-    pub fn check_and_install_tor() -> anyhow::Result<()> {
+
+    /// Copy the running binary to [`Self::system_install_path`]. Only done on
+    /// request (`nipe install`), since it may replace a package-managed copy.
+    pub fn install_self() -> anyhow::Result<PathBuf> {
+        let current_exe = std::env::current_exe()?;
+        let install_path = Self::system_install_path();
+
+        // Nothing to do when we are already running from the target path
+        if current_exe == install_path {
+            return Ok(install_path);
+        }
+
+        if let Some(install_dir) = install_path.parent() {
+            std::fs::create_dir_all(install_dir)?;
+        }
+        std::fs::copy(&current_exe, &install_path).map_err(|e| {
+            anyhow::anyhow!("Failed to copy nipe to {}: {}", install_path.display(), e)
+        })?;
+
+        Ok(install_path)
+    }
+
+    /// Remove the copy made by [`Self::install_self`], if there is one.
+    pub fn uninstall_self() -> anyhow::Result<Option<PathBuf>> {
+        let install_path = Self::system_install_path();
+        if !install_path.exists() {
+            return Ok(None);
+        }
+        std::fs::remove_file(&install_path)
+            .map_err(|e| anyhow::anyhow!("Failed to remove {}: {}", install_path.display(), e))?;
+        Ok(Some(install_path))
+    }
+
+    pub fn check_and_install_tor(policy: InstallPolicy) -> anyhow::Result<()> {
         // Check if Tor is installed
         if Self::is_tor_installed() {
             info!("Tor is already installed");
             return Ok(());
         }
 
+        match policy {
+            InstallPolicy::Never => {
+                return Err(anyhow::anyhow!(
+                    "Tor is not installed and --no-install was given"
+                ))
+            }
+            InstallPolicy::Prompt
+                if !Self::confirm(
+                    "Tor is not installed. Install it with the system package manager?",
+                )? =>
+            {
+                return Err(anyhow::anyhow!("Tor is not installed"));
+            }
+            _ => {}
+        }
+
         info!("Tor not found. Installing automatically...");
         Self::install_tor()?;
 
         Ok(())
     }
 
-    pub fn check_obfs4proxy() -> anyhow::Result<()> {
-        if Self::is_command_available("obfs4proxy") {
-            Ok(())
-        } else {
-            Err(anyhow::anyhow!("obfs4proxy not found"))
+    /// The installed Tor's version as printed by `tor --version`, e.g. "0.4.8.10".
+    pub fn tor_version(config: &NipeConfig) -> anyhow::Result<String> {
+        let tor_path = NipeEngine::new(config.clone())?.find_tor_path()?;
+        let output = Command::new(&tor_path).arg("--version").output()?;
+        if !output.status.success() {
+            return Err(anyhow::anyhow!("{} --version failed", tor_path));
         }
+
+        // "Tor version 0.4.8.10." possibly followed by build details
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        stdout
+            .split("Tor version ")
+            .nth(1)
+            .and_then(|rest| rest.split_whitespace().next())
+            .map(|version| version.trim_end_matches('.').to_string())
+            .filter(|version| parse_tor_version(version).is_some())
+            .ok_or_else(|| anyhow::anyhow!("Unrecognised `tor --version` output"))
+    }
+
+    /// Refuse Tor releases older than `tor.min_tor_version`; an undetectable
+    /// version only warns, since some builds print their version differently.
+    fn check_tor_version(config: &NipeConfig) -> anyhow::Result<()> {
+        let version = match Self::tor_version(config) {
+            Ok(version) => version,
+            Err(e) => {
+                eprintln!(
+                    "{} {}",
+                    "[!] Could not determine the Tor version:".yellow(),
+                    e
+                );
+                return Ok(());
+            }
+        };
+
+        let minimum = &config.tor.min_tor_version;
+        if parse_tor_version(&version) < parse_tor_version(minimum) {
+            return Err(anyhow::anyhow!(
+                "Tor {} is too old: nipe needs {} or newer (see tor.min_tor_version)",
+                version,
+                minimum
+            ));
+        }
+
+        say!("{} {}", "[✓] Tor version".green(), version.green());
+        Ok(())
+    }
+
+    /// Ask a yes/no question on the terminal; anything but "y"/"yes" is no.
+    pub fn confirm(question: &str) -> anyhow::Result<bool> {
+        use std::io::Write;
+
+        print!("{} ", format!("[?] {} [y/N]", question).yellow());
+        std::io::stdout().flush()?;
+
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+    }
+
+    /// First line of `obfs4proxy -version`, e.g. "obfs4proxy-0.0.14".
+    pub fn obfs4proxy_version() -> anyhow::Result<String> {
+        let output = Command::new("obfs4proxy").arg("-version").output()?;
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .next()
+            .map(|line| line.trim().to_string())
+            .filter(|line| !line.is_empty())
+            .ok_or_else(|| anyhow::anyhow!("obfs4proxy -version printed nothing"))
     }
 
     fn is_command_available(cmd: &str) -> bool {
@@ -139,11 +208,46 @@ impl Installer {
 
     #[cfg(target_os = "windows")]
     fn install_tor() -> anyhow::Result<()> {
-        // On Windows, we cannot automatically install Tor via a package manager.
-        // Provide a helpful error message with download instructions.
-        Err(anyhow::anyhow!(
-            "Automatic Tor installation is not supported on Windows.\n\nPlease download and install Tor from the official website:\nhttps://www.torproject.org/download/\n\nAfter installing, ensure `tor.exe` is in your PATH or located at C:\\Program Files\\Tor\\tor.exe"
-        ))
+        // (package manager, install arguments), in order of preference
+        const MANAGERS: [(&str, &[&str]); 2] = [
+            (
+                "winget",
+                &[
+                    "install",
+                    "--exact",
+                    "--id",
+                    "TorProject.Tor",
+                    "--accept-source-agreements",
+                    "--accept-package-agreements",
+                ],
+            ),
+            ("choco", &["install", "tor", "-y"]),
+        ];
+
+        let manual = || {
+            anyhow::anyhow!(
+                "Failed to auto-install Tor.\n\nPlease download and install Tor from the official website:\nhttps://www.torproject.org/download/\n\nAfter installing, ensure `tor.exe` is in your PATH or located at C:\\Program Files\\Tor\\tor.exe"
+            )
+        };
+
+        let Some((manager, args)) = MANAGERS
+            .iter()
+            .find(|(manager, _)| Self::is_command_available(manager))
+        else {
+            return Err(manual());
+        };
+
+        info!("Installing Tor via {}...", manager);
+        println!("Installing Tor via {} (requires Administrator)...", manager);
+
+        match Command::new(manager).args(*args).status() {
+            Ok(status) if status.success() => {
+                println!("✅ Tor installed successfully!");
+                Ok(())
+            }
+            // Fall back to manual instructions
+            _ => Err(manual()),
+        }
     }
 
     #[cfg(target_os = "macos")]
@@ -178,29 +282,41 @@ impl Installer {
 
     #[cfg(target_os = "linux")]
     fn install_tor() -> anyhow::Result<()> {
-        info!("Installing Tor via apt...");
+        // (package manager, install arguments), in order of preference
+        const MANAGERS: [(&str, &[&str]); 4] = [
+            ("apt-get", &["install", "-y", "tor"]),
+            ("dnf", &["install", "-y", "tor"]),
+            ("pacman", &["-S", "--noconfirm", "tor"]),
+            ("zypper", &["--non-interactive", "install", "tor"]),
+        ];
 
-        println!("Installing Tor via apt-get (requires sudo)...");
+        let manual = || {
+            anyhow::anyhow!(
+                "Failed to auto-install Tor. Please install manually:\n\
+                Debian/Ubuntu: sudo apt-get install tor\n\
+                Fedora: sudo dnf install tor\n\
+                Arch: sudo pacman -S tor\n\
+                openSUSE: sudo zypper install tor"
+            )
+        };
 
-        // Try apt-get
-        let output = Command::new("apt-get")
-            .args(&["install", "-y", "tor"])
-            .status();
+        let Some((manager, args)) = MANAGERS
+            .iter()
+            .find(|(manager, _)| Self::is_command_available(manager))
+        else {
+            return Err(manual());
+        };
 
-        match output {
+        info!("Installing Tor via {}...", manager);
+        println!("Installing Tor via {} (requires sudo)...", manager);
+
+        match Command::new(manager).args(*args).status() {
             Ok(status) if status.success() => {
                 println!("✅ Tor installed successfully!");
                 Ok(())
             }
-            _ => {
-                // Fall back to manual instructions
-                Err(anyhow::anyhow!(
-                    "Failed to auto-install Tor. Please install manually:\n\
-                    Debian/Ubuntu: sudo apt-get install tor\n\
-                    Fedora: sudo dnf install tor\n\
-                    Arch: sudo pacman -S tor"
-                ))
-            }
+            // Fall back to manual instructions
+            _ => Err(manual()),
         }
     }
 
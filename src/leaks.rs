@@ -0,0 +1,171 @@
+use crate::config::NipeConfig;
+use crate::status::ConnectionStatus;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Outcome of one leak test run by `nipe check-leaks`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LeakCheck {
+    pub name: String,
+    pub passed: bool,
+    pub detail: String,
+}
+
+impl LeakCheck {
+    fn new(name: &str, passed: bool, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            passed,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// IPv6-only echo service; reaching it at all means IPv6 is not blocked.
+const IPV6_ECHO_URL: &str = "https://api6.ipify.org";
+/// Public resolver queried directly to see whether DNS can bypass Tor.
+const PUBLIC_RESOLVER: &str = "1.1.1.1:53";
+
+/// Run every leak test against the current setup.
+pub async fn check_leaks(config: &NipeConfig) -> Vec<LeakCheck> {
+    vec![
+        check_ipv6().await,
+        check_dns().await,
+        check_direct_ip(config).await,
+    ]
+}
+
+/// IPv6 must be unreachable outside Tor.
+async fn check_ipv6() -> LeakCheck {
+    let name = "IPv6";
+    match direct_get(IPV6_ECHO_URL).await {
+        Ok(ip) => LeakCheck::new(name, false, format!("reachable directly as {}", ip)),
+        Err(_) => LeakCheck::new(name, true, "blocked"),
+    }
+}
+
+/// A DNS query sent straight to a public resolver must either be dropped
+/// or answered by Tor's DNSPort. The query is `id.server` in the CHAOS
+/// class: real resolvers answer it with their own identity, while Tor only
+/// resolves Internet-class names and returns no answer.
+async fn check_dns() -> LeakCheck {
+    let name = "DNS";
+    match query_chaos_id(PUBLIC_RESOLVER).await {
+        Ok(0) => LeakCheck::new(name, true, "direct queries are answered by Tor"),
+        Ok(_) => LeakCheck::new(
+            name,
+            false,
+            format!("{} answered a direct query, bypassing Tor", PUBLIC_RESOLVER),
+        ),
+        Err(_) => LeakCheck::new(name, true, "direct queries are blocked"),
+    }
+}
+
+/// The IP seen without the proxy must not be the Tor exit, and with the
+/// kill switch on there must be no direct route at all.
+async fn check_direct_ip(config: &NipeConfig) -> LeakCheck {
+    let name = "Direct IP";
+    let tor = match ConnectionStatus::check(&config.tor).await {
+        Ok(status) if status.is_tor => status.current_ip,
+        _ => return LeakCheck::new(name, false, "no working Tor connection to compare with"),
+    };
+
+    let direct = match ConnectionStatus::check_direct().await {
+        Ok(direct) if direct.latency_ms.is_some() => direct,
+        _ => {
+            return LeakCheck::new(
+                name,
+                true,
+                format!("direct connections blocked; Tor exit is {}", tor),
+            )
+        }
+    };
+
+    if direct.is_tor {
+        return LeakCheck::new(
+            name,
+            true,
+            format!(
+                "direct connections are routed through Tor (as {}; Tor exit is {})",
+                direct.current_ip, tor
+            ),
+        );
+    }
+
+    if direct.current_ip == tor {
+        LeakCheck::new(
+            name,
+            false,
+            format!(
+                "direct and Tor traffic both appear as {}",
+                direct.current_ip
+            ),
+        )
+    } else if config.firewall.enable_kill_switch {
+        LeakCheck::new(
+            name,
+            false,
+            format!(
+                "kill switch is on but {} is reachable directly (Tor exit is {})",
+                direct.current_ip, tor
+            ),
+        )
+    } else {
+        LeakCheck::new(
+            name,
+            true,
+            format!("direct {} differs from Tor exit {}", direct.current_ip, tor),
+        )
+    }
+}
+
+/// GET `url` without any proxy and return the trimmed body.
+async fn direct_get(url: &str) -> anyhow::Result<String> {
+    let client = reqwest::Client::builder()
+        .no_proxy()
+        .timeout(Duration::from_secs(10))
+        .build()?;
+    let body = client
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    Ok(body.trim().to_string())
+}
+
+/// Send a CHAOS-class TXT query for `id.server` and return how many answer
+/// records came back.
+async fn query_chaos_id(resolver: &str) -> anyhow::Result<u16> {
+    let socket = tokio::net::UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect(resolver).await?;
+
+    let id: u16 = rand_id();
+    let mut query = Vec::with_capacity(32);
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question, no other records
+    query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+    for label in ["id", "server"] {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    // Root label, QTYPE TXT (16), QCLASS CH (3)
+    query.extend_from_slice(&[0, 0x00, 0x10, 0x00, 0x03]);
+    socket.send(&query).await?;
+
+    let mut response = [0u8; 512];
+    let len = tokio::time::timeout(Duration::from_secs(5), socket.recv(&mut response)).await??;
+    if len < 12 || response[..2] != id.to_be_bytes() {
+        return Err(anyhow::anyhow!("malformed DNS response"));
+    }
+    Ok(u16::from_be_bytes([response[6], response[7]]))
+}
+
+/// A DNS transaction id; it only has to differ between runs.
+fn rand_id() -> u16 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u16)
+        .unwrap_or(0x4e50)
+}
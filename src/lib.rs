@@ -0,0 +1,16 @@
+//! Nipe core: Tor process management, control port access, firewall
+//! handling, connection status and leak checks. The `nipe` binary is a CLI on
+//! top of this crate.
+
+pub mod bridges;
+pub mod circuit;
+pub mod config;
+pub mod control;
+pub mod engine;
+pub mod error;
+pub mod http_proxy;
+pub mod leaks;
+pub mod metrics;
+mod platform;
+pub mod status;
+pub mod status_server;
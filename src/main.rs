@@ -1,180 +1,812 @@
 use anyhow::Result;
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use colored::Colorize;
-use tracing::info;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{debug, info, warn};
 
-mod config;
-mod engine;
-mod error;
+/// Set by `--quiet`: drop banners and progress lines from stdout.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// `println!` for cosmetic output that `--quiet` suppresses.
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if !$crate::QUIET.load(std::sync::atomic::Ordering::Relaxed) {
+            println!($($arg)*);
+        }
+    };
+}
+
+mod doctor;
 mod installer;
 mod monitor;
-mod platform;
-mod status;
 
-use config::NipeConfig;
-use engine::NipeEngine;
+use nipe::config::{self, NipeConfig};
+use nipe::engine::NipeEngine;
+use nipe::error::{exit_code, NipeError};
+use nipe::status;
 
 #[derive(Parser)]
 #[command(name = "nipe")]
 #[command(version, about = "Route all traffic through Tor network", long_about = None)]
 struct Cli {
+    /// Run an isolated instance with its own data directory and ports
+    #[arg(long, global = true, value_name = "NAME")]
+    instance: Option<String>,
+
+    /// Load and save this config file instead of ~/.config/nipe/config.toml
+    #[arg(long, global = true, value_name = "PATH", conflicts_with = "instance")]
+    config: Option<PathBuf>,
+
+    /// Only print errors and essential results (no banners or progress)
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    /// Disable colored output (also set by the NO_COLOR environment variable)
+    #[arg(long, global = true)]
+    no_color: bool,
+
+    /// Log output format
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        env = "NIPE_LOG_FORMAT",
+        default_value_t = LogFormat::Pretty
+    )]
+    log_format: LogFormat,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormat {
+    /// Human-readable lines
+    Pretty,
+    /// One JSON object per line, for log collectors
+    Json,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Switch {
+    On,
+    Off,
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Start Nipe (enable Tor routing)
     Start {
-        /// Select exit node country (e.g., "us", "de")
+        /// Select exit node country (e.g., "us", "de", or several: "us,de")
         #[arg(short, long)]
         country: Option<String>,
+        /// Proceed if the HTTP check fails but Tor reports an established circuit
+        #[arg(long)]
+        force: bool,
+        /// Show Tor's log output live while it bootstraps
+        #[arg(long)]
+        verbose_tor: bool,
+        /// Also run an HTTP proxy on this localhost port that forwards to Tor (e.g. 8118)
+        #[arg(long, value_name = "PORT")]
+        http_proxy_port: Option<u16>,
+        /// Run this Tor executable instead of searching the usual locations
+        #[arg(long, value_name = "PATH")]
+        tor_path: Option<PathBuf>,
+        /// Install Tor without asking if it is missing
+        #[arg(short, long, conflicts_with = "no_install")]
+        yes: bool,
+        /// Never install Tor; fail if it is missing
+        #[arg(long)]
+        no_install: bool,
+        /// Keep Tor attached, stream its log and stop everything on Ctrl-C
+        #[arg(long, conflicts_with = "daemon")]
+        foreground: bool,
+        /// Stay in the foreground, rotating on `rotation.interval_seconds`
+        /// until SIGTERM/SIGINT, then stop Nipe
+        #[arg(long)]
+        daemon: bool,
+        /// With --daemon or --foreground, serve /health and /metrics (status,
+        /// traffic, rotations, time since bootstrap) on this address
+        #[arg(long, value_name = "ADDR")]
+        metrics_addr: Option<std::net::SocketAddr>,
+        /// Print the torrc and firewall commands, then exit without changing anything
+        #[arg(long, conflicts_with_all = ["foreground", "daemon"])]
+        dry_run: bool,
     },
     /// Stop Nipe (disable Tor routing)
     Stop,
+    /// Turn only the kill switch on or off, without starting or stopping Tor
+    KillSwitch {
+        #[arg(value_enum)]
+        state: Switch,
+    },
     /// Check connection status
-    Status,
+    Status {
+        /// Print the status as JSON (exits non-zero when not using Tor)
+        #[arg(long, conflicts_with = "prometheus")]
+        json: bool,
+        /// Print metrics in Prometheus text format (for the textfile collector)
+        #[arg(long)]
+        prometheus: bool,
+        /// Print one compact line such as "🟢 US 185.220.101.4" or "🔴 off",
+        /// for shell prompts (fast with --cached)
+        #[arg(long, conflicts_with_all = ["json", "prometheus"])]
+        oneline: bool,
+        /// Write machine-readable output to a file (atomically) instead of stdout
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+        /// If the check fails, request a new circuit and try once more
+        #[arg(long, conflicts_with = "cached")]
+        rotate_on_fail: bool,
+        /// Show the last status saved on start or rotate, without network I/O
+        #[arg(long)]
+        cached: bool,
+    },
     /// Rotate IP identity
-    Rotate,
+    Rotate {
+        /// Only rotate if the current circuit is slower than this many milliseconds
+        #[arg(long, value_name = "MS")]
+        if_slow: Option<u64>,
+        /// Only rotate if the current exit is in one of these countries (e.g. "us,gb")
+        #[arg(long, value_name = "CC", value_delimiter = ',')]
+        if_country: Vec<String>,
+        /// Wait until the exit IP has actually changed (up to SECS, default 30)
+        #[arg(long, value_name = "SECS", num_args = 0..=1, default_missing_value = "30")]
+        wait: Option<u64>,
+    },
+    /// List Tor's current circuits and the relays in each
+    Circuit {
+        /// Print the circuits as JSON
+        #[arg(long)]
+        json: bool,
+        /// Include circuits that are still being built or have failed
+        #[arg(long)]
+        all: bool,
+    },
+    /// Print the .onion address of each configured hidden service
+    OnionAddress,
+    /// Test for IPv6, DNS and direct-connection leaks
+    CheckLeaks {
+        /// Print the results as JSON
+        #[arg(long)]
+        json: bool,
+    },
     /// Real-time monitoring dashboard
-    Monitor,
+    Monitor {
+        /// Re-check the connection every SECS seconds
+        #[arg(long, value_name = "SECS", default_value_t = 5)]
+        refresh: u64,
+    },
     /// Restart Nipe
     Restart,
-    /// Show current configuration
-    Config,
+    /// Show nipe, Tor and obfs4proxy versions and the firewall backend
+    Version,
+    /// Check the setup: Tor, bridges, control port, data directory, firewall and ports
+    Doctor,
+    /// Serve /health (status JSON) and /metrics (Prometheus) over HTTP
+    ServeStatus {
+        /// Address to listen on; use 0.0.0.0:PORT to allow other machines
+        #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:9080")]
+        bind: std::net::SocketAddr,
+    },
+    /// Copy this nipe binary into the system path
+    Install,
+    /// Stop Nipe and remove its binary copy, Tor data and config
+    Uninstall {
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Show current configuration, or change it
+    Config {
+        #[command(subcommand)]
+        action: Option<ConfigAction>,
+    },
+    /// Show the effective configuration against the config file
+    DiffConfig,
+    /// Manage obfs4 bridges
+    Bridges {
+        #[command(subcommand)]
+        action: BridgesAction,
+    },
+    /// Run the HTTP-to-SOCKS proxy in the foreground (started by `start`)
+    #[command(hide = true)]
+    HttpProxy {
+        #[arg(long)]
+        port: u16,
+        #[arg(long)]
+        socks_port: u16,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Change one setting, e.g. `nipe config set tor.socks_port 9150`
+    Set {
+        /// Dotted key, e.g. "tor.socks_port" or "firewall.allow_lan"
+        key: String,
+        /// New value; numbers, booleans and ["lists"] are parsed as TOML
+        value: String,
+    },
+    /// Restore the default configuration, keeping a `.bak` copy of the old file
+    Reset,
+}
+
+#[derive(Subcommand)]
+enum BridgesAction {
+    /// Download Tor Browser's built-in obfs4 bridges into the config
+    Fetch,
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() {
+    if let Err(e) = run().await {
+        eprintln!("Error: {:?}", e);
+        let code = e
+            .downcast_ref::<NipeError>()
+            .map_or(exit_code::FAILURE, NipeError::exit_code);
+        std::process::exit(code);
+    }
+}
+
+async fn run() -> Result<()> {
+    let cli = Cli::parse();
+    QUIET.store(cli.quiet, Ordering::Relaxed);
+
+    // https://no-color.org: any non-empty NO_COLOR disables color
+    let no_color = cli.no_color || std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    if no_color {
+        colored::control::set_override(false);
+    }
+
     // Initialize logging
-    tracing_subscriber::fmt()
+    let logger = tracing_subscriber::fmt()
         .with_env_filter(
             tracing_subscriber::EnvFilter::from_default_env()
                 .add_directive(tracing::Level::INFO.into()),
         )
-        .init();
-
-    let cli = Cli::parse();
-    let config = NipeConfig::load().unwrap_or_default();
+        .with_ansi(!no_color && std::io::stderr().is_terminal())
+        // Keep stdout clean for machine-readable output
+        .with_writer(std::io::stderr);
+    match cli.log_format {
+        LogFormat::Pretty => logger.init(),
+        LogFormat::Json => logger.json().init(),
+    }
+    // A command that saves the config must not replace a file it could not
+    // parse with the defaults
+    let writes_config = matches!(
+        cli.command,
+        Commands::Config {
+            action: Some(ConfigAction::Set { .. })
+        } | Commands::Bridges { .. }
+    );
+    let config = match (&cli.config, &cli.instance) {
+        (Some(path), _) => NipeConfig::load_from(path)?,
+        (None, Some(name)) => NipeConfig::load_instance(name)?,
+        (None, None) if writes_config => NipeConfig::load()?,
+        (None, None) => NipeConfig::load().unwrap_or_default(),
+    };
 
-    // Check for root/sudo unless just checking version/help (which clap handles before this)
-    if !is_root() {
-        eprintln!(
-            "{}",
+    // Check for root/sudo unless the command only reads state, e.g.
+    // `status --cached --oneline` in a shell prompt, or previews a start
+    let read_only = matches!(
+        cli.command,
+        Commands::Start { dry_run: true, .. }
+            | Commands::Status {
+                rotate_on_fail: false,
+                ..
+            }
+            | Commands::Version
+            | Commands::Doctor
+            | Commands::ServeStatus { .. }
+            | Commands::Config { action: None }
+            | Commands::DiffConfig
+    );
+    if !read_only && !is_root() {
+        let message = if cfg!(windows) {
+            "Error: Nipe must be run as Administrator (open the terminal with \"Run as administrator\")"
+        } else {
             "Error: Nipe must be run as root (use sudo)"
-                .bright_red()
-                .bold()
-        );
-        std::process::exit(1);
+        };
+        eprintln!("{}", message.bright_red().bold());
+        std::process::exit(exit_code::NOT_ROOT);
     }
 
     match cli.command {
-        Commands::Start { country } => {
-            println!("{}", "━".repeat(50).bright_blue());
-            println!("{}", "  Starting Nipe...".bright_blue().bold());
-            println!("{}", "━".repeat(50).bright_blue());
+        Commands::Start {
+            country,
+            force,
+            verbose_tor,
+            http_proxy_port,
+            tor_path,
+            yes,
+            no_install,
+            foreground,
+            daemon,
+            metrics_addr,
+            dry_run,
+        } => {
+            if metrics_addr.is_some() && !daemon && !foreground {
+                eprintln!(
+                    "{}",
+                    "[✗] --metrics-addr needs nipe to keep running (--daemon or --foreground)"
+                        .bright_red()
+                );
+                std::process::exit(exit_code::USAGE);
+            }
 
-            // Ensure all prerequisites are met (Tor, self-install, bridges)
-            installer::Installer::ensure_prerequisites(&config)?;
+            if !dry_run {
+                say!("{}", "━".repeat(50).bright_blue());
+                say!("{}", "  Starting Nipe...".bright_blue().bold());
+                say!("{}", "━".repeat(50).bright_blue());
+            }
 
             // Prepare configuration (possibly overridden by CLI args)
-            let run_config = if let Some(c) = country {
-                let mut cfg = config.clone();
-                cfg.tor.country = Some(c);
-                cfg
+            let mut run_config = config;
+            if let Some(c) = country {
+                run_config.tor.country = Some(c);
+            }
+            if let Some(port) = http_proxy_port {
+                run_config.tor.http_proxy_port = Some(port);
+            }
+            if let Some(path) = tor_path {
+                run_config.tor.tor_binary = Some(path);
+            }
+
+            if dry_run {
+                run_config.validate()?;
+                let engine = NipeEngine::new(run_config)?;
+                println!("# {}", engine.torrc_path().display());
+                print!("{}", engine.torrc_contents()?);
+                println!("\n# Firewall ({})", engine.firewall_backend()?);
+                for line in engine.describe_firewall()? {
+                    println!("{}", line);
+                }
+                if let Some(port) = engine.config().tor.http_proxy_port {
+                    println!("# HTTP proxy helper on 127.0.0.1:{}", port);
+                }
+                return Ok(());
+            }
+
+            // Ensure all prerequisites are met (Tor, self-install, bridges)
+            let policy = if no_install {
+                installer::InstallPolicy::Never
+            } else if yes {
+                installer::InstallPolicy::Yes
             } else {
-                config
+                installer::InstallPolicy::Prompt
             };
+            installer::Installer::ensure_prerequisites(&run_config, policy)?;
 
             let mut engine = NipeEngine::new(run_config)?;
+            engine.set_force(force);
+            engine.set_verbose_tor(verbose_tor);
+            engine.set_foreground(foreground);
 
-            match engine.start().await {
+            // Tor's own log already shows bootstrap progress in these modes
+            let progress = (!verbose_tor && !foreground && !cli.quiet).then(|| {
+                let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+                engine.set_bootstrap_progress(sender);
+                tokio::spawn(show_bootstrap_progress(receiver))
+            });
+            let started = engine.start().await;
+            if let Some(progress) = progress {
+                let _ = progress.await;
+            }
+
+            match started {
                 Ok(_) => {
-                    println!("{}", "[✓] Tor process started".green());
-                    println!("{}", "[✓] Kill switch enabled".green());
-                    println!("{}", "[✓] System proxy configured".green());
-                    println!(
+                    say!("{}", "[✓] Tor process started".green());
+                    if engine.verification_skipped() {
+                        println!(
+                            "{}",
+                            "[!] External verification skipped (--force): the check endpoint was unreachable, but Tor reports an established circuit"
+                                .yellow()
+                        );
+                    }
+                    if let Some(name) = &engine.config().instance {
+                        println!(
+                            "{} {} {}",
+                            "[✓] Instance".green(),
+                            name.bright_cyan(),
+                            format!(
+                                "listening on SOCKS 127.0.0.1:{}",
+                                engine.config().tor.socks_port
+                            )
+                            .green()
+                        );
+                    } else {
+                        say!("{}", "[✓] Kill switch enabled".green());
+                        say!("{}", "[✓] System proxy configured".green());
+                    }
+                    if let Some(port) = engine.config().tor.http_proxy_port {
+                        say!(
+                            "{}",
+                            format!(
+                                "[✓] HTTP proxy at 127.0.0.1:{} (http_proxy/https_proxy)",
+                                port
+                            )
+                            .green()
+                        );
+                    }
+                    for (name, address) in engine.onion_addresses() {
+                        match address {
+                            Ok(Some(address)) => println!(
+                                "{} {} {}",
+                                "[✓] Hidden service".green(),
+                                name.bright_cyan(),
+                                format!("at {}", address).green()
+                            ),
+                            Ok(None) => println!(
+                                "{}",
+                                format!("[!] Hidden service {} has not published yet", name)
+                                    .yellow()
+                            ),
+                            Err(e) => println!(
+                                "{}",
+                                format!("[!] Hidden service {}: {}", name, e).yellow()
+                            ),
+                        }
+                    }
+                    say!(
                         "\n{}",
                         "Nipe is now active - All traffic routed through Tor"
                             .bright_green()
                             .bold()
                     );
-                    println!("{}", "━".repeat(50).bright_blue());
+                    say!("{}", "━".repeat(50).bright_blue());
+
+                    if let Some(addr) = metrics_addr {
+                        let server = nipe::status_server::serve(
+                            addr,
+                            engine.config().clone(),
+                            Some(engine.metrics()),
+                        );
+                        tokio::spawn(async move {
+                            if let Err(e) = server.await {
+                                warn!("Metrics server stopped: {}", e);
+                            }
+                        });
+                    }
+
+                    if daemon {
+                        run_daemon(&mut engine).await?;
+                    } else if foreground {
+                        say!(
+                            "{}",
+                            "Running in the foreground, press Ctrl-C to stop".dimmed()
+                        );
+                        if let Err(e) = engine.supervise().await {
+                            eprintln!("{} {}", "[✗]".bright_red(), e);
+                            std::process::exit(e.exit_code());
+                        }
+                        say!(
+                            "{}",
+                            "Nipe stopped - Direct internet connection restored"
+                                .bright_yellow()
+                                .bold()
+                        );
+                    }
                 }
                 Err(e) => {
                     eprintln!("{} {}", "[✗] Failed to start:".bright_red(), e);
-                    std::process::exit(1);
+                    std::process::exit(e.exit_code());
                 }
             }
         }
 
+        Commands::KillSwitch { state } => {
+            let engine = NipeEngine::new(config)?;
+            let on = state == Switch::On;
+            if on && engine.running_tor_pid().is_none() {
+                eprintln!(
+                    "{}",
+                    "[!] Tor is not running: with the kill switch on, all traffic is blocked"
+                        .yellow()
+                );
+            }
+
+            if let Err(e) = engine.set_kill_switch(on) {
+                eprintln!(
+                    "{} {}",
+                    "[✗] Failed to change the kill switch:".bright_red(),
+                    e
+                );
+                std::process::exit(e.exit_code());
+            }
+            if on {
+                say!("{}", "[✓] Kill switch enabled".bright_green());
+            } else {
+                say!("{}", "[✓] Kill switch disabled".yellow());
+            }
+        }
+
         Commands::Stop => {
-            println!("{}", "━".repeat(50).bright_yellow());
-            println!("{}", "  Stopping Nipe...".bright_yellow().bold());
-            println!("{}", "━".repeat(50).bright_yellow());
+            say!("{}", "━".repeat(50).bright_yellow());
+            say!("{}", "  Stopping Nipe...".bright_yellow().bold());
+            say!("{}", "━".repeat(50).bright_yellow());
 
             let mut engine = NipeEngine::new(config)?;
 
             match engine.stop().await {
                 Ok(_) => {
-                    println!("{}", "[✓] Tor process stopped".yellow());
-                    println!("{}", "[✓] Kill switch disabled".yellow());
-                    println!("{}", "[✓] System proxy removed".yellow());
-                    println!(
+                    say!("{}", "[✓] Tor process stopped".yellow());
+                    say!("{}", "[✓] Kill switch disabled".yellow());
+                    say!("{}", "[✓] System proxy removed".yellow());
+                    say!(
                         "\n{}",
                         "Nipe stopped - Direct internet connection restored"
                             .bright_yellow()
                             .bold()
                     );
-                    println!("{}", "━".repeat(50).bright_yellow());
+                    say!("{}", "━".repeat(50).bright_yellow());
                 }
                 Err(e) => {
                     eprintln!("{} {}", "[✗] Failed to stop:".bright_red(), e);
-                    std::process::exit(1);
+                    std::process::exit(e.exit_code());
                 }
             }
         }
 
-        Commands::Status => {
+        Commands::Status {
+            json,
+            prometheus,
+            oneline,
+            output,
+            rotate_on_fail,
+            cached,
+        } => {
+            if output.is_some() && !json && !prometheus {
+                eprintln!(
+                    "{}",
+                    "[✗] --output needs a machine-readable format (--json or --prometheus)"
+                        .bright_red()
+                );
+                std::process::exit(exit_code::USAGE);
+            }
+
+            if cached {
+                let status = match status::ConnectionStatus::load_cached(&config.tor) {
+                    Ok(status) => status,
+                    // `stop` removes the cache, so a prompt should just say off
+                    Err(_) if oneline => {
+                        println!("🔴 off");
+                        return Ok(());
+                    }
+                    Err(e) => {
+                        eprintln!(
+                            "{} {}",
+                            "[✗] No cached status (is nipe started?):".bright_red(),
+                            e
+                        );
+                        std::process::exit(exit_code::FAILURE);
+                    }
+                };
+                return print_status(&status, json, prometheus, oneline, output.as_deref());
+            }
+
             info!("Checking status...");
-            match status::ConnectionStatus::check().await {
-                Ok(status) => status.display(),
+            let (mut result, real_ip) = tokio::join!(
+                status::ConnectionStatus::check(&config.tor),
+                status::ConnectionStatus::check_real_ip()
+            );
+
+            // A single retry on a fresh circuit; a genuinely down Tor still fails
+            if rotate_on_fail && !matches!(&result, Ok(s) if s.is_tor) {
+                info!("Status check failed, rotating circuit and retrying once");
+                match NipeEngine::new(config.clone())?.rotate().await {
+                    Ok(_) => {
+                        tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+                        result = status::ConnectionStatus::check(&config.tor).await;
+                    }
+                    Err(e) => warn!("Rotation failed, not retrying: {}", e),
+                }
+            }
+
+            match result {
+                Ok(mut status) => {
+                    status.real_ip = real_ip;
+                    status.check_exit_country(&config.tor).await;
+                    status.check_tor_state(&config.tor).await;
+                    status.tor_version = installer::Installer::tor_version(&config).ok();
+                    print_status(&status, json, prometheus, oneline, output.as_deref())?;
+                }
                 Err(e) => {
                     eprintln!("{} {}", "[✗] Failed to check status:".bright_red(), e);
-                    std::process::exit(1);
+                    std::process::exit(exit_code::FAILURE);
                 }
             }
         }
 
-        Commands::Rotate => {
-            println!("{}", "[+] Rotating identity...".bright_cyan());
+        Commands::Rotate {
+            if_slow,
+            if_country,
+            wait,
+        } => {
+            if if_slow.is_some() || !if_country.is_empty() {
+                let mut current = status::ConnectionStatus::check(&config.tor).await?;
+                current.check_exit_country(&config.tor).await;
+
+                let too_slow = if_slow
+                    .is_some_and(|max| current.latency_ms.is_none_or(|latency| latency > max));
+                let denied_country = current.exit_country.as_ref().is_some_and(|cc| {
+                    if_country
+                        .iter()
+                        .any(|denied| denied.eq_ignore_ascii_case(cc))
+                });
+
+                if !too_slow && !denied_country {
+                    println!(
+                        "{} (latency: {}, exit country: {})",
+                        "[✓] Current circuit is fine, not rotating".bright_green(),
+                        current
+                            .latency_ms
+                            .map_or("unknown".to_string(), |l| format!("{} ms", l)),
+                        current.exit_country.as_deref().unwrap_or("unknown")
+                    );
+                    return Ok(());
+                }
+            }
+
+            let tor = config.tor.clone();
+            let old_ip = match wait {
+                Some(_) => Some(status::ConnectionStatus::check(&tor).await?.current_ip),
+                None => None,
+            };
+
+            say!("{}", "[+] Rotating identity...".bright_cyan());
 
             let engine = NipeEngine::new(config)?;
 
             match engine.rotate().await {
                 Ok(_) => {
-                    println!("{}", "[✓] New identity acquired".bright_green());
+                    say!("{}", "[✓] New identity acquired".bright_green());
 
-                    // Show new IP
-                    tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
-                    if let Ok(status) = status::ConnectionStatus::check().await {
-                        println!("{} {}", "New IP:".bold(), status.current_ip.bright_cyan());
+                    if let (Some(timeout), Some(old_ip)) = (wait, old_ip) {
+                        let deadline =
+                            tokio::time::Instant::now() + tokio::time::Duration::from_secs(timeout);
+                        loop {
+                            tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+                            if let Ok(status) = status::ConnectionStatus::check(&tor).await {
+                                if status.is_tor && status.current_ip != old_ip {
+                                    save_cached_status(status.clone(), &tor).await;
+                                    println!("{} {}", "Old IP:".bold(), old_ip.bright_red());
+                                    println!(
+                                        "{} {}",
+                                        "New IP:".bold(),
+                                        status.current_ip.bright_cyan()
+                                    );
+                                    break;
+                                }
+                            }
+                            if tokio::time::Instant::now() >= deadline {
+                                eprintln!(
+                                    "{} {} {}",
+                                    "[✗] Exit IP is still".bright_red(),
+                                    old_ip,
+                                    format!("after {}s", timeout).bright_red()
+                                );
+                                std::process::exit(exit_code::FAILURE);
+                            }
+                        }
+                    } else {
+                        // Show new IP
+                        tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+                        if let Ok(status) = status::ConnectionStatus::check(&tor).await {
+                            println!("{} {}", "New IP:".bold(), status.current_ip.bright_cyan());
+                            if status.is_tor {
+                                save_cached_status(status, &tor).await;
+                            }
+                        }
                     }
                 }
                 Err(e) => {
                     eprintln!("{} {}", "[✗] Failed to rotate:".bright_red(), e);
-                    std::process::exit(1);
+                    std::process::exit(e.exit_code());
                 }
             }
         }
 
-        Commands::Monitor => {
-            println!("{}", "Starting real-time monitor...".bright_blue());
-            monitor::Monitor::new().run().await?;
+        Commands::CheckLeaks { json } => {
+            if !json {
+                say!("{}", "Checking for leaks...".bright_cyan());
+            }
+            let checks = nipe::leaks::check_leaks(&config).await;
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&checks)?);
+            } else {
+                for check in &checks {
+                    let verdict = if check.passed {
+                        "PASS".bright_green().bold()
+                    } else {
+                        "FAIL".bright_red().bold()
+                    };
+                    println!("  {} {:<10} {}", verdict, check.name.bold(), check.detail);
+                }
+            }
+
+            if checks.iter().any(|c| !c.passed) {
+                std::process::exit(exit_code::FAILURE);
+            }
+        }
+
+        Commands::Circuit { json, all } => {
+            let engine = NipeEngine::new(config)?;
+            let circuits: Vec<_> = match engine.circuits().await {
+                Ok(circuits) => circuits
+                    .into_iter()
+                    .filter(|c| all || c.is_built())
+                    .collect(),
+                Err(e) => {
+                    eprintln!("{} {}", "[✗] Failed to read circuits:".bright_red(), e);
+                    std::process::exit(e.exit_code());
+                }
+            };
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&circuits)?);
+            } else if circuits.is_empty() {
+                println!("{}", "No circuits".yellow());
+            } else {
+                for circuit in &circuits {
+                    println!(
+                        "{} {} {}",
+                        format!("Circuit {}", circuit.id).bold(),
+                        circuit.status.bright_cyan(),
+                        circuit.purpose.as_deref().unwrap_or_default().dimmed()
+                    );
+                    for (i, relay) in circuit.hops.iter().enumerate() {
+                        println!(
+                            "  {}. {:<19} {} {:<15} {}",
+                            i + 1,
+                            relay.nickname.as_deref().unwrap_or("-"),
+                            relay.fingerprint.dimmed(),
+                            relay.ip.as_deref().unwrap_or("-"),
+                            relay
+                                .country
+                                .as_deref()
+                                .map_or("??".to_string(), |cc| cc.to_uppercase())
+                                .bright_cyan()
+                        );
+                    }
+                }
+            }
+        }
+
+        Commands::OnionAddress => {
+            let engine = NipeEngine::new(config)?;
+            let addresses = engine.onion_addresses();
+            if addresses.is_empty() {
+                println!(
+                    "{}",
+                    "No hidden services configured (tor.hidden_services)".yellow()
+                );
+            }
+            for (name, address) in addresses {
+                match address {
+                    Ok(Some(address)) => println!("{:<16} {}", name.bold(), address.bright_cyan()),
+                    Ok(None) => println!(
+                        "{:<16} {}",
+                        name.bold(),
+                        "not published yet (is nipe started?)".yellow()
+                    ),
+                    Err(e) => println!("{:<16} {}", name.bold(), e.to_string().yellow()),
+                }
+            }
+        }
+
+        Commands::Monitor { refresh } => {
+            say!("{}", "Starting real-time monitor...".bright_blue());
+            monitor::Monitor::new(config, std::time::Duration::from_secs(refresh.max(1)))
+                .run()
+                .await?;
         }
 
         Commands::Restart => {
-            println!("{}", "Restarting Nipe...".bright_cyan());
+            say!("{}", "Restarting Nipe...".bright_cyan());
 
             let mut engine = NipeEngine::new(config)?;
 
@@ -189,27 +821,432 @@ async fn main() -> Result<()> {
             // Start again
             engine.start().await?;
 
-            println!("{}", "[✓] Nipe restarted successfully".bright_green());
+            say!("{}", "[✓] Nipe restarted successfully".bright_green());
+        }
+
+        Commands::Version => {
+            let unavailable = |e: anyhow::Error| format!("not found ({})", e).dimmed();
+            println!(
+                "{} {}",
+                "nipe".bold(),
+                env!("CARGO_PKG_VERSION").bright_cyan()
+            );
+            println!(
+                "{} {}",
+                "Tor:".bold(),
+                installer::Installer::tor_version(&config)
+                    .map_or_else(unavailable, |v| v.bright_cyan())
+            );
+            println!(
+                "{} {}",
+                "obfs4proxy:".bold(),
+                installer::Installer::obfs4proxy_version()
+                    .map_or_else(unavailable, |v| v.bright_cyan())
+            );
+            println!(
+                "{} {}",
+                "Firewall:".bold(),
+                NipeEngine::new(config)?
+                    .firewall_backend()
+                    .map_or_else(|e| unavailable(e.into()), |b| b.bright_cyan())
+            );
+            println!(
+                "{} {} {}",
+                "Platform:".bold(),
+                std::env::consts::OS,
+                std::env::consts::ARCH
+            );
+        }
+
+        Commands::Doctor => {
+            say!("{}", "Checking the Nipe setup...".bright_cyan());
+            let checks = doctor::run_checks(&config).await?;
+            if !doctor::print_checks(&checks) {
+                std::process::exit(exit_code::FAILURE);
+            }
+        }
+
+        Commands::ServeStatus { bind } => {
+            say!(
+                "{} http://{}/health, http://{}/metrics",
+                "Serving status on".bright_cyan(),
+                bind,
+                bind
+            );
+            nipe::status_server::serve(bind, config, None).await?;
         }
 
-        Commands::Config => {
+        Commands::Install => match installer::Installer::install_self() {
+            Ok(path) => println!(
+                "{} {}",
+                "[✓] Installed Nipe to".bright_green(),
+                path.display().to_string().bright_green()
+            ),
+            Err(e) => {
+                eprintln!("{} {}", "[✗]".bright_red(), e);
+                std::process::exit(exit_code::FAILURE);
+            }
+        },
+
+        Commands::Uninstall { yes } => {
+            let config_dir = NipeConfig::config_dir();
+            println!("{}", "This will stop Nipe and delete:".yellow());
+            for path in [
+                installer::Installer::system_install_path(),
+                config.tor.data_directory.clone(),
+                config.tor.work_dir(),
+                config_dir.clone(),
+            ] {
+                println!("  {}", path.display());
+            }
+            if !yes && !installer::Installer::confirm("Continue?")? {
+                println!("Aborted.");
+                return Ok(());
+            }
+
+            let mut engine = NipeEngine::new(config)?;
+            if let Err(e) = engine.stop().await {
+                eprintln!("{} {}", "[!] Warning during stop:".yellow(), e);
+            }
+
+            let mut removed = engine.remove_files()?;
+            removed.extend(installer::Installer::uninstall_self()?);
+            if config_dir.exists() {
+                std::fs::remove_dir_all(&config_dir)?;
+                removed.push(config_dir);
+            }
+
+            for path in &removed {
+                println!("{} {}", "[✓] Removed".green(), path.display());
+            }
+            println!("{}", "[✓] Nipe uninstalled".bright_green());
+        }
+
+        Commands::Config {
+            action: Some(ConfigAction::Set { key, value }),
+        } => {
+            let mut config = config;
+            if let Err(e) = config.set_key(&key, &value) {
+                eprintln!("{} {}", "[✗]".bright_red(), e);
+                std::process::exit(e.exit_code());
+            }
+            config.save()?;
+            println!(
+                "{} {} {} {}",
+                "[✓] Set".green(),
+                key.bright_cyan(),
+                "in".green(),
+                config.path().display()
+            );
+        }
+
+        Commands::Config {
+            action: Some(ConfigAction::Reset),
+        } => {
+            let (config, backup) = config.reset()?;
+            if let Some(backup) = backup {
+                println!(
+                    "{} {}",
+                    "[✓] Previous config saved to".green(),
+                    backup.display()
+                );
+            }
+            println!(
+                "{} {}",
+                "[✓] Default config written to".green(),
+                config.path().display()
+            );
+        }
+
+        Commands::Config { action: None } => {
             use std::io::Write;
             let mut stdout = std::io::stdout();
             let _ = writeln!(stdout, "{}", "Current Configuration:".bright_blue().bold());
             let _ = writeln!(stdout, "{}", "━".repeat(50).bright_blue());
             let _ = writeln!(stdout, "{:#?}", config);
         }
+
+        Commands::Bridges {
+            action: BridgesAction::Fetch,
+        } => {
+            use nipe::bridges::BridgeSource;
+
+            say!("{}", "[+] Fetching built-in obfs4 bridges...".cyan());
+            let (bridges, source) = nipe::bridges::fetch_builtin_obfs4(config.tor.socks_port).await;
+            match source {
+                BridgeSource::Tor => say!("{}", "[✓] Downloaded through Tor".green()),
+                BridgeSource::Direct => {
+                    say!("{}", "[✓] Downloaded over a direct connection".green())
+                }
+                BridgeSource::Bundled => println!(
+                    "{}",
+                    "[!] Could not reach bridges.torproject.org; using the bridges bundled with Nipe"
+                        .yellow()
+                ),
+            }
+
+            let mut config = config;
+            config.tor.bridges = bridges;
+            config.tor.use_bridges = true;
+            config.save()?;
+            println!(
+                "{} {} {} {}",
+                "[✓] Saved".green(),
+                config.tor.bridges.len(),
+                "bridges and enabled use_bridges in".green(),
+                config.path().display()
+            );
+        }
+
+        Commands::HttpProxy { port, socks_port } => {
+            nipe::http_proxy::serve(port, socks_port).await?;
+        }
+
+        Commands::DiffConfig => {
+            use config::ConfigSource;
+
+            println!(
+                "{} {}",
+                "Config file:".bold(),
+                config.path().display().to_string().bright_cyan()
+            );
+            println!("{}", "━".repeat(50).bright_blue());
+
+            for entry in config.diff_with_file()? {
+                let shown = entry.effective.as_deref().unwrap_or("-");
+                match entry.source {
+                    ConfigSource::File => {
+                        println!("  {} = {} {}", entry.key, shown, "(file)".dimmed())
+                    }
+                    ConfigSource::Default => println!(
+                        "  {} = {} {}",
+                        entry.key.yellow(),
+                        shown.yellow(),
+                        "(default, not in file)".yellow()
+                    ),
+                    ConfigSource::Override => println!(
+                        "  {} = {} {}",
+                        entry.key.bright_cyan(),
+                        shown.bright_cyan(),
+                        format!(
+                            "(override, file has {})",
+                            entry.file.as_deref().unwrap_or("-")
+                        )
+                        .bright_cyan()
+                    ),
+                    ConfigSource::Unused => println!(
+                        "  {} = {} {}",
+                        entry.key.bright_red(),
+                        entry.file.as_deref().unwrap_or("-").bright_red(),
+                        "(in file, not a known setting - ignored)".bright_red()
+                    ),
+                }
+            }
+        }
     }
 
     Ok(())
 }
 
+/// Draw a one-line bootstrap progress bar until the engine stops sending.
+async fn show_bootstrap_progress(
+    mut receiver: tokio::sync::mpsc::UnboundedReceiver<nipe::control::BootstrapPhase>,
+) {
+    use std::io::Write;
+    const WIDTH: usize = 30;
+
+    let mut drawn = false;
+    while let Some(phase) = receiver.recv().await {
+        let filled = WIDTH * usize::from(phase.progress.min(100)) / 100;
+        print!(
+            "\r\x1b[2K{} [{}{}] {:>3}% {} {}",
+            "[+] Bootstrapping".cyan(),
+            "#".repeat(filled).bright_green(),
+            "-".repeat(WIDTH - filled).dimmed(),
+            phase.progress,
+            phase.summary,
+            format!("({})", phase.tag).dimmed()
+        );
+        let _ = std::io::stdout().flush();
+        drawn = true;
+    }
+    if drawn {
+        println!();
+    }
+}
+
+/// Rotate on the configured interval until SIGTERM or SIGINT, then stop.
+async fn run_daemon(engine: &mut NipeEngine) -> Result<()> {
+    let rotation = engine.config().rotation.clone();
+    let auto_rotate = rotation.auto_rotate && rotation.interval_seconds > 0;
+
+    if auto_rotate {
+        info!(
+            "Daemon running, rotating identity every {}s",
+            rotation.interval_seconds
+        );
+    } else {
+        info!("Daemon running, auto rotation disabled");
+    }
+
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    let period = tokio::time::Duration::from_secs(rotation.interval_seconds.max(1));
+    // The first tick fires immediately; skip it so we don't rotate right after start
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // Refreshes the cached status after a rotation, off the schedule
+    let mut refresh: Option<tokio::task::JoinHandle<()>> = None;
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick(), if auto_rotate => match engine.rotate().await {
+                Ok(_) => {
+                    info!("Rotated identity");
+                    let tor = engine.config().tor.clone();
+                    let task = tokio::spawn(async move {
+                        // Give Tor time to move new streams to a fresh circuit
+                        tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+                        if let Ok(status) = status::ConnectionStatus::check(&tor).await {
+                            if status.is_tor {
+                                save_cached_status(status, &tor).await;
+                            }
+                        }
+                    });
+                    if let Some(previous) = refresh.replace(task) {
+                        previous.abort();
+                    }
+                }
+                Err(e) => warn!("Scheduled rotation failed: {}", e),
+            },
+            _ = sigterm.recv() => {
+                info!("Received SIGTERM, stopping");
+                break;
+            }
+            _ = tokio::signal::ctrl_c() => {
+                info!("Received SIGINT, stopping");
+                break;
+            }
+        }
+    }
+
+    // Don't let a late refresh bring back the cache `stop` removes
+    if let Some(task) = refresh {
+        task.abort();
+    }
+    engine.stop().await?;
+    say!(
+        "{}",
+        "Nipe stopped - Direct internet connection restored"
+            .bright_yellow()
+            .bold()
+    );
+    Ok(())
+}
+
+/// Print `status` in the format `nipe status` was asked for.
+fn print_status(
+    status: &status::ConnectionStatus,
+    json: bool,
+    prometheus: bool,
+    oneline: bool,
+    output: Option<&Path>,
+) -> Result<()> {
+    if json {
+        let mut content = serde_json::to_string_pretty(status)?;
+        content.push('\n');
+        write_output(output, &content)?;
+        if !status.is_tor {
+            std::process::exit(exit_code::NOT_CONNECTED);
+        }
+    } else if prometheus {
+        write_output(output, &status.to_prometheus())?;
+    } else if oneline {
+        println!("{}", status.to_oneline());
+    } else {
+        status.display();
+    }
+    Ok(())
+}
+
+/// Print to stdout, or replace `path` atomically so readers such as the
+/// node_exporter textfile collector never see a partial file.
+fn write_output(path: Option<&Path>, content: &str) -> Result<()> {
+    let Some(path) = path else {
+        print!("{}", content);
+        return Ok(());
+    };
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    std::fs::create_dir_all(dir)?;
+
+    // Temp file in the same directory so the rename stays on one filesystem
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("Invalid output path: {}", path.display()))?;
+    let tmp = dir.join(format!(
+        ".{}.{}.tmp",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+
+    if let Err(e) = std::fs::write(&tmp, content).and_then(|_| std::fs::rename(&tmp, path)) {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(anyhow::anyhow!("Failed to write {}: {}", path.display(), e));
+    }
+    Ok(())
+}
+
+/// Save a status checked after rotating, with its exit country, for
+/// `status --cached`.
+async fn save_cached_status(mut status: status::ConnectionStatus, tor: &config::TorConfig) {
+    status.check_exit_country(tor).await;
+    if let Err(e) = status.save_cached(tor) {
+        debug!("Cannot save the status for `status --cached`: {}", e);
+    }
+}
+
 #[cfg(unix)]
 fn is_root() -> bool {
     unsafe { libc::geteuid() == 0 }
 }
 
-#[cfg(not(unix))]
+/// Whether the process token is in the Administrators group (i.e. elevated).
+#[cfg(windows)]
 fn is_root() -> bool {
-    true // Windows check would go here
+    use windows_sys::Win32::Foundation::PSID;
+    use windows_sys::Win32::Security::{
+        AllocateAndInitializeSid, CheckTokenMembership, FreeSid, SECURITY_NT_AUTHORITY,
+    };
+    use windows_sys::Win32::System::SystemServices::{
+        DOMAIN_ALIAS_RID_ADMINS, SECURITY_BUILTIN_DOMAIN_RID,
+    };
+
+    unsafe {
+        let mut admins: PSID = std::ptr::null_mut();
+        if AllocateAndInitializeSid(
+            &SECURITY_NT_AUTHORITY,
+            2,
+            SECURITY_BUILTIN_DOMAIN_RID as u32,
+            DOMAIN_ALIAS_RID_ADMINS as u32,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            &mut admins,
+        ) == 0
+        {
+            return false;
+        }
+
+        // A null token checks the calling thread's effective token
+        let mut is_member = 0;
+        let ok = CheckTokenMembership(0, admins, &mut is_member) != 0;
+        FreeSid(admins);
+        ok && is_member != 0
+    }
 }
@@ -0,0 +1,89 @@
+use crate::config::TorConfig;
+use crate::control::TorControl;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Counters kept by a long-running nipe (`start --daemon` or
+/// `--foreground`) and exposed on `--metrics-addr`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    rotations: AtomicU64,
+    last_bootstrap: Mutex<Option<Instant>>,
+}
+
+impl Metrics {
+    /// Count a successful identity rotation.
+    pub fn record_rotation(&self) {
+        self.rotations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Note that Tor has just finished bootstrapping.
+    pub fn record_bootstrap(&self) {
+        *self.last_bootstrap.lock().unwrap() = Some(Instant::now());
+    }
+
+    pub fn rotations(&self) -> u64 {
+        self.rotations.load(Ordering::Relaxed)
+    }
+
+    /// Seconds since the last successful bootstrap, if there was one.
+    pub fn seconds_since_bootstrap(&self) -> Option<u64> {
+        self.last_bootstrap
+            .lock()
+            .unwrap()
+            .map(|at| at.elapsed().as_secs())
+    }
+
+    /// Render the counters as Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP nipe_rotations_total Identity rotations since nipe started.\n");
+        out.push_str("# TYPE nipe_rotations_total counter\n");
+        out.push_str(&format!("nipe_rotations_total {}\n", self.rotations()));
+
+        if let Some(secs) = self.seconds_since_bootstrap() {
+            out.push_str(
+                "# HELP nipe_seconds_since_bootstrap Time since Tor last finished bootstrapping.\n",
+            );
+            out.push_str("# TYPE nipe_seconds_since_bootstrap gauge\n");
+            out.push_str(&format!("nipe_seconds_since_bootstrap {}\n", secs));
+        }
+
+        out
+    }
+}
+
+/// Tor's total traffic counters in Prometheus text format, or nothing when
+/// the control port can't be reached.
+pub async fn traffic_metrics(tor: &TorConfig) -> String {
+    let Ok(mut control) = TorControl::connect(tor).await else {
+        return String::new();
+    };
+
+    let mut out = String::new();
+    for (key, name, help) in [
+        (
+            "traffic/read",
+            "nipe_tor_read_bytes_total",
+            "Bytes Tor has read since it started.",
+        ),
+        (
+            "traffic/written",
+            "nipe_tor_written_bytes_total",
+            "Bytes Tor has written since it started.",
+        ),
+    ] {
+        let Ok(value) = control.getinfo(key).await else {
+            continue;
+        };
+        let Ok(bytes) = value.trim().parse::<u64>() else {
+            continue;
+        };
+        out.push_str(&format!("# HELP {} {}\n", name, help));
+        out.push_str(&format!("# TYPE {} counter\n", name));
+        out.push_str(&format!("{} {}\n", name, bytes));
+    }
+    out
+}
@@ -1,34 +1,179 @@
-use crate::config::NipeConfig;
-use crate::engine::NipeEngine;
-use crate::status::ConnectionStatus;
 use anyhow::Result;
 use crossterm::{
     event::{self, Event, KeyCode},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
+use nipe::config::{NipeConfig, TorConfig};
+use nipe::control::TorControl;
+use nipe::engine::NipeEngine;
+use nipe::status::ConnectionStatus;
 use ratatui::{
     backend::CrosstermBackend,
     layout::{Constraint, Direction, Layout},
     style::{Color, Modifier, Style},
     text::{Line, Span},
-    widgets::{Block, Borders, Paragraph},
+    widgets::{Block, Borders, Paragraph, Sparkline},
     Terminal,
 };
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::JoinHandle;
 use tokio::time::Instant;
 
 pub struct Monitor {
     config: NipeConfig,
+    refresh: Duration,
 }
 
-impl Monitor {
-    pub fn new() -> Self {
+/// What a key press asks the monitor to do.
+enum Action {
+    Quit,
+    Rotate,
+    ScrollUp(usize),
+    ScrollDown(usize),
+}
+
+/// How much of the end of Tor's log the monitor reads, and keeps.
+const LOG_TAIL_BYTES: u64 = 64 * 1024;
+const LOG_TAIL_LINES: usize = 500;
+
+/// The last lines of Tor's log, reading at most the final `LOG_TAIL_BYTES`.
+fn read_log_tail(path: &std::path::Path) -> Vec<String> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let Ok(mut file) = std::fs::File::open(path) else {
+        return vec![format!("No Tor log at {}", path.display())];
+    };
+    let len = file.metadata().map(|m| m.len()).unwrap_or(0);
+    let start = len.saturating_sub(LOG_TAIL_BYTES);
+    let mut content = Vec::new();
+    if file.seek(SeekFrom::Start(start)).is_err() || file.read_to_end(&mut content).is_err() {
+        return Vec::new();
+    }
+
+    let content = String::from_utf8_lossy(&content);
+    let mut lines: Vec<String> = content
+        .lines()
+        // A read starting mid-file begins with a partial line
+        .skip(usize::from(start > 0))
+        .map(|l| l.to_string())
+        .collect();
+    let excess = lines.len().saturating_sub(LOG_TAIL_LINES);
+    lines.drain(..excess);
+    lines
+}
+
+/// What the Connection Info panel shows, updated by background checks.
+#[derive(Clone)]
+struct StatusView {
+    status_msg: String,
+    ip_info: String,
+    exit_country: String,
+    is_secure: bool,
+}
+
+impl StatusView {
+    fn checking() -> Self {
         Self {
-            config: NipeConfig::load().unwrap_or_default(),
+            status_msg: "Checking...".to_string(),
+            ip_info: "Unknown".to_string(),
+            exit_country: "Unknown".to_string(),
+            is_secure: false,
         }
     }
 
+    fn from_status(status: ConnectionStatus) -> Self {
+        Self {
+            status_msg: if status.is_tor {
+                "SECURE".to_string()
+            } else {
+                "UNSECURE".to_string()
+            },
+            ip_info: status.current_ip,
+            exit_country: status
+                .exit_country
+                .map_or("Unknown".to_string(), |cc| cc.to_uppercase()),
+            is_secure: status.is_tor,
+        }
+    }
+}
+
+/// How many per-second samples the bandwidth sparkline keeps.
+const BANDWIDTH_HISTORY: usize = 120;
+
+/// Tor's traffic counters from the control port, sampled once a second.
+#[derive(Default)]
+struct Bandwidth {
+    control: Option<TorControl>,
+    last: Option<(u64, u64, Instant)>,
+    read_total: u64,
+    written_total: u64,
+    read_rate: u64,
+    written_rate: u64,
+    /// Download rate history, newest last
+    history: VecDeque<u64>,
+}
+
+impl Bandwidth {
+    async fn sample(&mut self, tor: &TorConfig) {
+        if self.control.is_none() {
+            self.control = TorControl::connect(tor).await.ok();
+        }
+        let Some(control) = self.control.as_mut() else {
+            return;
+        };
+
+        let read = control.getinfo("traffic/read").await;
+        let written = control.getinfo("traffic/written").await;
+        let (Ok(read), Ok(written)) = (read, written) else {
+            // Reconnect next time, e.g. after Tor restarted
+            self.control = None;
+            return;
+        };
+        let read = read.trim().parse().unwrap_or(self.read_total);
+        let written = written.trim().parse().unwrap_or(self.written_total);
+        let now = Instant::now();
+
+        if let Some((last_read, last_written, at)) = self.last {
+            let secs = now.duration_since(at).as_secs_f64().max(0.001);
+            self.read_rate = (read.saturating_sub(last_read) as f64 / secs) as u64;
+            self.written_rate = (written.saturating_sub(last_written) as f64 / secs) as u64;
+            if self.history.len() == BANDWIDTH_HISTORY {
+                self.history.pop_front();
+            }
+            self.history.push_back(self.read_rate);
+        }
+
+        self.last = Some((read, written, now));
+        self.read_total = read;
+        self.written_total = written;
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+impl Monitor {
+    /// A monitor for `config` that re-checks the connection every `refresh`.
+    pub fn new(config: NipeConfig, refresh: Duration) -> Self {
+        Self { config, refresh }
+    }
+
     pub async fn run(&self) -> Result<()> {
         // Setup terminal
         enable_raw_mode()?;
@@ -57,24 +202,45 @@ impl Monitor {
     ) -> Result<()> {
         let mut last_tick = Instant::now();
         let tick_rate = Duration::from_millis(250);
+        let mut bandwidth = Bandwidth::default();
+        let mut last_sample = Instant::now();
+        bandwidth.sample(&self.config.tor).await;
 
-        let mut status_msg = "Checking...".to_string();
-        let mut ip_info = "Unknown".to_string();
-        let mut is_secure = false;
+        // Tor log pane; `log_scroll` counts lines up from the newest
+        let log_path = self.config.tor.log_path();
+        let mut log_lines = read_log_tail(&log_path);
+        let mut log_scroll = 0usize;
 
-        // Initial check
-        if let Ok(status) = ConnectionStatus::check().await {
-            is_secure = status.is_tor;
-            ip_info = status.current_ip;
-            status_msg = if is_secure {
-                "SECURE".to_string()
-            } else {
-                "UNSECURE".to_string()
-            };
-        }
+        // Status checks run in the background so the UI never waits on them
+        let view = Arc::new(Mutex::new(StatusView::checking()));
+        let mut refresh_task = self.spawn_check(view.clone());
+        let mut last_refresh = Instant::now();
 
-        loop {
-            terminal.draw(|f| {
+        // Rotation runs in its own task and reports back when done
+        let (rotated_tx, mut rotated_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut rotating = false;
+
+        let result = loop {
+            if let Ok(rotated) = rotated_rx.try_recv() {
+                rotating = false;
+                match rotated {
+                    Some(rotated) => *view.lock().unwrap() = rotated,
+                    // Shown until the next periodic check
+                    None => view.lock().unwrap().status_msg = "ROTATION FAILED".to_string(),
+                }
+            }
+
+            let StatusView {
+                mut status_msg,
+                ip_info,
+                exit_country,
+                is_secure,
+            } = view.lock().unwrap().clone();
+            if rotating {
+                status_msg = "Rotating...".to_string();
+            }
+
+            let drawn = terminal.draw(|f| {
                 let size = f.size();
                 let chunks = Layout::default()
                     .direction(Direction::Vertical)
@@ -82,7 +248,9 @@ impl Monitor {
                     .constraints(
                         [
                             Constraint::Length(3), // Title
-                            Constraint::Min(5),    // Main Content
+                            Constraint::Length(7), // Main Content
+                            Constraint::Length(8), // Bandwidth
+                            Constraint::Min(5),    // Tor Log
                             Constraint::Length(3), // Footer
                         ]
                         .as_ref(),
@@ -119,14 +287,7 @@ impl Monitor {
                     Line::from(""),
                     Line::from(vec![
                         Span::raw("Exit Country: "),
-                        Span::styled(
-                            self.config
-                                .tor
-                                .country
-                                .clone()
-                                .unwrap_or("Random".to_string()),
-                            Style::default().fg(Color::Blue),
-                        ),
+                        Span::styled(exit_country.clone(), Style::default().fg(Color::Blue)),
                     ]),
                 ];
 
@@ -139,48 +300,191 @@ impl Monitor {
                     .style(Style::default().fg(Color::White));
                 f.render_widget(main_block, chunks[1]);
 
+                // Bandwidth
+                let bandwidth_block = Block::default()
+                    .title("Tor Bandwidth")
+                    .borders(Borders::ALL);
+                let bandwidth_area = bandwidth_block.inner(chunks[2]);
+                f.render_widget(bandwidth_block, chunks[2]);
+                let bandwidth_chunks = Layout::default()
+                    .direction(Direction::Vertical)
+                    .constraints([Constraint::Length(2), Constraint::Min(1)].as_ref())
+                    .split(bandwidth_area);
+
+                let totals = vec![
+                    Line::from(vec![
+                        Span::raw("Down: "),
+                        Span::styled(
+                            format!("{}/s", format_bytes(bandwidth.read_rate)),
+                            Style::default().fg(Color::Green),
+                        ),
+                        Span::raw(format!(" (total {})", format_bytes(bandwidth.read_total))),
+                    ]),
+                    Line::from(vec![
+                        Span::raw("Up:   "),
+                        Span::styled(
+                            format!("{}/s", format_bytes(bandwidth.written_rate)),
+                            Style::default().fg(Color::Magenta),
+                        ),
+                        Span::raw(format!(
+                            " (total {})",
+                            format_bytes(bandwidth.written_total)
+                        )),
+                    ]),
+                ];
+                f.render_widget(Paragraph::new(totals), bandwidth_chunks[0]);
+
+                // Newest samples on the right, as many as fit
+                let width = usize::from(bandwidth_chunks[1].width);
+                let history: Vec<u64> = bandwidth
+                    .history
+                    .iter()
+                    .skip(bandwidth.history.len().saturating_sub(width))
+                    .copied()
+                    .collect();
+                let sparkline = Sparkline::default()
+                    .data(&history)
+                    .style(Style::default().fg(Color::Green));
+                f.render_widget(sparkline, bandwidth_chunks[1]);
+
+                // Tor Log
+                let log_height = usize::from(chunks[3].height.saturating_sub(2));
+                log_scroll = log_scroll.min(log_lines.len().saturating_sub(log_height));
+                let end = log_lines.len() - log_scroll;
+                let visible: Vec<Line> = log_lines[end.saturating_sub(log_height)..end]
+                    .iter()
+                    .map(|l| {
+                        let color = if l.contains("[warn]") {
+                            Color::Yellow
+                        } else if l.contains("[err]") {
+                            Color::Red
+                        } else {
+                            Color::Gray
+                        };
+                        Line::styled(l.as_str(), Style::default().fg(color))
+                    })
+                    .collect();
+                let log_title = if log_scroll > 0 {
+                    format!("Tor Log ({} lines up, End to follow)", log_scroll)
+                } else {
+                    "Tor Log".to_string()
+                };
+                let log = Paragraph::new(visible)
+                    .block(Block::default().title(log_title).borders(Borders::ALL));
+                f.render_widget(log, chunks[3]);
+
                 // Footer
-                let footer = Paragraph::new("Press 'q' to Quit | 'r' to Rotate Identity")
-                    .style(Style::default().fg(Color::Gray))
-                    .block(Block::default().borders(Borders::ALL));
-                f.render_widget(footer, chunks[2]);
-            })?;
+                let footer = Paragraph::new(
+                    "Press 'q' to Quit | 'r' to Rotate Identity | Up/Down to scroll log",
+                )
+                .style(Style::default().fg(Color::Gray))
+                .block(Block::default().borders(Borders::ALL));
+                f.render_widget(footer, chunks[4]);
+            });
+            if let Err(e) = drawn {
+                break Err(e.into());
+            }
 
             let timeout = tick_rate
                 .checked_sub(last_tick.elapsed())
                 .unwrap_or_else(|| Duration::from_secs(0));
 
-            if crossterm::event::poll(timeout)? {
-                if let Event::Key(key) = event::read()? {
-                    match key.code {
-                        KeyCode::Char('q') => return Ok(()),
-                        KeyCode::Char('r') => {
-                            status_msg = "Rotating...".to_string();
-                            // Non-blocking rotation attempt (spawn a task or just do it blocking for now)
-                            // Ideally we shouldn't block the UI thread too long
-                            if let Ok(engine) = NipeEngine::new(self.config.clone()) {
-                                let _ = engine.rotate().await;
-                                // Re-check status
-                                if let Ok(status) = ConnectionStatus::check().await {
-                                    is_secure = status.is_tor;
-                                    ip_info = status.current_ip;
-                                    status_msg = if is_secure {
-                                        "SECURE".to_string()
-                                    } else {
-                                        "UNSECURE".to_string()
-                                    };
-                                }
-                            }
-                        }
-                        _ => {}
-                    }
+            match Self::read_action(timeout) {
+                Ok(Some(Action::Quit)) => break Ok(()),
+                Ok(Some(Action::Rotate)) if !rotating => {
+                    rotating = true;
+                    self.spawn_rotation(rotated_tx.clone());
                 }
+                Ok(Some(Action::ScrollUp(lines))) => log_scroll = log_scroll.saturating_add(lines),
+                Ok(Some(Action::ScrollDown(lines))) => {
+                    log_scroll = log_scroll.saturating_sub(lines)
+                }
+                Ok(_) => {}
+                Err(e) => break Err(e),
+            }
+
+            if last_sample.elapsed() >= Duration::from_secs(1) {
+                bandwidth.sample(&self.config.tor).await;
+                let previous = log_lines.len();
+                log_lines = read_log_tail(&log_path);
+                // Keep a scrolled-back view on the same lines as new ones arrive
+                if log_scroll > 0 {
+                    log_scroll += log_lines.len().saturating_sub(previous);
+                }
+                last_sample = Instant::now();
             }
 
             if last_tick.elapsed() >= tick_rate {
-                // Periodic refresh could go here if needed, but we rely on manual refresh/events for now or slow poll
+                // Re-check the connection periodically, one check at a time
+                if refresh_task.is_finished() && last_refresh.elapsed() >= self.refresh {
+                    refresh_task = self.spawn_check(view.clone());
+                    last_refresh = Instant::now();
+                }
                 last_tick = Instant::now();
             }
+        };
+
+        refresh_task.abort();
+        result
+    }
+
+    /// Wait up to `timeout` for a key press and map it to an action.
+    fn read_action(timeout: Duration) -> Result<Option<Action>> {
+        if !crossterm::event::poll(timeout)? {
+            return Ok(None);
         }
+        Ok(match event::read()? {
+            Event::Key(key) => match key.code {
+                KeyCode::Char('q') => Some(Action::Quit),
+                KeyCode::Char('r') => Some(Action::Rotate),
+                KeyCode::Up => Some(Action::ScrollUp(1)),
+                KeyCode::Down => Some(Action::ScrollDown(1)),
+                KeyCode::PageUp => Some(Action::ScrollUp(10)),
+                KeyCode::PageDown => Some(Action::ScrollDown(10)),
+                KeyCode::End => Some(Action::ScrollDown(usize::MAX)),
+                _ => None,
+            },
+            _ => None,
+        })
     }
+
+    /// Rotate identity and re-check the connection in the background,
+    /// sending the new status (or `None` on failure) when finished.
+    fn spawn_rotation(&self, done: UnboundedSender<Option<StatusView>>) {
+        let config = self.config.clone();
+        tokio::spawn(async move {
+            let rotated = match NipeEngine::new(config.clone()) {
+                Ok(engine) => engine.rotate().await.is_ok(),
+                Err(_) => false,
+            };
+            let status = if rotated {
+                // Give Tor a moment to switch to the new circuit
+                tokio::time::sleep(Duration::from_secs(2)).await;
+                check_status(&config.tor)
+                    .await
+                    .ok()
+                    .map(StatusView::from_status)
+            } else {
+                None
+            };
+            let _ = done.send(status);
+        });
+    }
+
+    /// Run one connection check in the background, publishing it to `view`.
+    fn spawn_check(&self, view: Arc<Mutex<StatusView>>) -> JoinHandle<()> {
+        let tor = self.config.tor.clone();
+        tokio::spawn(async move {
+            if let Ok(status) = check_status(&tor).await {
+                *view.lock().unwrap() = StatusView::from_status(status);
+            }
+        })
+    }
+}
+
+/// Run the connection check and resolve the exit's actual country.
+async fn check_status(tor: &TorConfig) -> Result<ConnectionStatus> {
+    let mut status = ConnectionStatus::check(tor).await?;
+    status.check_exit_country(tor).await;
+    Ok(status)
 }
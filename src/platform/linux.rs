@@ -1,35 +1,112 @@
+use crate::config::NipeConfig;
 use crate::error::{NipeError, Result};
-use crate::platform::FirewallProvider;
+use crate::platform::{self, CommandSpec, FirewallProvider};
+use std::path::PathBuf;
 use std::process::Command;
-use tracing::info;
+use tracing::{debug, info, warn};
+
+/// Name of the nftables table holding all of Nipe's rules
+const NFT_TABLE: &str = "nipe";
+
+/// Chain holding Nipe's rules in each iptables table, jumped to from OUTPUT
+const IPT_CHAIN: &str = "NIPE_OUTPUT";
+
+/// Private (RFC 1918) and link-local ranges reachable when `allow_lan` is set
+const LAN_RANGES: [&str; 4] = [
+    "10.0.0.0/8",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "169.254.0.0/16",
+];
+
+/// Why the kill switch is refused when Tor has to run as root.
+const NO_TOR_USER: &str = "no unprivileged user (debian-tor, tor or nobody) to run Tor as; \
+     Tor would run as root, and exempting it would let every root process bypass the kill switch";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Backend {
+    Nftables,
+    Iptables,
+}
 
 pub struct LinuxFirewall {
-    tor_user: String,
+    /// Uid of the unprivileged account Tor runs as; `None` when there is
+    /// none and Tor runs as root
+    tor_user: Option<String>,
+    backend: Backend,
+    allow_lan: bool,
+    block_ipv6: bool,
+    trans_port: u16,
+    dns_port: u16,
+    has_ip6tables: bool,
+    /// `firewall.bypass_ports`, as rule arguments
+    bypass_ports: Vec<String>,
+    /// `firewall.bypass_cidrs`, split by address family
+    bypass_v4: Vec<String>,
+    bypass_v6: Vec<String>,
+    /// Where the iptables rulesets from before `start` are saved; root-only,
+    /// since whatever is there gets loaded with `iptables-restore`
+    backup_dir: PathBuf,
 }
 
 impl FirewallProvider for LinuxFirewall {
-    fn new() -> Result<Self> {
+    fn new(config: &NipeConfig) -> Result<Self> {
+        let (bypass_v6, bypass_v4): (Vec<&String>, Vec<&String>) = config
+            .firewall
+            .bypass_cidrs
+            .iter()
+            .partition(|cidr| cidr.contains(':'));
+        let (bypass_v4, bypass_v6) = (bypass_v4.into_iter(), bypass_v6.into_iter());
+
         Ok(Self {
-            tor_user: "debian-tor".to_string(), // Default Tor user on Debian/Ubuntu
+            // The engine runs Tor as this user, or as root when none exists
+            tor_user: platform::find_tor_user().map(|(uid, _)| uid.to_string()),
+            backend: detect_backend(),
+            allow_lan: config.firewall.allow_lan,
+            block_ipv6: config.firewall.block_ipv6,
+            trans_port: config.tor.trans_port,
+            dns_port: config.tor.dns_port,
+            has_ip6tables: has_command("ip6tables"),
+            bypass_ports: config
+                .firewall
+                .bypass_ports
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            bypass_v4: bypass_v4.cloned().collect(),
+            bypass_v6: bypass_v6.cloned().collect(),
+            backup_dir: config.tor.state_dir(),
         })
     }
 
     fn enable_kill_switch(&self) -> Result<()> {
-        info!("Enabling Linux kill switch with iptables");
-
-        // Flush existing rules
-        Command::new("iptables")
-            .args(&["-t", "nat", "-F", "OUTPUT"])
-            .output()?;
-        Command::new("iptables")
-            .args(&["-t", "filter", "-F", "OUTPUT"])
-            .output()?;
+        info!("Enabling Linux kill switch with {}", self.backend());
+        if self.tor_user.is_none() {
+            return Err(NipeError::FirewallError(NO_TOR_USER.to_string()));
+        }
+        if self.backend == Backend::Iptables && self.block_ipv6 && !self.has_ip6tables {
+            warn!("ip6tables not found: IPv6 traffic is NOT blocked and may bypass Tor");
+        }
 
-        // NAT table rules
-        self.setup_nat_rules()?;
+        if self.backend == Backend::Iptables {
+            self.backup_iptables()?;
+        }
 
-        // Filter table rules
-        self.setup_filter_rules()?;
+        // Deleting what isn't there fails, so only these may fail quietly
+        for command in self.reset_commands() {
+            command.output()?;
+        }
+        // A rule that doesn't apply leaves a hole in the kill switch
+        for command in self.kill_switch_commands() {
+            let output = command.output()?;
+            if !output.status.success() {
+                return Err(NipeError::FirewallError(format!(
+                    "`{}` failed: {}",
+                    command,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )));
+            }
+        }
 
         info!("Kill switch enabled");
         Ok(())
@@ -38,18 +115,14 @@ impl FirewallProvider for LinuxFirewall {
     fn disable_kill_switch(&self) -> Result<()> {
         info!("Disabling Linux kill switch");
 
-        Command::new("iptables")
-            .args(&["-t", "nat", "-F", "OUTPUT"])
-            .output()?;
-        Command::new("iptables")
-            .args(&["-t", "filter", "-F", "OUTPUT"])
-            .output()?;
-        Command::new("iptables")
-            .args(&["-t", "nat", "-X"])
-            .output()?;
-        Command::new("iptables")
-            .args(&["-t", "filter", "-X"])
-            .output()?;
+        if self.backend == Backend::Iptables {
+            self.restore_iptables();
+        }
+        // After a restore there is nothing left to remove; without a backup
+        // this still takes out Nipe's own chains
+        for command in self.disable_commands() {
+            command.output()?;
+        }
 
         info!("Kill switch disabled");
         Ok(())
@@ -66,16 +139,559 @@ impl FirewallProvider for LinuxFirewall {
         // No-op on Linux
         Ok(())
     }
+
+    fn backend(&self) -> &'static str {
+        match self.backend {
+            Backend::Nftables => "nftables",
+            Backend::Iptables => "iptables",
+        }
+    }
+
+    fn describe_enable(&self, socks_port: u16, _web_proxy_port: Option<u16>) -> Vec<String> {
+        let mut lines = Vec::new();
+        if self.tor_user.is_none() {
+            lines.push(format!("# Would fail: {}", NO_TOR_USER));
+        }
+        if self.backend == Backend::Iptables {
+            for program in self.iptables_programs() {
+                lines.push(format!(
+                    "{}-save > {}",
+                    program,
+                    self.backup_path(program).display()
+                ));
+            }
+        }
+        lines.extend(
+            self.reset_commands()
+                .iter()
+                .chain(&self.kill_switch_commands())
+                .map(ToString::to_string),
+        );
+        lines.push(format!(
+            "# No system proxy is set; apps use SOCKS at 127.0.0.1:{}",
+            socks_port
+        ));
+        lines
+    }
+
+    fn backend_available(&self) -> bool {
+        match self.backend {
+            Backend::Nftables => has_command("nft"),
+            Backend::Iptables => has_command("iptables"),
+        }
+    }
+}
+
+/// Whether `tool --version` runs successfully.
+fn has_command(tool: &str) -> bool {
+    Command::new(tool)
+        .arg("--version")
+        .output()
+        .map(|o| o.status.success())
+        .unwrap_or(false)
+}
+
+/// Prefer nftables when the `nft` tool is present; fall back to iptables.
+fn detect_backend() -> Backend {
+    if has_command("nft") {
+        Backend::Nftables
+    } else {
+        Backend::Iptables
+    }
 }
 
 impl LinuxFirewall {
-    fn setup_nat_rules(&self) -> Result<()> {
-        let commands = vec![
+    /// The uid exempted from the kill switch as Tor's. Root only when no
+    /// Tor user exists, which [`enable_kill_switch`](FirewallProvider::enable_kill_switch) refuses.
+    fn tor_uid(&self) -> &str {
+        self.tor_user.as_deref().unwrap_or("0")
+    }
+
+    /// The kill switch rules, each of which must apply.
+    fn kill_switch_commands(&self) -> Vec<CommandSpec> {
+        match self.backend {
+            Backend::Nftables => self.nft_kill_switch_commands(),
+            Backend::Iptables => {
+                let mut commands = self.nat_rules();
+                commands.extend(self.filter_rules());
+                commands.extend(self.ipv6_rules());
+                commands
+            }
+        }
+    }
+
+    /// Remove rules left by an earlier start, so repeated starts don't
+    /// stack them; run before [`Self::kill_switch_commands`].
+    fn reset_commands(&self) -> Vec<CommandSpec> {
+        match self.backend {
+            Backend::Nftables => Self::nft_delete_tables(),
+            Backend::Iptables => self.iptables_remove_chains(),
+        }
+    }
+
+    /// Every command `disable_kill_switch` runs, in order.
+    fn disable_commands(&self) -> Vec<CommandSpec> {
+        match self.backend {
+            // All rules live in our own tables, so dropping them restores the system
+            Backend::Nftables => Self::nft_delete_tables(),
+            Backend::Iptables => self.iptables_remove_chains(),
+        }
+    }
+
+    /// The iptables tools in use: IPv4 always, IPv6 when installed.
+    fn iptables_programs(&self) -> Vec<&'static str> {
+        if self.has_ip6tables {
+            vec!["iptables", "ip6tables"]
+        } else {
+            vec!["iptables"]
+        }
+    }
+
+    fn backup_path(&self, program: &str) -> PathBuf {
+        self.backup_dir.join(format!("{}.rules", program))
+    }
+
+    /// Save the current rulesets so `disable_kill_switch` can put them back
+    /// exactly. A backup left by a start that was never stopped is kept,
+    /// since the live rules may already be Nipe's; one root did not write
+    /// is replaced.
+    fn backup_iptables(&self) -> Result<()> {
+        if let Some(parent) = self.backup_dir.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        platform::private_dir(&self.backup_dir)?;
+        for program in self.iptables_programs() {
+            let path = self.backup_path(program);
+            if platform::owned_by_us(&path) {
+                continue;
+            }
+            // Not ours, so not a backup to trust; replace it
+            if path.symlink_metadata().is_ok() {
+                std::fs::remove_file(&path)?;
+            }
+
+            let output = Command::new(format!("{}-save", program)).output()?;
+            if !output.status.success() {
+                return Err(NipeError::FirewallError(format!(
+                    "{}-save failed: {}",
+                    program,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )));
+            }
+            std::fs::write(&path, output.stdout)?;
+        }
+        Ok(())
+    }
+
+    /// Restore and remove the backups from [`Self::backup_iptables`]; a
+    /// missing or unusable backup is left to the chain removal.
+    fn restore_iptables(&self) {
+        for program in self.iptables_programs() {
+            let path = self.backup_path(program);
+            if path.symlink_metadata().is_ok() && !platform::owned_by_us(&path) {
+                warn!(
+                    "Not restoring {}: it is not a file root wrote; removing only Nipe's rules",
+                    path.display()
+                );
+                continue;
+            }
+            if !path.exists() {
+                // Also the normal case for the stop that precedes every start
+                debug!(
+                    "No {} backup at {}; removing only Nipe's rules",
+                    program,
+                    path.display()
+                );
+                continue;
+            }
+
+            let restore = CommandSpec::new(
+                &format!("{}-restore", program),
+                [path.display().to_string()],
+            );
+            match restore.output() {
+                Ok(output) if output.status.success() => {
+                    info!("Restored {} rules from {}", program, path.display());
+                    let _ = std::fs::remove_file(&path);
+                }
+                Ok(output) => warn!(
+                    "`{}` failed: {}",
+                    restore,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ),
+                Err(e) => warn!("`{}` failed: {}", restore, e),
+            }
+        }
+    }
+
+    /// Unhook and delete [`IPT_CHAIN`] in every table Nipe uses, leaving
+    /// the rest of OUTPUT alone. Each step fails if the chain isn't there.
+    fn iptables_remove_chains(&self) -> Vec<CommandSpec> {
+        let mut tables = vec![("iptables", "nat"), ("iptables", "filter")];
+        if self.has_ip6tables {
+            tables.push(("ip6tables", "filter"));
+        }
+
+        tables
+            .into_iter()
+            .flat_map(|(program, table)| {
+                [
+                    vec!["-t", table, "-D", "OUTPUT", "-j", IPT_CHAIN],
+                    vec!["-t", table, "-F", IPT_CHAIN],
+                    vec!["-t", table, "-X", IPT_CHAIN],
+                ]
+                .into_iter()
+                .map(move |args| CommandSpec::new(program, args))
+            })
+            .collect()
+    }
+
+    fn nft_delete_tables() -> Vec<CommandSpec> {
+        ["ip", "ip6"]
+            .into_iter()
+            .map(|family| CommandSpec::new("nft", ["delete", "table", family, NFT_TABLE]))
+            .collect()
+    }
+
+    fn nft_kill_switch_commands(&self) -> Vec<CommandSpec> {
+        let dns_target = format!(":{}", self.dns_port);
+        let trans_target = format!(":{}", self.trans_port);
+
+        let mut commands = vec![
+            vec!["add", "table", "ip", NFT_TABLE],
+            vec![
+                "add",
+                "chain",
+                "ip",
+                NFT_TABLE,
+                "nat_output",
+                "{ type nat hook output priority -100 ; }",
+            ],
+            vec![
+                "add",
+                "chain",
+                "ip",
+                NFT_TABLE,
+                "filter_output",
+                "{ type filter hook output priority 0 ; }",
+            ],
+            // NAT rules
+            vec![
+                "add",
+                "rule",
+                "ip",
+                NFT_TABLE,
+                "nat_output",
+                "ct",
+                "state",
+                "established",
+                "return",
+            ],
+            vec![
+                "add",
+                "rule",
+                "ip",
+                NFT_TABLE,
+                "nat_output",
+                "meta",
+                "skuid",
+                self.tor_uid(),
+                "return",
+            ],
+            // Nipe's own SOCKS and control connections stay local
+            vec![
+                "add",
+                "rule",
+                "ip",
+                NFT_TABLE,
+                "nat_output",
+                "oifname",
+                "lo",
+                "return",
+            ],
+            vec![
+                "add",
+                "rule",
+                "ip",
+                NFT_TABLE,
+                "nat_output",
+                "ip",
+                "daddr",
+                "127.0.0.0/8",
+                "return",
+            ],
+            vec![
+                "add",
+                "rule",
+                "ip",
+                NFT_TABLE,
+                "nat_output",
+                "udp",
+                "dport",
+                "53",
+                "redirect",
+                "to",
+                &dns_target,
+            ],
+            vec![
+                "add",
+                "rule",
+                "ip",
+                NFT_TABLE,
+                "nat_output",
+                "tcp",
+                "dport",
+                "53",
+                "redirect",
+                "to",
+                &dns_target,
+            ],
+        ];
+        // LAN traffic goes out directly; DNS above still goes through Tor
+        if self.allow_lan {
+            for range in LAN_RANGES {
+                commands.push(vec![
+                    "add",
+                    "rule",
+                    "ip",
+                    NFT_TABLE,
+                    "nat_output",
+                    "ip",
+                    "daddr",
+                    range,
+                    "return",
+                ]);
+            }
+        }
+        for cidr in &self.bypass_v4 {
+            commands.push(vec![
+                "add",
+                "rule",
+                "ip",
+                NFT_TABLE,
+                "nat_output",
+                "ip",
+                "daddr",
+                cidr,
+                "return",
+            ]);
+        }
+        for port in &self.bypass_ports {
+            commands.push(vec![
+                "add",
+                "rule",
+                "ip",
+                NFT_TABLE,
+                "nat_output",
+                "tcp",
+                "dport",
+                port,
+                "return",
+            ]);
+        }
+        commands.push(vec![
+            "add",
+            "rule",
+            "ip",
+            NFT_TABLE,
+            "nat_output",
+            "meta",
+            "l4proto",
+            "tcp",
+            "redirect",
+            "to",
+            &trans_target,
+        ]);
+        // Filter rules
+        commands.extend([
+            vec![
+                "add",
+                "rule",
+                "ip",
+                NFT_TABLE,
+                "filter_output",
+                "ct",
+                "state",
+                "established",
+                "accept",
+            ],
+            vec![
+                "add",
+                "rule",
+                "ip",
+                NFT_TABLE,
+                "filter_output",
+                "meta",
+                "skuid",
+                self.tor_uid(),
+                "accept",
+            ],
+            vec![
+                "add",
+                "rule",
+                "ip",
+                NFT_TABLE,
+                "filter_output",
+                "oifname",
+                "lo",
+                "accept",
+            ],
+            vec![
+                "add",
+                "rule",
+                "ip",
+                NFT_TABLE,
+                "filter_output",
+                "ip",
+                "daddr",
+                "127.0.0.0/8",
+                "accept",
+            ],
+        ]);
+        if self.allow_lan {
+            for range in LAN_RANGES {
+                commands.push(vec![
+                    "add",
+                    "rule",
+                    "ip",
+                    NFT_TABLE,
+                    "filter_output",
+                    "ip",
+                    "daddr",
+                    range,
+                    "accept",
+                ]);
+            }
+        }
+        for cidr in &self.bypass_v4 {
+            commands.push(vec![
+                "add",
+                "rule",
+                "ip",
+                NFT_TABLE,
+                "filter_output",
+                "ip",
+                "daddr",
+                cidr,
+                "accept",
+            ]);
+        }
+        for port in &self.bypass_ports {
+            for proto in ["tcp", "udp"] {
+                commands.push(vec![
+                    "add",
+                    "rule",
+                    "ip",
+                    NFT_TABLE,
+                    "filter_output",
+                    proto,
+                    "dport",
+                    port,
+                    "accept",
+                ]);
+            }
+        }
+        commands.extend([
+            vec![
+                "add",
+                "rule",
+                "ip",
+                NFT_TABLE,
+                "filter_output",
+                "meta",
+                "l4proto",
+                "udp",
+                "reject",
+            ],
+            vec![
+                "add",
+                "rule",
+                "ip",
+                NFT_TABLE,
+                "filter_output",
+                "meta",
+                "l4proto",
+                "icmp",
+                "reject",
+            ],
+        ]);
+
+        if self.block_ipv6 {
+            commands.extend([
+                vec!["add", "table", "ip6", NFT_TABLE],
+                vec![
+                    "add",
+                    "chain",
+                    "ip6",
+                    NFT_TABLE,
+                    "filter_output",
+                    "{ type filter hook output priority 0 ; }",
+                ],
+                vec![
+                    "add",
+                    "rule",
+                    "ip6",
+                    NFT_TABLE,
+                    "filter_output",
+                    "oifname",
+                    "lo",
+                    "accept",
+                ],
+            ]);
+            for cidr in &self.bypass_v6 {
+                commands.push(vec![
+                    "add",
+                    "rule",
+                    "ip6",
+                    NFT_TABLE,
+                    "filter_output",
+                    "ip6",
+                    "daddr",
+                    cidr,
+                    "accept",
+                ]);
+            }
+            for port in &self.bypass_ports {
+                for proto in ["tcp", "udp"] {
+                    commands.push(vec![
+                        "add",
+                        "rule",
+                        "ip6",
+                        NFT_TABLE,
+                        "filter_output",
+                        proto,
+                        "dport",
+                        port,
+                        "accept",
+                    ]);
+                }
+            }
+            commands.push(vec![
+                "add",
+                "rule",
+                "ip6",
+                NFT_TABLE,
+                "filter_output",
+                "drop",
+            ]);
+        }
+
+        commands
+            .into_iter()
+            .map(|args| CommandSpec::new("nft", args))
+            .collect()
+    }
+
+    fn nat_rules(&self) -> Vec<CommandSpec> {
+        let dns_port = self.dns_port.to_string();
+        let trans_port = self.trans_port.to_string();
+        let mut commands = vec![
+            vec!["-t", "nat", "-N", IPT_CHAIN],
             vec![
                 "-t",
                 "nat",
                 "-A",
-                "OUTPUT",
+                IPT_CHAIN,
                 "-m",
                 "state",
                 "--state",
@@ -87,70 +703,101 @@ impl LinuxFirewall {
                 "-t",
                 "nat",
                 "-A",
-                "OUTPUT",
+                IPT_CHAIN,
                 "-m",
                 "owner",
                 "--uid-owner",
-                &self.tor_user,
+                self.tor_uid(),
                 "-j",
                 "RETURN",
             ],
+            // Nipe's own SOCKS and control connections stay local
+            vec!["-t", "nat", "-A", IPT_CHAIN, "-o", "lo", "-j", "RETURN"],
             vec![
                 "-t",
                 "nat",
                 "-A",
-                "OUTPUT",
-                "-p",
-                "udp",
-                "--dport",
-                "53",
+                IPT_CHAIN,
+                "-d",
+                "127.0.0.0/8",
                 "-j",
-                "REDIRECT",
-                "--to-ports",
-                "9061",
+                "RETURN",
             ],
             vec![
                 "-t",
                 "nat",
                 "-A",
-                "OUTPUT",
+                IPT_CHAIN,
                 "-p",
-                "tcp",
+                "udp",
                 "--dport",
                 "53",
                 "-j",
                 "REDIRECT",
                 "--to-ports",
-                "9061",
+                &dns_port,
             ],
             vec![
                 "-t",
                 "nat",
                 "-A",
-                "OUTPUT",
+                IPT_CHAIN,
                 "-p",
                 "tcp",
+                "--dport",
+                "53",
                 "-j",
                 "REDIRECT",
                 "--to-ports",
-                "9051",
+                &dns_port,
             ],
         ];
-
-        for args in commands {
-            Command::new("iptables").args(&args).output()?;
+        // LAN traffic goes out directly; DNS above still goes through Tor
+        if self.allow_lan {
+            for range in LAN_RANGES {
+                commands.push(vec![
+                    "-t", "nat", "-A", IPT_CHAIN, "-d", range, "-j", "RETURN",
+                ]);
+            }
+        }
+        for cidr in &self.bypass_v4 {
+            commands.push(vec![
+                "-t", "nat", "-A", IPT_CHAIN, "-d", cidr, "-j", "RETURN",
+            ]);
         }
+        for port in &self.bypass_ports {
+            commands.push(vec![
+                "-t", "nat", "-A", IPT_CHAIN, "-p", "tcp", "--dport", port, "-j", "RETURN",
+            ]);
+        }
+        commands.push(vec![
+            "-t",
+            "nat",
+            "-A",
+            IPT_CHAIN,
+            "-p",
+            "tcp",
+            "-j",
+            "REDIRECT",
+            "--to-ports",
+            &trans_port,
+        ]);
+        commands.push(vec!["-t", "nat", "-I", "OUTPUT", "1", "-j", IPT_CHAIN]);
 
-        Ok(())
+        commands
+            .into_iter()
+            .map(|args| CommandSpec::new("iptables", args))
+            .collect()
     }
 
-    fn setup_filter_rules(&self) -> Result<()> {
-        let commands = vec![
+    fn filter_rules(&self) -> Vec<CommandSpec> {
+        let mut commands = vec![
+            vec!["-t", "filter", "-N", IPT_CHAIN],
             vec![
                 "-t",
                 "filter",
                 "-A",
-                "OUTPUT",
+                IPT_CHAIN,
                 "-m",
                 "state",
                 "--state",
@@ -162,22 +809,350 @@ impl LinuxFirewall {
                 "-t",
                 "filter",
                 "-A",
-                "OUTPUT",
+                IPT_CHAIN,
                 "-m",
                 "owner",
                 "--uid-owner",
-                &self.tor_user,
+                self.tor_uid(),
+                "-j",
+                "ACCEPT",
+            ],
+            vec!["-t", "filter", "-A", IPT_CHAIN, "-o", "lo", "-j", "ACCEPT"],
+            vec![
+                "-t",
+                "filter",
+                "-A",
+                IPT_CHAIN,
+                "-d",
+                "127.0.0.0/8",
                 "-j",
                 "ACCEPT",
             ],
-            vec!["-t", "filter", "-A", "OUTPUT", "-p", "udp", "-j", "REJECT"],
-            vec!["-t", "filter", "-A", "OUTPUT", "-p", "icmp", "-j", "REJECT"],
         ];
+        if self.allow_lan {
+            for range in LAN_RANGES {
+                commands.push(vec![
+                    "-t", "filter", "-A", IPT_CHAIN, "-d", range, "-j", "ACCEPT",
+                ]);
+            }
+        }
+        for cidr in &self.bypass_v4 {
+            commands.push(vec![
+                "-t", "filter", "-A", IPT_CHAIN, "-d", cidr, "-j", "ACCEPT",
+            ]);
+        }
+        for port in &self.bypass_ports {
+            for proto in ["tcp", "udp"] {
+                commands.push(vec![
+                    "-t", "filter", "-A", IPT_CHAIN, "-p", proto, "--dport", port, "-j", "ACCEPT",
+                ]);
+            }
+        }
+        commands.extend([
+            vec!["-t", "filter", "-A", IPT_CHAIN, "-p", "udp", "-j", "REJECT"],
+            vec![
+                "-t", "filter", "-A", IPT_CHAIN, "-p", "icmp", "-j", "REJECT",
+            ],
+            vec!["-t", "filter", "-I", "OUTPUT", "1", "-j", IPT_CHAIN],
+        ]);
 
-        for args in commands {
-            Command::new("iptables").args(&args).output()?;
+        commands
+            .into_iter()
+            .map(|args| CommandSpec::new("iptables", args))
+            .collect()
+    }
+
+    /// Drop all IPv6 output except loopback; the IPv4 rules above don't
+    /// cover it, so without these a dual-stack host leaks around Tor.
+    fn ipv6_rules(&self) -> Vec<CommandSpec> {
+        if !self.has_ip6tables || !self.block_ipv6 {
+            return vec![];
         }
 
-        Ok(())
+        let mut commands = vec![
+            vec!["-t", "filter", "-N", IPT_CHAIN],
+            vec!["-t", "filter", "-A", IPT_CHAIN, "-o", "lo", "-j", "ACCEPT"],
+        ];
+        for cidr in &self.bypass_v6 {
+            commands.push(vec![
+                "-t", "filter", "-A", IPT_CHAIN, "-d", cidr, "-j", "ACCEPT",
+            ]);
+        }
+        for port in &self.bypass_ports {
+            for proto in ["tcp", "udp"] {
+                commands.push(vec![
+                    "-t", "filter", "-A", IPT_CHAIN, "-p", proto, "--dport", port, "-j", "ACCEPT",
+                ]);
+            }
+        }
+        commands.extend([
+            vec!["-t", "filter", "-A", IPT_CHAIN, "-j", "DROP"],
+            vec!["-t", "filter", "-I", "OUTPUT", "1", "-j", IPT_CHAIN],
+        ]);
+
+        commands
+            .into_iter()
+            .map(|args| CommandSpec::new("ip6tables", args))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A firewall for `config`, with the host-specific parts pinned.
+    fn firewall(backend: Backend, configure: impl FnOnce(&mut NipeConfig)) -> LinuxFirewall {
+        let mut config = NipeConfig::default();
+        configure(&mut config);
+        LinuxFirewall {
+            tor_user: Some("108".to_string()),
+            backend,
+            has_ip6tables: true,
+            ..LinuxFirewall::new(&config).unwrap()
+        }
+    }
+
+    fn lines(commands: &[CommandSpec]) -> Vec<String> {
+        commands.iter().map(ToString::to_string).collect()
+    }
+
+    /// Index of `rule` in `rules`, failing the test when it is missing.
+    fn position(rules: &[String], rule: &str) -> usize {
+        rules
+            .iter()
+            .position(|r| r == rule)
+            .unwrap_or_else(|| panic!("`{}` not in {:#?}", rule, rules))
+    }
+
+    #[test]
+    fn refuses_the_kill_switch_when_tor_runs_as_root() {
+        let fw = LinuxFirewall {
+            tor_user: None,
+            ..firewall(Backend::Iptables, |_| {})
+        };
+        assert!(matches!(
+            fw.enable_kill_switch(),
+            Err(NipeError::FirewallError(_))
+        ));
+        assert!(fw.describe_enable(9050, None)[0].starts_with("# Would fail:"));
+    }
+
+    #[test]
+    fn nat_rules_exempt_tor_and_loopback_before_redirecting() {
+        let rules = lines(&firewall(Backend::Iptables, |_| {}).nat_rules());
+
+        let tor = position(
+            &rules,
+            "iptables -t nat -A NIPE_OUTPUT -m owner --uid-owner 108 -j RETURN",
+        );
+        let lo = position(&rules, "iptables -t nat -A NIPE_OUTPUT -o lo -j RETURN");
+        let local = position(
+            &rules,
+            "iptables -t nat -A NIPE_OUTPUT -d 127.0.0.0/8 -j RETURN",
+        );
+        let dns = position(
+            &rules,
+            "iptables -t nat -A NIPE_OUTPUT -p udp --dport 53 -j REDIRECT --to-ports 9061",
+        );
+        let tcp = position(
+            &rules,
+            "iptables -t nat -A NIPE_OUTPUT -p tcp -j REDIRECT --to-ports 9040",
+        );
+        assert!(tor < dns && lo < dns && local < dns && dns < tcp);
+        assert_eq!(
+            rules.last().unwrap(),
+            "iptables -t nat -I OUTPUT 1 -j NIPE_OUTPUT"
+        );
+    }
+
+    #[test]
+    fn allow_lan_returns_lan_ranges_after_dns() {
+        let rules =
+            lines(&firewall(Backend::Iptables, |c| c.firewall.allow_lan = true).nat_rules());
+        let dns = position(
+            &rules,
+            "iptables -t nat -A NIPE_OUTPUT -p tcp --dport 53 -j REDIRECT --to-ports 9061",
+        );
+        let tcp = position(
+            &rules,
+            "iptables -t nat -A NIPE_OUTPUT -p tcp -j REDIRECT --to-ports 9040",
+        );
+        for range in LAN_RANGES {
+            let lan = position(
+                &rules,
+                &format!("iptables -t nat -A NIPE_OUTPUT -d {} -j RETURN", range),
+            );
+            assert!(dns < lan && lan < tcp);
+        }
+
+        let filter =
+            lines(&firewall(Backend::Iptables, |c| c.firewall.allow_lan = true).filter_rules());
+        position(
+            &filter,
+            "iptables -t filter -A NIPE_OUTPUT -d 192.168.0.0/16 -j ACCEPT",
+        );
+
+        let rules =
+            lines(&firewall(Backend::Iptables, |c| c.firewall.allow_lan = false).nat_rules());
+        assert!(!rules.iter().any(|r| r.contains("192.168.0.0/16")));
+    }
+
+    #[test]
+    fn filter_rules_accept_tor_and_loopback_then_reject() {
+        let rules = lines(&firewall(Backend::Iptables, |_| {}).filter_rules());
+        let tor = position(
+            &rules,
+            "iptables -t filter -A NIPE_OUTPUT -m owner --uid-owner 108 -j ACCEPT",
+        );
+        let lo = position(&rules, "iptables -t filter -A NIPE_OUTPUT -o lo -j ACCEPT");
+        let udp = position(&rules, "iptables -t filter -A NIPE_OUTPUT -p udp -j REJECT");
+        let icmp = position(
+            &rules,
+            "iptables -t filter -A NIPE_OUTPUT -p icmp -j REJECT",
+        );
+        assert!(tor < udp && lo < udp && udp < icmp);
+    }
+
+    #[test]
+    fn bypass_settings_are_let_through_before_the_catch_alls() {
+        let fw = firewall(Backend::Iptables, |c| {
+            c.firewall.bypass_ports = vec![22];
+            c.firewall.bypass_cidrs =
+                vec!["203.0.113.0/24".to_string(), "2001:db8::/32".to_string()];
+        });
+        assert_eq!(fw.bypass_v4, ["203.0.113.0/24"]);
+        assert_eq!(fw.bypass_v6, ["2001:db8::/32"]);
+
+        let nat = lines(&fw.nat_rules());
+        let tcp = position(
+            &nat,
+            "iptables -t nat -A NIPE_OUTPUT -p tcp -j REDIRECT --to-ports 9040",
+        );
+        assert!(
+            position(
+                &nat,
+                "iptables -t nat -A NIPE_OUTPUT -d 203.0.113.0/24 -j RETURN"
+            ) < tcp
+        );
+        assert!(
+            position(
+                &nat,
+                "iptables -t nat -A NIPE_OUTPUT -p tcp --dport 22 -j RETURN"
+            ) < tcp
+        );
+
+        let filter = lines(&fw.filter_rules());
+        let udp = position(
+            &filter,
+            "iptables -t filter -A NIPE_OUTPUT -p udp -j REJECT",
+        );
+        for rule in [
+            "iptables -t filter -A NIPE_OUTPUT -d 203.0.113.0/24 -j ACCEPT",
+            "iptables -t filter -A NIPE_OUTPUT -p tcp --dport 22 -j ACCEPT",
+            "iptables -t filter -A NIPE_OUTPUT -p udp --dport 22 -j ACCEPT",
+        ] {
+            assert!(position(&filter, rule) < udp);
+        }
+
+        let ipv6 = lines(&fw.ipv6_rules());
+        let drop = position(&ipv6, "ip6tables -t filter -A NIPE_OUTPUT -j DROP");
+        assert!(
+            position(
+                &ipv6,
+                "ip6tables -t filter -A NIPE_OUTPUT -d 2001:db8::/32 -j ACCEPT"
+            ) < drop
+        );
+        assert!(
+            position(
+                &ipv6,
+                "ip6tables -t filter -A NIPE_OUTPUT -p udp --dport 22 -j ACCEPT"
+            ) < drop
+        );
+        // IPv6 networks never reach the IPv4 tools
+        assert!(!nat
+            .iter()
+            .chain(&filter)
+            .any(|r| r.contains("2001:db8::/32")));
+    }
+
+    #[test]
+    fn ipv6_rules_follow_block_ipv6_and_ip6tables() {
+        let rules =
+            lines(&firewall(Backend::Iptables, |c| c.firewall.block_ipv6 = true).ipv6_rules());
+        let lo = position(&rules, "ip6tables -t filter -A NIPE_OUTPUT -o lo -j ACCEPT");
+        let drop = position(&rules, "ip6tables -t filter -A NIPE_OUTPUT -j DROP");
+        assert!(lo < drop);
+
+        let off = firewall(Backend::Iptables, |c| c.firewall.block_ipv6 = false);
+        assert!(off.ipv6_rules().is_empty());
+
+        let missing = LinuxFirewall {
+            has_ip6tables: false,
+            ..firewall(Backend::Iptables, |c| c.firewall.block_ipv6 = true)
+        };
+        assert!(missing.ipv6_rules().is_empty());
+    }
+
+    #[test]
+    fn nft_rules_match_the_iptables_ones() {
+        let fw = firewall(Backend::Nftables, |c| {
+            c.firewall.allow_lan = true;
+            c.firewall.block_ipv6 = true;
+            c.firewall.bypass_ports = vec![22];
+            c.firewall.bypass_cidrs =
+                vec!["203.0.113.0/24".to_string(), "2001:db8::/32".to_string()];
+        });
+        let rules = lines(&fw.kill_switch_commands());
+        assert_eq!(rules, lines(&fw.nft_kill_switch_commands()));
+
+        let lo = position(&rules, "nft add rule ip nipe nat_output oifname lo return");
+        let local = position(
+            &rules,
+            "nft add rule ip nipe nat_output ip daddr 127.0.0.0/8 return",
+        );
+        let dns = position(
+            &rules,
+            "nft add rule ip nipe nat_output udp dport 53 redirect to :9061",
+        );
+        let lan = position(
+            &rules,
+            "nft add rule ip nipe nat_output ip daddr 10.0.0.0/8 return",
+        );
+        let bypass = position(
+            &rules,
+            "nft add rule ip nipe nat_output tcp dport 22 return",
+        );
+        let tcp = position(
+            &rules,
+            "nft add rule ip nipe nat_output meta l4proto tcp redirect to :9040",
+        );
+        assert!(lo < dns && local < dns && dns < lan && lan < tcp && bypass < tcp);
+
+        let reject = position(
+            &rules,
+            "nft add rule ip nipe filter_output meta l4proto udp reject",
+        );
+        for rule in [
+            "nft add rule ip nipe filter_output meta skuid 108 accept",
+            "nft add rule ip nipe filter_output oifname lo accept",
+            "nft add rule ip nipe filter_output ip daddr 203.0.113.0/24 accept",
+            "nft add rule ip nipe filter_output udp dport 22 accept",
+        ] {
+            assert!(position(&rules, rule) < reject);
+        }
+
+        let drop = position(&rules, "nft add rule ip6 nipe filter_output drop");
+        assert!(
+            position(
+                &rules,
+                "nft add rule ip6 nipe filter_output ip6 daddr 2001:db8::/32 accept"
+            ) < drop
+        );
+
+        let no_ipv6 = firewall(Backend::Nftables, |c| c.firewall.block_ipv6 = false);
+        assert!(!lines(&no_ipv6.kill_switch_commands())
+            .iter()
+            .any(|r| r.contains(" ip6 ")));
     }
 }
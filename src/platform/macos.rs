@@ -1,64 +1,88 @@
+use crate::config::NipeConfig;
 use crate::error::{NipeError, Result};
-use crate::platform::FirewallProvider;
+use crate::platform::{CommandSpec, FirewallProvider};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 use std::process::Command;
 use tracing::{info, warn};
 
+/// Where the kill switch ruleset is written for `pfctl -f`.
+const PF_RULES_PATH: &str = "/tmp/nipe_pf.conf";
+
 pub struct MacOSFirewall {
     interface: String,
-    service: Option<String>,
+    block_ipv6: bool,
+    bypass_ports: Vec<u16>,
+    bypass_cidrs: Vec<String>,
+    /// Proxy settings from before `start`, restored by `disable_socks_proxy`
+    saved_proxies_path: PathBuf,
+}
+
+/// One `networksetup` proxy setting as it was before Nipe changed it.
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedProxy {
+    service: String,
+    /// `networksetup` name of the proxy, e.g. "socksfirewallproxy"
+    kind: String,
+    enabled: bool,
+    server: String,
+    port: u16,
 }
 
 impl FirewallProvider for MacOSFirewall {
-    fn new() -> Result<Self> {
+    fn new(config: &NipeConfig) -> Result<Self> {
         let interface = Self::detect_interface()?;
-        let service = Self::detect_service(&interface).ok();
 
-        Ok(Self { interface, service })
+        Ok(Self {
+            interface,
+            block_ipv6: config.firewall.block_ipv6,
+            bypass_ports: config.firewall.bypass_ports.clone(),
+            bypass_cidrs: config.firewall.bypass_cidrs.clone(),
+            saved_proxies_path: config.tor.work_dir().join("macos-proxies.json"),
+        })
     }
 
     fn enable_kill_switch(&self) -> Result<()> {
         info!("Enabling macOS kill switch with PF");
 
-        let pf_rules = format!(
-            r#"
-# Nipe Kill Switch Rules
-ext_if = "{}"
-tor_user = "root"
-
-# Options
-set block-policy drop
-set skip on lo0
-
-# Allow DNS for Tor bootstrap
-pass out quick on $ext_if proto udp to any port 53 keep state
-
-# Allow all TCP traffic from Tor (running as root)
-pass out quick on $ext_if proto tcp user $tor_user keep state
-
-# Block IPv6 entirely (prevent leaks)
-block drop quick inet6 all
-
-# Block everything else
-block drop out quick on $ext_if all
-"#,
-            self.interface
-        );
-
-        let rules_path = "/tmp/nipe_pf.conf";
-        std::fs::write(rules_path, pf_rules)?;
+        std::fs::write(PF_RULES_PATH, self.pf_rules())?;
 
         // Enable PF with rules
-        let output = Command::new("pfctl")
-            .args(["-ef", rules_path])
-            .stdin(std::process::Stdio::null())
+        let output = Self::enable_pf_command()
             .output()
             .map_err(|e| NipeError::FirewallError(format!("Failed to enable PF: {}", e)))?;
 
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
         if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
+            // `-e` fails when PF is already on even though `-f` loaded the
+            // rules; anything else (e.g. a syntax error) means they were rejected.
+            let errors: Vec<&str> = stderr
+                .lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty() && !Self::is_benign_pfctl_message(l))
+                .collect();
+            if !errors.is_empty() {
+                return Err(NipeError::FirewallError(format!(
+                    "PF rejected the kill switch rules: {}",
+                    errors.join("; ")
+                )));
+            }
             warn!("PF warning: {}", stderr);
         }
 
+        // pfctl can exit cleanly without loading anything (SIP, MDM profiles,
+        // locked pf.conf), so confirm the rules are actually active.
+        if !self.rules_loaded()? {
+            return Err(NipeError::FirewallError(format!(
+                "PF did not load the kill switch rules; traffic is NOT being blocked. pfctl said: {}",
+                if stderr.is_empty() {
+                    "(no output)"
+                } else {
+                    stderr.as_str()
+                }
+            )));
+        }
+
         info!("Kill switch enabled");
         Ok(())
     }
@@ -66,8 +90,7 @@ block drop out quick on $ext_if all
     fn disable_kill_switch(&self) -> Result<()> {
         info!("Disabling macOS kill switch");
 
-        let output = Command::new("pfctl")
-            .arg("-d")
+        let output = Self::disable_pf_command()
             .output()
             .map_err(|e| NipeError::FirewallError(format!("Failed to disable PF: {}", e)))?;
 
@@ -76,7 +99,7 @@ block drop out quick on $ext_if all
         }
 
         // Clean up rules file
-        let _ = std::fs::remove_file("/tmp/nipe_pf.conf");
+        let _ = std::fs::remove_file(PF_RULES_PATH);
 
         info!("Kill switch disabled");
         Ok(())
@@ -85,60 +108,319 @@ block drop out quick on $ext_if all
     fn enable_socks_proxy(&self, port: u16) -> Result<()> {
         info!("Enabling system SOCKS proxy on port {}", port);
 
-        let default_service = "Wi-Fi".to_string();
-        let service = self.service.as_ref().unwrap_or(&default_service);
-
-        // Set SOCKS proxy
-        let status = Command::new("networksetup")
-            .args([
-                "-setsocksfirewallproxy",
-                service,
-                "127.0.0.1",
-                &port.to_string(),
-            ])
-            .status()
-            .map_err(|e| NipeError::FirewallError(e.to_string()))?;
-
-        if !status.success() {
-            return Err(NipeError::FirewallError(
-                "Failed to set SOCKS proxy".to_string(),
-            ));
+        // The active service can change after `new` (e.g. Wi-Fi to Ethernet)
+        for service in &Self::target_services() {
+            self.save_proxy(service, "socksfirewallproxy");
+            Self::set_proxy(service, "socksfirewallproxy", port)?;
+            info!("System SOCKS proxy enabled on {}", service);
         }
+        Ok(())
+    }
 
-        // Enable it
-        let status = Command::new("networksetup")
-            .args(["-setsocksfirewallproxystate", service, "on"])
-            .status()?;
+    fn enable_web_proxy(&self, port: u16) -> Result<()> {
+        info!("Enabling system HTTPS web proxy on port {}", port);
 
-        if !status.success() {
-            return Err(NipeError::FirewallError(
-                "Failed to enable SOCKS proxy".to_string(),
-            ));
+        for service in &Self::target_services() {
+            self.save_proxy(service, "securewebproxy");
+            // Tor's HTTPTunnelPort only speaks CONNECT, so only the secure web
+            // proxy can point at it; plain HTTP from such apps hits the kill switch.
+            Self::set_proxy(service, "securewebproxy", port)?;
+            info!("System HTTPS web proxy enabled on {}", service);
         }
-
-        info!("System SOCKS proxy enabled on {}", service);
         Ok(())
     }
 
     fn disable_socks_proxy(&self) -> Result<()> {
         info!("Disabling system SOCKS proxy");
 
-        let default_service = "Wi-Fi".to_string();
-        let service = self.service.as_ref().unwrap_or(&default_service);
+        // Every service `start` touched, plus the current one in case the
+        // saved settings are missing
+        let mut services: Vec<String> = self
+            .read_saved_proxies()
+            .into_iter()
+            .map(|p| p.service)
+            .collect();
+        services.extend(Self::target_services());
+        services.sort();
+        services.dedup();
+
+        // The HTTPS web proxy too, in case it was enabled; failures only
+        // mean there was nothing to turn off
+        for service in &services {
+            for kind in ["socksfirewallproxy", "securewebproxy"] {
+                let _ = Self::proxy_state_command(service, kind, false).output();
+            }
+        }
 
-        // Disable SOCKS proxy
-        let _ = Command::new("networksetup")
-            .args(["-setsocksfirewallproxystate", service, "off"])
-            .status();
+        // Put back whatever proxy the user had configured before
+        self.restore_proxies();
 
         self.disable_kill_switch()?;
 
         info!("System SOCKS proxy disabled");
         Ok(())
     }
+
+    fn backend(&self) -> &'static str {
+        "pf"
+    }
+
+    fn describe_enable(&self, socks_port: u16, web_proxy_port: Option<u16>) -> Vec<String> {
+        let mut lines = vec![format!("# {}:", PF_RULES_PATH)];
+        lines.extend(
+            self.pf_rules()
+                .lines()
+                .filter(|l| !l.is_empty())
+                .map(|l| format!("#   {}", l)),
+        );
+        lines.push(Self::enable_pf_command().to_string());
+
+        for service in &Self::target_services() {
+            let mut commands = Self::proxy_commands(service, "socksfirewallproxy", socks_port);
+            if let Some(port) = web_proxy_port {
+                commands.extend(Self::proxy_commands(service, "securewebproxy", port));
+            }
+            lines.extend(commands.iter().map(ToString::to_string));
+        }
+        lines
+    }
 }
 
 impl MacOSFirewall {
+    /// The kill switch ruleset written to [`PF_RULES_PATH`].
+    fn pf_rules(&self) -> String {
+        let ipv6_rules = if self.block_ipv6 {
+            "# Block IPv6 entirely (prevent leaks)\nblock drop quick inet6 all\n"
+        } else {
+            ""
+        };
+
+        let mut bypass_rules = String::new();
+        if !self.bypass_cidrs.is_empty() || !self.bypass_ports.is_empty() {
+            bypass_rules.push_str("# Destinations allowed to bypass Tor\n");
+        }
+        for cidr in &self.bypass_cidrs {
+            bypass_rules.push_str(&format!(
+                "pass out quick on $ext_if to {} keep state\n",
+                cidr
+            ));
+        }
+        for port in &self.bypass_ports {
+            bypass_rules.push_str(&format!(
+                "pass out quick on $ext_if proto {{ tcp udp }} to any port {} keep state\n",
+                port
+            ));
+        }
+
+        format!(
+            r#"
+# Nipe Kill Switch Rules
+ext_if = "{}"
+tor_user = "root"
+
+# Options
+set block-policy drop
+set skip on lo0
+
+# Allow DNS for Tor bootstrap
+pass out quick on $ext_if proto udp to any port 53 keep state
+
+# Allow all TCP traffic from Tor (running as root)
+pass out quick on $ext_if proto tcp user $tor_user keep state
+
+{}{}
+# Block everything else
+block drop out quick on $ext_if all
+"#,
+            self.interface, bypass_rules, ipv6_rules
+        )
+    }
+
+    /// Load [`PF_RULES_PATH`] and enable PF.
+    fn enable_pf_command() -> CommandSpec {
+        CommandSpec::new("pfctl", ["-ef", PF_RULES_PATH])
+    }
+
+    fn disable_pf_command() -> CommandSpec {
+        CommandSpec::new("pfctl", ["-d"])
+    }
+
+    /// Point `service`'s `kind` proxy ("socksfirewallproxy" or
+    /// "securewebproxy") at 127.0.0.1:`port` and turn it on.
+    fn proxy_commands(service: &str, kind: &str, port: u16) -> Vec<CommandSpec> {
+        vec![
+            CommandSpec::new(
+                "networksetup",
+                [
+                    format!("-set{}", kind),
+                    service.to_string(),
+                    "127.0.0.1".to_string(),
+                    port.to_string(),
+                ],
+            ),
+            Self::proxy_state_command(service, kind, true),
+        ]
+    }
+
+    /// Turn `service`'s `kind` proxy on or off.
+    fn proxy_state_command(service: &str, kind: &str, on: bool) -> CommandSpec {
+        CommandSpec::new(
+            "networksetup",
+            [
+                format!("-set{}state", kind),
+                service.to_string(),
+                if on { "on" } else { "off" }.to_string(),
+            ],
+        )
+    }
+
+    /// Run the commands from [`Self::proxy_commands`], failing on the first
+    /// that doesn't succeed.
+    fn set_proxy(service: &str, kind: &str, port: u16) -> Result<()> {
+        for command in Self::proxy_commands(service, kind, port) {
+            let output = command
+                .output()
+                .map_err(|e| NipeError::FirewallError(e.to_string()))?;
+            if !output.status.success() {
+                return Err(NipeError::FirewallError(format!(
+                    "Failed to set the {} on {}: `{}` failed",
+                    kind, service, command
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// pfctl chatter that doesn't mean the ruleset failed to load.
+    fn is_benign_pfctl_message(line: &str) -> bool {
+        const BENIGN: [&str; 5] = [
+            "pf already enabled",
+            "pf enabled",
+            "No ALTQ support in kernel",
+            "ALTQ related functions disabled",
+            "flushing of rules present in the main ruleset",
+        ];
+        line.starts_with("Use of -f option") || BENIGN.iter().any(|benign| line.contains(benign))
+    }
+
+    /// Re-read the active ruleset and check that PF is enabled with our block rule.
+    fn rules_loaded(&self) -> Result<bool> {
+        let info = Command::new("pfctl")
+            .arg("-si")
+            .output()
+            .map_err(|e| NipeError::FirewallError(format!("Failed to query PF: {}", e)))?;
+        let enabled = String::from_utf8_lossy(&info.stdout)
+            .lines()
+            .chain(String::from_utf8_lossy(&info.stderr).lines())
+            .any(|l| l.trim_start().starts_with("Status: Enabled"));
+
+        let rules = Command::new("pfctl")
+            .arg("-sr")
+            .output()
+            .map_err(|e| NipeError::FirewallError(format!("Failed to read PF rules: {}", e)))?;
+        let block_rule = format!("block drop out quick on {} all", self.interface);
+        let loaded = String::from_utf8_lossy(&rules.stdout)
+            .lines()
+            .any(|l| l.trim() == block_rule);
+
+        Ok(enabled && loaded)
+    }
+
+    fn read_saved_proxies(&self) -> Vec<SavedProxy> {
+        std::fs::read_to_string(&self.saved_proxies_path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    /// Record the current `kind` proxy setting of `service` before Nipe
+    /// overwrites it. A setting saved by an earlier `start` is kept, since the
+    /// current one may already be Nipe's.
+    fn save_proxy(&self, service: &str, kind: &str) {
+        let mut saved = self.read_saved_proxies();
+        if saved.iter().any(|p| p.service == service && p.kind == kind) {
+            return;
+        }
+
+        let output = match Command::new("networksetup")
+            .arg(format!("-get{}", kind))
+            .arg(service)
+            .output()
+        {
+            Ok(output) if output.status.success() => output,
+            _ => {
+                warn!("Could not read the current {} of {}", kind, service);
+                return;
+            }
+        };
+
+        // Enabled: Yes / Server: proxy.corp / Port: 1080
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let field = |name: &str| {
+            stdout
+                .lines()
+                .find_map(|l| l.strip_prefix(name))
+                .map(|v| v.trim().to_string())
+                .unwrap_or_default()
+        };
+        saved.push(SavedProxy {
+            service: service.to_string(),
+            kind: kind.to_string(),
+            enabled: field("Enabled:") == "Yes",
+            server: field("Server:"),
+            port: field("Port:").parse().unwrap_or(0),
+        });
+
+        match serde_json::to_string_pretty(&saved) {
+            Ok(content) => {
+                if let Err(e) = std::fs::write(&self.saved_proxies_path, content) {
+                    warn!("Could not save the previous proxy settings: {}", e);
+                }
+            }
+            Err(e) => warn!("Could not save the previous proxy settings: {}", e),
+        }
+    }
+
+    /// Reapply the proxy settings saved by `save_proxy` and forget them.
+    fn restore_proxies(&self) {
+        for proxy in self.read_saved_proxies() {
+            if !proxy.server.is_empty() {
+                info!(
+                    "Restoring {} {}:{} on {}",
+                    proxy.kind, proxy.server, proxy.port, proxy.service
+                );
+            }
+            for command in Self::restore_commands(&proxy) {
+                let _ = command.output();
+            }
+        }
+
+        let _ = std::fs::remove_file(&self.saved_proxies_path);
+    }
+
+    /// Reapply one saved setting: its server, when there was one, then
+    /// whether it was on.
+    fn restore_commands(proxy: &SavedProxy) -> Vec<CommandSpec> {
+        let mut commands = Vec::new();
+        if !proxy.server.is_empty() {
+            commands.push(CommandSpec::new(
+                "networksetup",
+                [
+                    format!("-set{}", proxy.kind),
+                    proxy.service.clone(),
+                    proxy.server.clone(),
+                    proxy.port.to_string(),
+                ],
+            ));
+        }
+        commands.push(Self::proxy_state_command(
+            &proxy.service,
+            &proxy.kind,
+            proxy.enabled,
+        ));
+        commands
+    }
+
     fn detect_interface() -> Result<String> {
         let output = Command::new("route")
             .args(["get", "default"])
@@ -159,26 +441,126 @@ impl MacOSFirewall {
         Err(NipeError::InterfaceNotFound)
     }
 
-    fn detect_service(interface: &str) -> Result<String> {
+    /// Network services to put the proxy on: the one behind the current
+    /// default route, or every enabled service if that can't be determined.
+    fn target_services() -> Vec<String> {
+        let service = Self::detect_interface()
+            .ok()
+            .and_then(|interface| Self::detect_service(&interface));
+        match service {
+            Some(service) => vec![service],
+            None => {
+                let all = Self::all_services();
+                warn!(
+                    "Could not tell which network service is active, configuring all: {}",
+                    all.join(", ")
+                );
+                all
+            }
+        }
+    }
+
+    /// Map a device such as "en5" to its network service name using
+    /// `networksetup -listnetworkserviceorder`, whose entries look like:
+    ///
+    /// ```text
+    /// (2) Thunderbolt Ethernet Slot 1
+    /// (Hardware Port: Thunderbolt Ethernet Slot 1, Device: en5)
+    /// ```
+    fn detect_service(interface: &str) -> Option<String> {
         let output = Command::new("networksetup")
-            .arg("-listallhardwareports")
-            .output()?;
+            .arg("-listnetworkserviceorder")
+            .output()
+            .ok()?;
 
         let output_str = String::from_utf8_lossy(&output.stdout);
         let lines: Vec<&str> = output_str.lines().collect();
+        let device = format!("Device: {})", interface);
 
         for (i, line) in lines.iter().enumerate() {
-            if line.contains(interface) && i > 0 {
-                let service_line = lines[i - 1];
-                if let Some(service) = service_line.split(':').nth(1) {
-                    let service = service.trim().to_string();
-                    info!("Detected network service: {}", service);
-                    return Ok(service);
-                }
+            if line.contains(&device) && i > 0 {
+                // "(2) Thunderbolt Ethernet Slot 1", or "(*) ..." when disabled
+                let service = lines[i - 1].split_once(") ")?.1.trim().to_string();
+                info!("Detected network service: {}", service);
+                return Some(service);
             }
         }
 
-        info!("Could not detect service name, using default 'Wi-Fi'");
-        Ok("Wi-Fi".to_string())
+        None
+    }
+
+    /// Every enabled network service; disabled ones are marked with `*`.
+    fn all_services() -> Vec<String> {
+        let services: Vec<String> = Command::new("networksetup")
+            .arg("-listallnetworkservices")
+            .output()
+            .map(|output| {
+                String::from_utf8_lossy(&output.stdout)
+                    .lines()
+                    // The first line explains the asterisk
+                    .skip(1)
+                    .filter(|l| !l.trim().is_empty() && !l.starts_with('*'))
+                    .map(|l| l.trim().to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        if services.is_empty() {
+            vec!["Wi-Fi".to_string()]
+        } else {
+            services
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn firewall(
+        block_ipv6: bool,
+        bypass_ports: Vec<u16>,
+        bypass_cidrs: Vec<&str>,
+    ) -> MacOSFirewall {
+        MacOSFirewall {
+            interface: "en0".to_string(),
+            block_ipv6,
+            bypass_ports,
+            bypass_cidrs: bypass_cidrs.into_iter().map(String::from).collect(),
+            saved_proxies_path: PathBuf::from("/tmp/macos-proxies.json"),
+        }
+    }
+
+    #[test]
+    fn pf_rules_let_tor_out_and_block_the_rest() {
+        let rules = firewall(false, vec![], vec![]).pf_rules();
+        assert!(rules.contains("ext_if = \"en0\""));
+        assert!(rules.contains("set skip on lo0"));
+        assert!(rules.contains("pass out quick on $ext_if proto tcp user $tor_user keep state"));
+        assert!(rules
+            .trim_end()
+            .ends_with("block drop out quick on $ext_if all"));
+        assert!(!rules.contains("inet6"));
+        assert!(!rules.contains("bypass"));
+    }
+
+    #[test]
+    fn pf_rules_block_ipv6_when_asked() {
+        let rules = firewall(true, vec![], vec![]).pf_rules();
+        let ipv6 = rules.find("block drop quick inet6 all").unwrap();
+        assert!(ipv6 < rules.find("block drop out quick on $ext_if all").unwrap());
+    }
+
+    #[test]
+    fn pf_rules_pass_bypass_destinations_before_blocking() {
+        let rules = firewall(false, vec![22, 443], vec!["203.0.113.0/24"]).pf_rules();
+        let block = rules.find("block drop out quick on $ext_if all").unwrap();
+        for rule in [
+            "pass out quick on $ext_if to 203.0.113.0/24 keep state",
+            "pass out quick on $ext_if proto { tcp udp } to any port 22 keep state",
+            "pass out quick on $ext_if proto { tcp udp } to any port 443 keep state",
+        ] {
+            assert!(rules.find(rule).unwrap() < block, "`{}` missing", rule);
+        }
     }
 }
@@ -18,14 +18,140 @@ pub use linux::LinuxFirewall as Firewall;
 #[cfg(target_os = "windows")]
 pub use windows::WindowsFirewall as Firewall;
 
+use crate::config::NipeConfig;
 use crate::error::Result;
+use std::fmt;
+use std::path::Path;
+use std::process::{Command, Output};
 
 pub trait FirewallProvider {
-    fn new() -> Result<Self>
+    fn new(config: &NipeConfig) -> Result<Self>
     where
         Self: Sized;
     fn enable_kill_switch(&self) -> Result<()>;
     fn disable_kill_switch(&self) -> Result<()>;
     fn enable_socks_proxy(&self, port: u16) -> Result<()>;
     fn disable_socks_proxy(&self) -> Result<()>;
+
+    /// Name of the firewall tool the kill switch is built on, e.g. "nftables".
+    fn backend(&self) -> &'static str;
+
+    /// Whether that tool is installed and runs.
+    fn backend_available(&self) -> bool {
+        true
+    }
+
+    /// Point the system HTTPS web proxy at Tor's HTTP CONNECT port. Only
+    /// platforms with a system web proxy setting implement this.
+    fn enable_web_proxy(&self, _port: u16) -> Result<()> {
+        Ok(())
+    }
+
+    /// What `enable_kill_switch`, `enable_socks_proxy` and (with a port)
+    /// `enable_web_proxy` would run, one line per command, without running
+    /// anything. Files they write are shown as `#` comment lines.
+    fn describe_enable(&self, socks_port: u16, web_proxy_port: Option<u16>) -> Vec<String>;
+}
+
+/// An external command kept as data, so a provider can show it
+/// (`start --dry-run`) as well as run it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandSpec {
+    pub program: String,
+    pub args: Vec<String>,
+}
+
+impl CommandSpec {
+    pub fn new<I, S>(program: &str, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            program: program.to_string(),
+            args: args.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// Run the command and collect its output, whatever the exit status.
+    pub fn output(&self) -> std::io::Result<Output> {
+        Command::new(&self.program).args(&self.args).output()
+    }
+}
+
+impl fmt::Display for CommandSpec {
+    /// Shell-style, quoting arguments that contain spaces or braces.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.program)?;
+        for arg in &self.args {
+            if arg.is_empty() || arg.contains([' ', '{', '}', ';', '"', '\'']) {
+                write!(f, " '{}'", arg.replace('\'', r"'\''"))?;
+            } else {
+                write!(f, " {}", arg)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Create `dir` (its parent must exist) as a directory only we can use:
+/// owned by the effective user with mode 0700. Fails when something else
+/// already sits there, such as a symlink or a directory planted by the
+/// Tor user.
+#[cfg(unix)]
+pub fn private_dir(dir: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+
+    match std::fs::DirBuilder::new().mode(0o700).create(dir) {
+        Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => return Err(e),
+        _ => {}
+    }
+
+    let metadata = std::fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() || metadata.uid() != unsafe { libc::geteuid() } {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("{} is not a directory owned by us", dir.display()),
+        ));
+    }
+    if metadata.mode() & 0o077 != 0 {
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(())
+}
+
+/// Whether `path` is a regular file owned by the effective user, i.e. not
+/// a symlink or a file another user planted.
+#[cfg(unix)]
+pub fn owned_by_us(path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    std::fs::symlink_metadata(path)
+        .is_ok_and(|m| m.is_file() && m.uid() == unsafe { libc::geteuid() })
+}
+
+/// Find the unprivileged account Tor should run as, trying the usual
+/// distro user names. Returns `(uid, gid)`.
+pub fn find_tor_user() -> Option<(u32, u32)> {
+    // Debian/Ubuntu, then Arch/Fedora, then a generic fallback
+    let users = ["debian-tor", "tor", "nobody"];
+
+    users.into_iter().find_map(|user| {
+        let uid = id_of(user, "-u")?;
+        let gid = id_of(user, "-g")?;
+        Some((uid, gid))
+    })
+}
+
+fn id_of(user: &str, flag: &str) -> Option<u32> {
+    let output = std::process::Command::new("id")
+        .arg(flag)
+        .arg(user)
+        .output()
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8_lossy(&output.stdout).trim().parse().ok()
 }
@@ -1,82 +1,390 @@
 // src/platform/windows.rs
 
-use crate::error::Result;
+use crate::config::NipeConfig;
+use crate::error::{NipeError, Result};
+use crate::platform::CommandSpec;
 use std::process::Command;
+use tracing::{info, warn};
 
 /// Windows implementation of the firewall and proxy handling for Nipe.
-/// It uses `netsh advfirewall` to create a kill‑switch rule and
-/// `netsh winhttp` to configure the system proxy.
-pub struct WindowsFirewall;
+/// It uses `netsh advfirewall` to block outbound traffic except Tor, and
+/// sets both the WinHTTP proxy (`netsh winhttp`, used by services) and the
+/// per-user WinINET proxy (used by browsers).
+pub struct WindowsFirewall {
+    /// Programs allowed out while the kill switch is on: Tor, and the
+    /// pluggable transport when bridges are used
+    allowed_programs: Vec<String>,
+    bypass_ports: Vec<u16>,
+    bypass_cidrs: Vec<String>,
+}
+
+/// Prefix shared by every rule Nipe adds, so they can be removed together.
+const RULE_NAME: &str = "Nipe Kill Switch";
+
+/// Per-user WinINET proxy settings, read by Edge, Chrome and most GUI apps.
+const INTERNET_SETTINGS: &str = r"HKCU\Software\Microsoft\Windows\CurrentVersion\Internet Settings";
 
 impl WindowsFirewall {
-    fn run_netsh(args: &[&str]) -> Result<()> {
-        let status = Command::new("netsh")
-            .args(args)
-            .status()
-            .map_err(|e| crate::error::NipeError::CommandError(e.to_string()))?;
-        if !status.success() {
-            Err(crate::error::NipeError::CommandError(format!(
-                "netsh command failed: {:?}",
-                args
-            )))
-        } else {
-            Ok(())
+    /// Run `command`, failing if it can't start or exits unsuccessfully.
+    fn run(command: &CommandSpec) -> Result<()> {
+        let output = command
+            .output()
+            .map_err(|e| NipeError::FirewallError(e.to_string()))?;
+        if !output.status.success() {
+            return Err(NipeError::FirewallError(format!("`{}` failed", command)));
         }
+        Ok(())
     }
-}
 
-impl crate::platform::FirewallProvider for WindowsFirewall {
-    fn new() -> Result<Self>
-    where
-        Self: Sized,
-    {
-        // No special initialization needed on Windows.
-        Ok(WindowsFirewall)
+    fn netsh(args: &[&str]) -> CommandSpec {
+        CommandSpec::new("netsh", args.iter().copied())
     }
 
-    fn enable_kill_switch(&self) -> Result<()> {
-        // Create a rule that blocks all outbound traffic except Tor (port 9050/9051) and DNS.
-        // First, delete any existing rule with the same name to avoid duplicates.
-        let _ = Self::run_netsh(&[
-            "advfirewall",
-            "firewall",
-            "delete",
-            "rule",
-            "name=Nipe Kill Switch",
-        ]);
-        // Block all outbound traffic.
-        Self::run_netsh(&[
+    /// Full path of tor.exe: the configured binary, else the first `tor`
+    /// on PATH, else the Tor Expert Bundle's default location.
+    fn find_tor_exe(config: &NipeConfig) -> String {
+        if let Some(path) = &config.tor.tor_binary {
+            return path.display().to_string();
+        }
+
+        Command::new("where")
+            .arg("tor")
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| {
+                String::from_utf8_lossy(&output.stdout)
+                    .lines()
+                    .next()
+                    .map(|l| l.trim().to_string())
+            })
+            .unwrap_or_else(|| r"C:\Program Files\Tor\tor.exe".to_string())
+    }
+
+    /// Write one value under [`INTERNET_SETTINGS`] with `reg add`.
+    fn internet_setting_command(name: &str, kind: &str, data: &str) -> CommandSpec {
+        CommandSpec::new(
+            "reg",
+            [
+                "add",
+                INTERNET_SETTINGS,
+                "/v",
+                name,
+                "/t",
+                kind,
+                "/d",
+                data,
+                "/f",
+            ],
+        )
+    }
+
+    /// Tell running WinINET clients to reload the proxy settings; without
+    /// this, browsers keep the old proxy until they restart.
+    fn refresh_internet_settings() {
+        use windows_sys::Win32::Networking::WinInet::{
+            InternetSetOptionW, INTERNET_OPTION_REFRESH, INTERNET_OPTION_SETTINGS_CHANGED,
+        };
+
+        unsafe {
+            InternetSetOptionW(
+                std::ptr::null(),
+                INTERNET_OPTION_SETTINGS_CHANGED,
+                std::ptr::null(),
+                0,
+            );
+            InternetSetOptionW(
+                std::ptr::null(),
+                INTERNET_OPTION_REFRESH,
+                std::ptr::null(),
+                0,
+            );
+        }
+    }
+
+    /// Remove every rule Nipe may have added. netsh fails when no rule
+    /// matches, which is fine here.
+    fn delete_rules() {
+        for command in Self::delete_rule_commands() {
+            let _ = command.output();
+        }
+    }
+
+    fn delete_rule_commands() -> Vec<CommandSpec> {
+        [
+            RULE_NAME.to_string(),
+            format!("{} (allow loopback)", RULE_NAME),
+            format!("{} (allow program)", RULE_NAME),
+            format!("{} (bypass)", RULE_NAME),
+        ]
+        .iter()
+        .map(|name| {
+            Self::netsh(&[
+                "advfirewall",
+                "firewall",
+                "delete",
+                "rule",
+                &format!("name={}", name),
+            ])
+        })
+        .collect()
+    }
+
+    /// Allow rules for loopback and the programs in `allowed_programs`,
+    /// then the default-block policy.
+    fn kill_switch_commands(&self) -> Vec<CommandSpec> {
+        // A block rule in Windows Firewall overrides every allow rule, so the
+        // kill switch is a default-block outbound policy with allow rules for
+        // loopback (apps talking to Tor's SOCKS port) and Tor itself.
+        let mut commands = vec![Self::netsh(&[
             "advfirewall",
             "firewall",
             "add",
             "rule",
-            "name=Nipe Kill Switch",
+            &format!("name={} (allow loopback)", RULE_NAME),
             "dir=out",
-            "action=block",
+            "action=allow",
+            "remoteip=127.0.0.1,::1",
             "enable=yes",
             "profile=any",
-        ])
+        ])];
+
+        for program in &self.allowed_programs {
+            commands.push(Self::netsh(&[
+                "advfirewall",
+                "firewall",
+                "add",
+                "rule",
+                &format!("name={} (allow program)", RULE_NAME),
+                "dir=out",
+                "action=allow",
+                &format!("program={}", program),
+                "enable=yes",
+                "profile=any",
+            ]));
+        }
+
+        // Destinations configured to bypass Tor
+        if !self.bypass_cidrs.is_empty() {
+            commands.push(Self::netsh(&[
+                "advfirewall",
+                "firewall",
+                "add",
+                "rule",
+                &format!("name={} (bypass)", RULE_NAME),
+                "dir=out",
+                "action=allow",
+                &format!("remoteip={}", self.bypass_cidrs.join(",")),
+                "enable=yes",
+                "profile=any",
+            ]));
+        }
+        if !self.bypass_ports.is_empty() {
+            let ports: Vec<String> = self.bypass_ports.iter().map(ToString::to_string).collect();
+            for protocol in ["TCP", "UDP"] {
+                commands.push(Self::netsh(&[
+                    "advfirewall",
+                    "firewall",
+                    "add",
+                    "rule",
+                    &format!("name={} (bypass)", RULE_NAME),
+                    "dir=out",
+                    "action=allow",
+                    &format!("protocol={}", protocol),
+                    &format!("remoteport={}", ports.join(",")),
+                    "enable=yes",
+                    "profile=any",
+                ]));
+            }
+        }
+
+        // Block everything else.
+        commands.push(Self::netsh(&[
+            "advfirewall",
+            "set",
+            "allprofiles",
+            "firewallpolicy",
+            "blockinbound,blockoutbound",
+        ]));
+        commands
     }
 
-    fn disable_kill_switch(&self) -> Result<()> {
-        // Remove the kill‑switch rule.
-        Self::run_netsh(&[
+    /// Windows' default policy: inbound blocked, outbound allowed.
+    fn default_policy_command() -> CommandSpec {
+        Self::netsh(&[
             "advfirewall",
-            "firewall",
-            "delete",
-            "rule",
-            "name=Nipe Kill Switch",
+            "set",
+            "allprofiles",
+            "firewallpolicy",
+            "blockinbound,allowoutbound",
         ])
     }
 
-    fn enable_socks_proxy(&self, port: u16) -> Result<()> {
-        // Set system proxy for WinHTTP (used by many apps). Tor listens on 127.0.0.1.
+    /// Clear the WinHTTP proxy and turn the WinINET one off.
+    fn reset_proxy_commands() -> Vec<CommandSpec> {
+        vec![
+            Self::netsh(&["winhttp", "reset", "proxy"]),
+            Self::internet_setting_command("ProxyEnable", "REG_DWORD", "0"),
+        ]
+    }
+
+    /// Point WinHTTP and the per-user WinINET settings at Tor's SOCKS port.
+    fn socks_proxy_commands(port: u16) -> Vec<CommandSpec> {
         let proxy = format!("127.0.0.1:{}", port);
-        Self::run_netsh(&["winhttp", "set", "proxy", &proxy])
+        vec![
+            Self::netsh(&["winhttp", "set", "proxy", &proxy]),
+            // Browsers ignore WinHTTP and use the WinINET settings instead
+            Self::internet_setting_command("ProxyServer", "REG_SZ", &format!("socks={}", proxy)),
+            Self::internet_setting_command("ProxyEnable", "REG_DWORD", "1"),
+        ]
+    }
+}
+
+impl crate::platform::FirewallProvider for WindowsFirewall {
+    fn new(config: &NipeConfig) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        let mut allowed_programs = vec![Self::find_tor_exe(config)];
+        if config.tor.use_bridges {
+            // With bridges it is the transport, not tor.exe, that connects out
+            allowed_programs.push(
+                config
+                    .tor
+                    .client_transport_plugin
+                    .clone()
+                    .unwrap_or_else(|| r"C:\Program Files\Tor\obfs4proxy.exe".to_string()),
+            );
+        }
+
+        Ok(WindowsFirewall {
+            allowed_programs,
+            bypass_ports: config.firewall.bypass_ports.clone(),
+            bypass_cidrs: config.firewall.bypass_cidrs.clone(),
+        })
+    }
+
+    fn enable_kill_switch(&self) -> Result<()> {
+        info!("Enabling Windows kill switch");
+        Self::delete_rules();
+
+        for program in &self.allowed_programs {
+            if !std::path::Path::new(program).is_file() {
+                warn!(
+                    "{} not found; the kill switch may block Tor's own connections",
+                    program
+                );
+            }
+        }
+        for command in self.kill_switch_commands() {
+            Self::run(&command)?;
+        }
+        Ok(())
+    }
+
+    fn disable_kill_switch(&self) -> Result<()> {
+        // Restore Windows' default policy and remove the kill‑switch rules.
+        info!("Disabling Windows kill switch");
+        Self::run(&Self::default_policy_command())?;
+        Self::delete_rules();
+        Ok(())
+    }
+
+    fn enable_socks_proxy(&self, port: u16) -> Result<()> {
+        for command in Self::socks_proxy_commands(port) {
+            Self::run(&command)?;
+        }
+        Self::refresh_internet_settings();
+        info!("System SOCKS proxy enabled");
+        Ok(())
     }
 
     fn disable_socks_proxy(&self) -> Result<()> {
         // Reset proxy configuration.
-        Self::run_netsh(&["winhttp", "reset", "proxy"])
+        for command in Self::reset_proxy_commands() {
+            Self::run(&command)?;
+        }
+        Self::refresh_internet_settings();
+        Ok(())
+    }
+
+    fn backend(&self) -> &'static str {
+        "Windows Firewall (netsh advfirewall)"
+    }
+
+    fn describe_enable(&self, socks_port: u16, _web_proxy_port: Option<u16>) -> Vec<String> {
+        let mut lines: Vec<String> = Self::delete_rule_commands()
+            .iter()
+            .chain(&self.kill_switch_commands())
+            .chain(&Self::socks_proxy_commands(socks_port))
+            .map(ToString::to_string)
+            .collect();
+        lines.push("# InternetSetOptionW(SETTINGS_CHANGED, REFRESH) to reload WinINET".to_string());
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn firewall(bypass_ports: Vec<u16>, bypass_cidrs: Vec<&str>) -> WindowsFirewall {
+        WindowsFirewall {
+            allowed_programs: vec![r"C:\Program Files\Tor\tor.exe".to_string()],
+            bypass_ports,
+            bypass_cidrs: bypass_cidrs.into_iter().map(String::from).collect(),
+        }
+    }
+
+    /// Whether `command` is a netsh call with every one of `args`.
+    fn has_args(command: &CommandSpec, args: &[&str]) -> bool {
+        command.program == "netsh" && args.iter().all(|arg| command.args.iter().any(|a| a == arg))
+    }
+
+    #[test]
+    fn kill_switch_allows_loopback_and_tor_then_blocks_outbound() {
+        let commands = firewall(vec![], vec![]).kill_switch_commands();
+        assert_eq!(commands.len(), 3);
+        assert!(has_args(
+            &commands[0],
+            &[
+                "name=Nipe Kill Switch (allow loopback)",
+                "action=allow",
+                "remoteip=127.0.0.1,::1"
+            ]
+        ));
+        assert!(has_args(
+            &commands[1],
+            &["action=allow", r"program=C:\Program Files\Tor\tor.exe"]
+        ));
+        assert!(has_args(
+            commands.last().unwrap(),
+            &[
+                "allprofiles",
+                "firewallpolicy",
+                "blockinbound,blockoutbound"
+            ]
+        ));
+    }
+
+    #[test]
+    fn kill_switch_allows_bypass_destinations_before_blocking() {
+        let commands =
+            firewall(vec![22, 443], vec!["203.0.113.0/24", "2001:db8::/32"]).kill_switch_commands();
+        let bypass: Vec<&CommandSpec> = commands
+            .iter()
+            .filter(|c| has_args(c, &["name=Nipe Kill Switch (bypass)", "action=allow"]))
+            .collect();
+        assert_eq!(bypass.len(), 3);
+        assert!(has_args(
+            bypass[0],
+            &["remoteip=203.0.113.0/24,2001:db8::/32"]
+        ));
+        assert!(has_args(bypass[1], &["protocol=TCP", "remoteport=22,443"]));
+        assert!(has_args(bypass[2], &["protocol=UDP", "remoteport=22,443"]));
+        assert!(has_args(
+            commands.last().unwrap(),
+            &["firewallpolicy", "blockinbound,blockoutbound"]
+        ));
     }
 }
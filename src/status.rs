@@ -1,32 +1,120 @@
+use crate::config::TorConfig;
+use crate::control::TorControl;
 use colored::Colorize;
 use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::debug;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ConnectionStatus {
     pub is_tor: bool,
     pub current_ip: String,
     pub exit_country: Option<String>,
+    /// Tor's accounting hibernation state ("soft" or "hard") when it is not awake
+    #[serde(default)]
+    pub hibernating: Option<String>,
+    /// Tor went dormant after a period without client activity
+    #[serde(default)]
+    pub dormant: bool,
+    /// Round-trip time of the check request, when it got a response
+    #[serde(default)]
+    pub latency_ms: Option<u64>,
+    /// Version of the installed Tor binary, when it could be run
+    #[serde(default)]
+    pub tor_version: Option<String>,
+    /// Public IP of a direct, non-Tor request; `None` when direct traffic is
+    /// blocked or routed through Tor, as it should be. Only `nipe status`
+    /// sets it, see [`check_real_ip`](Self::check_real_ip).
+    #[serde(default)]
+    pub real_ip: Option<String>,
+    /// Seconds since this status was checked, when it was read from the
+    /// cache instead of checked live
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cached_age_secs: Option<u64>,
 }
 
+/// The state file written by [`ConnectionStatus::save_cached`].
+#[derive(Serialize, Deserialize)]
+struct CachedStatus {
+    /// Unix time of the check, in seconds
+    checked_at: u64,
+    status: ConnectionStatus,
+}
+
+/// Tor Project's check API, which reports whether a request came through Tor.
+pub const TORPROJECT_CHECK_URL: &str = "https://check.torproject.org/api/ip";
+
 impl ConnectionStatus {
-    pub async fn check() -> anyhow::Result<Self> {
+    /// Check through Tor's SOCKS port, retrying up to `tor.check_attempts`
+    /// times with exponential backoff (1s, 2s, 4s, ...) while the request fails.
+    pub async fn check(tor: &TorConfig) -> anyhow::Result<Self> {
+        let proxy_url = format!("socks5h://127.0.0.1:{}", tor.socks_port);
         let client = reqwest::Client::builder()
-            .proxy(reqwest::Proxy::all("socks5h://127.0.0.1:9050")?)
-            .timeout(std::time::Duration::from_secs(30))
+            .proxy(reqwest::Proxy::all(&proxy_url)?)
+            .timeout(std::time::Duration::from_secs(tor.check_timeout_secs))
             .build()?;
 
-        match client
-            .get("https://check.torproject.org/api/ip")
-            .send()
+        let mut backoff = std::time::Duration::from_secs(1);
+        let mut attempt = 1;
+        loop {
+            let status = Self::check_with(client.clone(), &tor.check_url).await?;
+            if status.latency_ms.is_some() || attempt >= tor.check_attempts {
+                return Ok(status);
+            }
+            debug!(
+                "Status check attempt {} failed, retrying in {:?}",
+                attempt, backoff
+            );
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+
+    /// The real IP for [`real_ip`](Self::real_ip). A direct request that
+    /// reaches the check service outside Tor shows it; blocked or
+    /// transparently proxied ones reveal nothing.
+    ///
+    /// This sends a non-Tor request, so only call it when the user asked.
+    pub async fn check_real_ip() -> Option<String> {
+        Self::check_direct()
             .await
-        {
+            .ok()
+            .filter(|d| d.latency_ms.is_some() && !d.is_tor)
+            .map(|d| d.current_ip)
+    }
+
+    /// Run the check without any proxy. With the kill switch on this is
+    /// either blocked or, on Linux, transparently routed through Tor. Always
+    /// uses the torproject API, since only it can tell those two apart.
+    pub async fn check_direct() -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .no_proxy()
+            // Short, since a kill switch that drops packets makes this hang
+            .timeout(std::time::Duration::from_secs(5))
+            .build()?;
+        Self::check_with(client, TORPROJECT_CHECK_URL).await
+    }
+
+    pub(crate) async fn check_with(client: reqwest::Client, url: &str) -> anyhow::Result<Self> {
+        let started = std::time::Instant::now();
+        match client.get(url).send().await {
             Ok(response) => {
-                let json: serde_json::Value = response.json().await?;
+                let latency_ms = started.elapsed().as_millis() as u64;
+                let (is_tor, current_ip) = read_check_response(response).await;
 
                 Ok(Self {
-                    is_tor: json["IsTor"].as_bool().unwrap_or(false),
-                    current_ip: json["IP"].as_str().unwrap_or("Unknown").to_string(),
+                    is_tor,
+                    current_ip,
                     exit_country: None,
+                    hibernating: None,
+                    dormant: false,
+                    latency_ms: Some(latency_ms),
+                    tor_version: None,
+                    real_ip: None,
+                    cached_age_secs: None,
                 })
             }
             Err(e) => {
@@ -36,11 +124,156 @@ impl ConnectionStatus {
                     // Show the actual error to the user for debugging
                     current_ip: format!("Not Connected ({})", e),
                     exit_country: None,
+                    hibernating: None,
+                    dormant: false,
+                    latency_ms: None,
+                    tor_version: None,
+                    real_ip: None,
+                    cached_age_secs: None,
                 })
             }
         }
     }
 
+    /// Where the last successful status is kept for `status --cached`.
+    pub fn cache_path(tor: &TorConfig) -> PathBuf {
+        tor.work_dir().join("status.json")
+    }
+
+    /// Save this status, stamped with the current time, as the last known one.
+    pub fn save_cached(&self, tor: &TorConfig) -> std::io::Result<()> {
+        let checked_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        // The real IP is only shown to whoever asked; keep it off disk
+        let status = Self {
+            real_ip: None,
+            ..self.clone()
+        };
+        let content = serde_json::to_string_pretty(&CachedStatus { checked_at, status })?;
+
+        // Write then rename, so a concurrent `status --cached` never sees half a file
+        let path = Self::cache_path(tor);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            // Only a directory nobody else can write to keeps the rename safe
+            let dir = tor.work_dir();
+            if std::fs::symlink_metadata(&dir)?.uid() != unsafe { libc::geteuid() } {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    format!("{} is not owned by us", dir.display()),
+                ));
+            }
+        }
+        let tmp = path.with_extension("json.tmp");
+        match std::fs::remove_file(&tmp) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        // create_new refuses anything already there, symlinks included
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp)?
+            .write_all(content.as_bytes())?;
+        std::fs::rename(tmp, path)
+    }
+
+    /// The last status saved with [`save_cached`](Self::save_cached), with
+    /// `cached_age_secs` set. Reads only the state file, no network.
+    pub fn load_cached(tor: &TorConfig) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(Self::cache_path(tor))?;
+        let cached: CachedStatus = serde_json::from_str(&content)?;
+
+        let checked_at = UNIX_EPOCH + Duration::from_secs(cached.checked_at);
+        let age = SystemTime::now()
+            .duration_since(checked_at)
+            .unwrap_or_default();
+        let mut status = cached.status;
+        status.cached_age_secs = Some(age.as_secs());
+        Ok(status)
+    }
+
+    /// Forget the saved status, e.g. once Nipe is stopped.
+    pub fn clear_cached(tor: &TorConfig) {
+        let _ = std::fs::remove_file(Self::cache_path(tor));
+    }
+
+    /// Look up the exit's country from Tor's GeoIP database.
+    pub async fn check_exit_country(&mut self, tor: &TorConfig) {
+        if !self.is_tor {
+            return;
+        }
+        let Ok(mut control) = TorControl::connect(tor).await else {
+            return;
+        };
+
+        self.exit_country = control
+            .getinfo(&format!("ip-to-country/{}", self.current_ip))
+            .await
+            .ok()
+            .filter(|cc| !cc.is_empty() && cc != "??");
+    }
+
+    /// Ask Tor whether it is dormant or hibernating because of bandwidth accounting.
+    pub async fn check_tor_state(&mut self, tor: &TorConfig) {
+        let Ok(mut control) = TorControl::connect(tor).await else {
+            return;
+        };
+
+        self.dormant = control.getinfo("dormant").await.ok().as_deref() == Some("1");
+
+        if control.getinfo("accounting/enabled").await.ok().as_deref() != Some("1") {
+            return;
+        }
+
+        self.hibernating = control
+            .getinfo("accounting/hibernating")
+            .await
+            .ok()
+            .filter(|state| state != "awake");
+    }
+
+    /// Render the status as Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP nipe_up Whether the status check reached the check service.\n");
+        out.push_str("# TYPE nipe_up gauge\n");
+        out.push_str(&format!("nipe_up {}\n", self.latency_ms.is_some() as u8));
+
+        out.push_str("# HELP nipe_is_tor Whether traffic is leaving through Tor.\n");
+        out.push_str("# TYPE nipe_is_tor gauge\n");
+        out.push_str(&format!("nipe_is_tor {}\n", self.is_tor as u8));
+
+        if let Some(latency) = self.latency_ms {
+            out.push_str("# HELP nipe_latency_ms Round-trip time of the status check.\n");
+            out.push_str("# TYPE nipe_latency_ms gauge\n");
+            out.push_str(&format!("nipe_latency_ms {}\n", latency));
+        }
+
+        if let Some(age) = self.cached_age_secs {
+            out.push_str("# HELP nipe_status_age_seconds Age of the cached status.\n");
+            out.push_str("# TYPE nipe_status_age_seconds gauge\n");
+            out.push_str(&format!("nipe_status_age_seconds {}\n", age));
+        }
+
+        out
+    }
+
+    /// One compact line for shell prompts: "🟢 US 185.220.101.4" through
+    /// Tor (the country only when known), "🔴 off" otherwise.
+    pub fn to_oneline(&self) -> String {
+        if !self.is_tor {
+            return "🔴 off".to_string();
+        }
+        match &self.exit_country {
+            Some(cc) => format!("🟢 {} {}", cc.to_uppercase(), self.current_ip),
+            None => format!("🟢 {}", self.current_ip),
+        }
+    }
+
     pub fn display(&self) {
         println!("\n{}", "━".repeat(60).bright_blue());
         println!(
@@ -63,6 +296,13 @@ impl ConnectionStatus {
                 "Current IP:".bold(),
                 self.current_ip.bright_cyan()
             );
+            println!(
+                "  {} {}",
+                "Exit Country:".bold(),
+                self.exit_country
+                    .as_deref()
+                    .map_or("Unknown".dimmed(), |cc| cc.to_uppercase().bright_cyan())
+            );
             println!(
                 "  {} {}",
                 "Protection:".bold(),
@@ -82,8 +322,84 @@ impl ConnectionStatus {
             println!("  {} {}", "Protection:".bold(), "None".bright_red());
         }
 
+        if let Some(real_ip) = &self.real_ip {
+            if *real_ip == self.current_ip {
+                println!(
+                    "  {} {}",
+                    "WARNING:".bright_red().bold(),
+                    "traffic is leaving with your real IP - it is NOT going through Tor"
+                        .bright_red()
+                        .bold()
+                );
+            }
+            println!("  {} {}", "Real IP:".bold(), real_ip.dimmed());
+        }
+
+        if let Some(version) = &self.tor_version {
+            println!("  {} {}", "Tor Version:".bold(), version.bright_cyan());
+        }
+
+        if let Some(age) = self.cached_age_secs {
+            println!(
+                "  {} {}",
+                "Checked:".bold(),
+                format!(
+                    "{}s ago (cached; run without --cached for a live check)",
+                    age
+                )
+                .bright_yellow()
+            );
+        }
+
+        if self.dormant {
+            println!(
+                "  {} {}",
+                "Activity:".bold(),
+                "Dormant (idle) - new connections are slow while Tor wakes up".bright_yellow()
+            );
+        }
+
+        if let Some(state) = &self.hibernating {
+            println!(
+                "  {} {}",
+                "Accounting:".bold(),
+                format!(
+                    "Hibernating ({}) - AccountingMax reached, Tor resumes next period",
+                    state
+                )
+                .bright_yellow()
+            );
+        }
+
         println!();
         println!("{}", "━".repeat(60).bright_blue());
         println!();
     }
 }
+
+/// Interpret a check endpoint's response as `(is_tor, ip)`. The torproject
+/// JSON API says whether the request came through Tor; any other endpoint
+/// counts as connected when it answers with a success status, and its IP is
+/// taken from an "ip"/"IP" JSON field or a plain-text body if there is one.
+pub(crate) async fn read_check_response(response: reqwest::Response) -> (bool, String) {
+    let success = response.status().is_success();
+    let body = response.text().await.unwrap_or_default();
+
+    let json = serde_json::from_str::<serde_json::Value>(&body).ok();
+    if let Some(is_tor) = json.as_ref().and_then(|j| j["IsTor"].as_bool()) {
+        let ip = json.as_ref().and_then(|j| j["IP"].as_str());
+        return (is_tor, ip.unwrap_or("Unknown").to_string());
+    }
+
+    let ip = json
+        .as_ref()
+        .and_then(|j| j["ip"].as_str().or(j["IP"].as_str()))
+        .map(str::to_string)
+        .or_else(|| {
+            body.trim()
+                .parse::<std::net::IpAddr>()
+                .ok()
+                .map(|ip| ip.to_string())
+        });
+    (success, ip.unwrap_or_else(|| "Unknown".to_string()))
+}
@@ -0,0 +1,191 @@
+use crate::config::{NipeConfig, TorConfig};
+use crate::error::{NipeError, Result};
+use crate::metrics::{traffic_metrics, Metrics};
+use crate::status::ConnectionStatus;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tracing::{debug, info};
+
+/// Largest request head read from a client; nothing here needs more.
+const MAX_REQUEST_BYTES: u64 = 16 * 1024;
+
+/// How long a client gets to send its request head.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connections served at once; more are closed right after accept.
+const MAX_CLIENTS: usize = 64;
+
+/// How often the served status is re-checked. Requests only read the last
+/// result, so clients can't make the host send traffic.
+const STATUS_REFRESH: Duration = Duration::from_secs(30);
+
+/// The last checked status and when it was checked.
+type SharedStatus = Arc<Mutex<(Instant, ConnectionStatus)>>;
+
+/// Serve the connection status over HTTP on `addr` for remote monitoring.
+///
+/// - `GET /health` returns the [`ConnectionStatus`] as JSON, with status 200
+///   when traffic goes through Tor and 503 otherwise.
+/// - `GET /metrics` returns the same status plus Tor's traffic counters in
+///   Prometheus text format, and `metrics` when nipe itself is running.
+///
+/// The status is checked every [`STATUS_REFRESH`] in the background, and
+/// both endpoints report its age in `cached_age_secs`. At most
+/// [`MAX_CLIENTS`] connections are served at once.
+pub async fn serve(
+    addr: SocketAddr,
+    config: NipeConfig,
+    metrics: Option<Arc<Metrics>>,
+) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| NipeError::Other(format!("Failed to listen on {}: {}", addr, e)))?;
+    info!("Status server listening on http://{}", addr);
+
+    let status = Arc::new(Mutex::new((
+        Instant::now(),
+        not_connected("not checked yet".to_string()),
+    )));
+    tokio::spawn(refresh_status(config.tor.clone(), Arc::clone(&status)));
+
+    let config = Arc::new(config);
+    let slots = Arc::new(Semaphore::new(MAX_CLIENTS));
+    loop {
+        let (client, peer) = listener.accept().await?;
+        let Ok(slot) = Arc::clone(&slots).try_acquire_owned() else {
+            debug!("Too many status clients, dropping {}", peer);
+            continue;
+        };
+        let config = Arc::clone(&config);
+        let status = Arc::clone(&status);
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(client, &config, &status, metrics.as_deref()).await {
+                debug!("Status request from {} failed: {}", peer, e);
+            }
+            drop(slot);
+        });
+    }
+}
+
+async fn handle_client(
+    client: TcpStream,
+    config: &NipeConfig,
+    status: &SharedStatus,
+    metrics: Option<&Metrics>,
+) -> Result<()> {
+    let mut client = BufReader::new(client.take(MAX_REQUEST_BYTES));
+
+    let mut request_line = String::new();
+    let read_head = async {
+        client.read_line(&mut request_line).await?;
+        // The headers don't matter; read them so the client sees a clean close
+        loop {
+            let mut line = String::new();
+            if client.read_line(&mut line).await? == 0 || line == "\r\n" || line == "\n" {
+                break;
+            }
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    tokio::time::timeout(REQUEST_TIMEOUT, read_head)
+        .await
+        .map_err(|_| NipeError::Other("timed out reading the request".to_string()))??;
+
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let client = &mut client.into_inner().into_inner();
+    if method != "GET" {
+        return respond(client, "405 Method Not Allowed", "text/plain", "").await;
+    }
+
+    match path {
+        "/health" => {
+            let status = last_status(status);
+            let code = if status.is_tor {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            };
+            let mut json =
+                serde_json::to_value(&status).map_err(|e| NipeError::Other(e.to_string()))?;
+            // Never hand the host's real IP to whoever can reach this port
+            if let Some(fields) = json.as_object_mut() {
+                fields.remove("real_ip");
+            }
+            let body =
+                serde_json::to_string_pretty(&json).map_err(|e| NipeError::Other(e.to_string()))?;
+            respond(client, code, "application/json", &body).await
+        }
+        "/metrics" => {
+            let mut body = last_status(status).to_prometheus();
+            body.push_str(&traffic_metrics(&config.tor).await);
+            if let Some(metrics) = metrics {
+                body.push_str(&metrics.to_prometheus());
+            }
+            respond(client, "200 OK", "text/plain; version=0.0.4", &body).await
+        }
+        _ => respond(client, "404 Not Found", "text/plain", "").await,
+    }
+}
+
+/// Re-run the Tor checks of `nipe status` every [`STATUS_REFRESH`],
+/// publishing each result to `shared`. A failed check is reported as not
+/// connected rather than as a server error.
+async fn refresh_status(tor: TorConfig, shared: SharedStatus) {
+    let mut ticker = tokio::time::interval(STATUS_REFRESH);
+    loop {
+        ticker.tick().await;
+        let mut status = match ConnectionStatus::check(&tor).await {
+            Ok(status) => status,
+            Err(e) => not_connected(e.to_string()),
+        };
+        status.check_exit_country(&tor).await;
+        status.check_tor_state(&tor).await;
+        *shared.lock().unwrap() = (Instant::now(), status);
+    }
+}
+
+/// The last published status, with `cached_age_secs` set.
+fn last_status(shared: &SharedStatus) -> ConnectionStatus {
+    let (checked_at, status) = &*shared.lock().unwrap();
+    ConnectionStatus {
+        cached_age_secs: Some(checked_at.elapsed().as_secs()),
+        ..status.clone()
+    }
+}
+
+fn not_connected(reason: String) -> ConnectionStatus {
+    ConnectionStatus {
+        is_tor: false,
+        current_ip: format!("Not Connected ({})", reason),
+        exit_country: None,
+        hibernating: None,
+        dormant: false,
+        latency_ms: None,
+        tor_version: None,
+        real_ip: None,
+        cached_age_secs: None,
+    }
+}
+
+async fn respond(
+    client: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    client.write_all(response.as_bytes()).await?;
+    Ok(())
+}